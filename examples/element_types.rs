@@ -0,0 +1,112 @@
+// Compare decoding every element with decoding only some element types, on FILE or on
+// generated input. Skipped types are still scanned to keep the delta and string table state.
+// Run with --release: cargo run --release --example element_types [FILE]
+use o5m_stream::{
+  Coord,Dataset,Decoder,DecoderOptions,ElementType,Encoder,Info,Node,NodeData,Relation,
+  RelationData,RelationMember,Tags,Way,WayData,
+};
+use std::time::{Duration,Instant};
+
+type Error = Box<dyn std::error::Error+Send+Sync>;
+
+// mostly nodes with author information and a few tags, then ways and relations, the way
+// extracts are laid out
+fn generate() -> Result<Vec<u8>,Error> {
+  let info = |id: u64| Some(Info {
+    version: Some(id % 4 + 1),
+    timestamp: Some(1_600_000_000 + (id as i64) * 13),
+    changeset: Some(id / 50),
+    uid: Some(id % 1000),
+    user: Some(format!("user {}", id % 1000)),
+  });
+  let mut encoder = Encoder::new();
+  let mut buf = vec![];
+  for id in 1..2_000_000u64 {
+    let mut tags = Tags::new();
+    if id % 4 == 0 {
+      tags.insert("name".to_string(), format!("node {}", id % 5000));
+      tags.insert("amenity".to_string(), "bench".to_string());
+    }
+    encoder.write_dataset(&Dataset::Node(Node {
+      id,
+      info: info(id),
+      data: Some(NodeData {
+        longitude: Coord((id * 7919 % 3_600_000_000) as i32 - 1_800_000_000),
+        latitude: Coord((id * 104_729 % 1_800_000_000) as i32 - 900_000_000),
+      }),
+      tags,
+    }), &mut buf)?;
+  }
+  for id in 1..200_000u64 {
+    let mut tags = Tags::new();
+    tags.insert("highway".to_string(), "residential".to_string());
+    encoder.write_dataset(&Dataset::Way(Way {
+      id,
+      info: info(id),
+      data: Some(WayData { refs: (id*10..id*10+10).collect() }),
+      tags,
+    }), &mut buf)?;
+  }
+  for id in 1..20_000u64 {
+    let mut tags = Tags::new();
+    tags.insert("type".to_string(), "multipolygon".to_string());
+    encoder.write_dataset(&Dataset::Relation(Relation {
+      id,
+      info: info(id),
+      data: Some(RelationData {
+        members: (id*10..id*10+4).map(|r| RelationMember {
+          id: r,
+          element_type: ElementType::Way(),
+          role: if r % 4 == 0 { "outer" } else { "inner" }.to_string(),
+        }).collect(),
+      }),
+      tags,
+    }), &mut buf)?;
+  }
+  Ok(buf)
+}
+
+// best of three runs
+fn time<F: FnMut() -> Result<usize,Error>>(mut f: F) -> Result<(Duration,usize),Error> {
+  let mut best = None;
+  let mut count = 0;
+  for _ in 0..3 {
+    let start = Instant::now();
+    count = f()?;
+    let t = start.elapsed();
+    if best.map(|b| t < b).unwrap_or(true) { best = Some(t) }
+  }
+  Ok((best.unwrap_or_default(),count))
+}
+
+fn bench(name: &str, input: &[u8]) -> Result<(),Error> {
+  println!["{}: {} bytes", name, input.len()];
+  let runs = [
+    ("all types",None),
+    ("nodes",Some(vec![ElementType::Node()])),
+    ("ways",Some(vec![ElementType::Way()])),
+    ("relations",Some(vec![ElementType::Relation()])),
+    ("none",Some(vec![])),
+  ];
+  let mut base = None;
+  for (label,element_types) in runs.iter() {
+    let (t,count) = time(|| {
+      let options = DecoderOptions { element_types: element_types.clone(), ..Default::default() };
+      let mut decoder = Decoder::with_options(input, options);
+      let mut n = 0;
+      while decoder.next_ref()?.is_some() { n += 1 }
+      Ok(n)
+    })?;
+    let base = *base.get_or_insert(t);
+    println!["  {:<10} {:>8.1?} {:.2}x {} datasets", label, t,
+      base.as_secs_f64() / t.as_secs_f64(), count];
+  }
+  Ok(())
+}
+
+fn main() -> Result<(),Error> {
+  match std::env::args().nth(1) {
+    Some(file) => bench(&file, &std::fs::read(&file)?),
+    None => bench("generated", &generate()?),
+  }
+}
@@ -0,0 +1,102 @@
+// Compare Decoder with ParallelDecoder at each thread count, on FILE or on generated input.
+// Run with --release: cargo run --release --example parallel [FILE]
+use o5m_stream::{
+  Coord,Dataset,Decoder,DecoderOptions,Encoder,EncoderOptions,FrameDecoder,Node,NodeData,
+  ParallelDecoder,Tags,Way,WayData,
+};
+use std::time::{Duration,Instant};
+
+type Error = Box<dyn std::error::Error+Send+Sync>;
+
+// nodes and ways with a few tags each, written with a reset every `reset_interval` elements
+fn generate(reset_interval: Option<usize>) -> Result<Vec<u8>,Error> {
+  let mut encoder = Encoder::with_options(EncoderOptions { reset_interval });
+  let mut buf = vec![];
+  for id in 1..2_000_000u64 {
+    let mut tags = Tags::new();
+    if id % 4 == 0 {
+      tags.insert("name".to_string(), format!("node {}", id % 5000));
+      tags.insert("amenity".to_string(), "bench".to_string());
+    }
+    encoder.write_dataset(&Dataset::Node(Node {
+      id,
+      info: None,
+      data: Some(NodeData {
+        longitude: Coord((id * 7919 % 3_600_000_000) as i32 - 1_800_000_000),
+        latitude: Coord((id * 104_729 % 1_800_000_000) as i32 - 900_000_000),
+      }),
+      tags,
+    }), &mut buf)?;
+  }
+  for id in 1..200_000u64 {
+    let mut tags = Tags::new();
+    tags.insert("highway".to_string(), "residential".to_string());
+    encoder.write_dataset(&Dataset::Way(Way {
+      id,
+      info: None,
+      data: Some(WayData { refs: (id*10..id*10+10).collect() }),
+      tags,
+    }), &mut buf)?;
+  }
+  Ok(buf)
+}
+
+// best of three runs
+fn time<F: FnMut() -> Result<usize,Error>>(mut f: F) -> Result<(Duration,usize),Error> {
+  let mut best = None;
+  let mut count = 0;
+  for _ in 0..3 {
+    let start = Instant::now();
+    count = f()?;
+    let t = start.elapsed();
+    if best.map(|b| t < b).unwrap_or(true) { best = Some(t) }
+  }
+  Ok((best.unwrap_or_default(),count))
+}
+
+fn bench(name: &str, input: &[u8]) -> Result<(),Error> {
+  let mut frames = FrameDecoder::new(input);
+  let mut resets = 0;
+  while let Some(frame) = frames.next_frame()? {
+    if frame.type_byte == 0xff { resets += 1 }
+  }
+  println!["{}: {} bytes, {} resets", name, input.len(), resets];
+  let (base,count) = time(|| {
+    let mut n = 0;
+    for item in Decoder::new(input) { item?; n += 1 }
+    Ok(n)
+  })?;
+  println!["  Decoder             {:>8.1?} {} datasets", base, count];
+  // about the work the reading thread does to follow the delta and string state through a
+  // section without resets, which bounds the speedup on such sections
+  let (t,_) = time(|| {
+    let options = DecoderOptions { element_types: Some(vec![]), ..Default::default() };
+    for item in Decoder::with_options(input, options) { item?; }
+    Ok(0)
+  })?;
+  println!["  state only          {:>8.1?} {:.2}x", t, base.as_secs_f64() / t.as_secs_f64()];
+  let cores = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+  let mut threads = 1;
+  loop {
+    let (t,_) = time(|| {
+      let mut n = 0;
+      for item in ParallelDecoder::new(input, threads) { item?; n += 1 }
+      Ok(n)
+    })?;
+    println!["  ParallelDecoder x{:<2} {:>8.1?} {:.2}x", threads, t,
+      base.as_secs_f64() / t.as_secs_f64()];
+    if threads >= cores { break }
+    threads = (threads * 2).min(cores);
+  }
+  Ok(())
+}
+
+fn main() -> Result<(),Error> {
+  match std::env::args().nth(1) {
+    Some(file) => bench(&file, &std::fs::read(&file)?),
+    None => {
+      bench("reset_interval 4000", &generate(Some(4000))?)?;
+      bench("default resets", &generate(None)?)
+    },
+  }
+}
@@ -0,0 +1,112 @@
+// Compare Decoder over an in-memory reader with SliceDecoder, on FILE or on generated input.
+// FrameDecoder and SliceFrameDecoder only split the input into frames, which is the work each
+// decoder does per element on top of parsing the dataset. The bare frame walk is a plain loop
+// over type bytes and lengths, the least any decoder has to do.
+// Run with --release: cargo run --release --example slice [FILE]
+use o5m_stream::{
+  Coord,Dataset,Decoder,Encoder,FrameDecoder,Node,NodeData,SliceDecoder,SliceFrameDecoder,Tags,
+  Way,WayData,
+};
+use std::time::{Duration,Instant};
+
+type Error = Box<dyn std::error::Error+Send+Sync>;
+
+// nodes and ways with a few tags each
+fn generate() -> Result<Vec<u8>,Error> {
+  let mut encoder = Encoder::new();
+  let mut buf = vec![];
+  for id in 1..2_000_000u64 {
+    let mut tags = Tags::new();
+    if id % 4 == 0 {
+      tags.insert("name".to_string(), format!("node {}", id % 5000));
+      tags.insert("amenity".to_string(), "bench".to_string());
+    }
+    encoder.write_dataset(&Dataset::Node(Node {
+      id,
+      info: None,
+      data: Some(NodeData {
+        longitude: Coord((id * 7919 % 3_600_000_000) as i32 - 1_800_000_000),
+        latitude: Coord((id * 104_729 % 1_800_000_000) as i32 - 900_000_000),
+      }),
+      tags,
+    }), &mut buf)?;
+  }
+  for id in 1..200_000u64 {
+    let mut tags = Tags::new();
+    tags.insert("highway".to_string(), "residential".to_string());
+    encoder.write_dataset(&Dataset::Way(Way {
+      id,
+      info: None,
+      data: Some(WayData { refs: (id*10..id*10+10).collect() }),
+      tags,
+    }), &mut buf)?;
+  }
+  Ok(buf)
+}
+
+// best of five runs
+fn time<F: FnMut() -> Result<usize,Error>>(mut f: F) -> Result<(Duration,usize),Error> {
+  let mut best = None;
+  let mut count = 0;
+  for _ in 0..5 {
+    let start = Instant::now();
+    count = f()?;
+    let t = start.elapsed();
+    if best.map(|b| t < b).unwrap_or(true) { best = Some(t) }
+  }
+  Ok((best.unwrap_or_default(),count))
+}
+
+fn report(name: &str, (t,count): (Duration,usize)) {
+  println!["  {:<20} {:>8.1?} {:>6.1} ns per frame", name, t,
+    t.as_nanos() as f64 / count.max(1) as f64];
+}
+
+fn bench(name: &str, input: &[u8]) -> Result<(),Error> {
+  println!["{}: {} bytes", name, input.len()];
+  report("Decoder", time(|| {
+    let mut decoder = Decoder::new(input);
+    let mut n = 0;
+    while decoder.next_ref()?.is_some() { n += 1 }
+    Ok(n)
+  })?);
+  report("SliceDecoder", time(|| {
+    let mut decoder = SliceDecoder::new(input);
+    let mut n = 0;
+    while decoder.next_ref()?.is_some() { n += 1 }
+    Ok(n)
+  })?);
+  report("FrameDecoder", time(|| {
+    let mut frames = FrameDecoder::new(input);
+    let mut n = 0;
+    while frames.next_frame()?.is_some() { n += 1 }
+    Ok(n)
+  })?);
+  report("SliceFrameDecoder", time(|| {
+    let mut frames = SliceFrameDecoder::new(input);
+    let mut n = 0;
+    while frames.next_frame()?.is_some() { n += 1 }
+    Ok(n)
+  })?);
+  report("bare frame walk", time(|| {
+    let mut i = 0;
+    let mut n = 0;
+    while i < input.len() {
+      n += 1;
+      i += 1;
+      if input[i-1] == 0xff { continue }
+      let (k,len) = o5m_stream::parse::unsigned(&input[i..])?;
+      i += k + len as usize;
+      std::hint::black_box(&input[i-(len as usize)..i]);
+    }
+    Ok(n)
+  })?);
+  Ok(())
+}
+
+fn main() -> Result<(),Error> {
+  match std::env::args().nth(1) {
+    Some(file) => bench(&file, &std::fs::read(&file)?),
+    None => bench("generated", &generate()?),
+  }
+}
@@ -0,0 +1,527 @@
+use futures::{executor::block_on,future,io::AllowStdIo,prelude::*,stream::{self,Stream}};
+use std::io::{BufReader,BufWriter,Write};
+use std::sync::{Arc,Mutex};
+use o5m_stream::{
+  Area,BBox,Coord,Dataset,DecodeError,DecodeStream,DecoderOptions,ElementType,Element,
+  Assembled,GeoJsonFormat,HashMapCache,Header,Info,SpatialFilter,Tags,Timestamp,
+};
+
+type Error = Box<dyn std::error::Error+Send+Sync>;
+type R = Box<dyn futures::io::AsyncRead+Send+Unpin>;
+type W = Box<dyn futures::io::AsyncWrite+Send+Unpin>;
+
+const USAGE: &str = "usage: o5m COMMAND [OPTIONS] [FILE]
+
+Read FILE, or stdin when FILE is missing or \"-\", and write to stdout.
+
+commands:
+  cat       print each item on one line
+  stats     count elements and report the id, bbox and timestamp ranges
+  filter    keep the elements that match all of the options below
+  convert   write the input in another format
+
+options:
+  --from FORMAT        input format. default: from the file extension, or o5m
+  --to FORMAT          output format for filter and convert. default: o5m
+  --type TYPES         keep elements of these comma-separated types: node, way, relation
+  --tag KEY[=VALUE]    keep elements with this tag. when given more than once, elements must
+                       have all of the tags
+  --bbox W,S,E,N       keep elements inside these bounds, in degrees
+  --complete-ways      with --bbox, also keep the nodes of ways that cross the edge
+  --lenient            skip malformed o5m data up to the next reset, or malformed pbf blocks,
+                       and print each error to stderr. without it, the first error stops the
+                       command with exit status 1
+
+formats: o5m, osm (xml), osc (osmChange xml), pbf, geojson, geojsonl (one feature per line)";
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+enum Format { O5m(), Osm(), Osc(), Pbf(), GeoJson(), GeoJsonLines() }
+
+impl Format {
+  fn parse(name: &str) -> Result<Self,Error> {
+    Ok(match name {
+      "o5m" | "o5c" => Self::O5m(),
+      "osm" | "xml" => Self::Osm(),
+      "osc" => Self::Osc(),
+      "pbf" => Self::Pbf(),
+      "geojson" | "json" => Self::GeoJson(),
+      "geojsonl" | "geojsonseq" | "ndjson" => Self::GeoJsonLines(),
+      _ => return Err(format!("unknown format {:?}", name).into()),
+    })
+  }
+  // format from the file extension, with o5m as the default
+  fn detect(path: &Option<String>) -> Self {
+    path.as_ref()
+      .and_then(|p| std::path::Path::new(p).extension())
+      .and_then(|ext| Self::parse(&ext.to_string_lossy()).ok())
+      .unwrap_or(Self::O5m())
+  }
+  fn is_geojson(self) -> bool {
+    matches!(self, Self::GeoJson() | Self::GeoJsonLines())
+  }
+}
+
+// element types and tags that `filter` keeps: elements of any of the types that have all of the
+// tags
+#[derive(Clone,Default)]
+struct Selection {
+  types: Option<Vec<ElementType>>,
+  tags: Vec<(String,Option<String>)>,
+}
+
+impl Selection {
+  fn keeps(&self, data: &Dataset) -> bool {
+    let element_type = match data.get_type() {
+      Some(t) => t,
+      None => return true,
+    };
+    match data {
+      Dataset::Node(node) => self.keeps_element(element_type, node.get_tags()),
+      Dataset::Way(way) => self.keeps_element(element_type, way.get_tags()),
+      Dataset::Relation(relation) => self.keeps_element(element_type, relation.get_tags()),
+      _ => self.keeps_type(element_type) && self.tags.is_empty(),
+    }
+  }
+  // ways and areas are checked once their geometry is built, so that the nodes they refer to
+  // reach the location cache whether or not they are kept themselves
+  fn keeps_assembled(&self, item: &Assembled) -> bool {
+    match item {
+      Assembled::Dataset(data) => self.keeps(data),
+      Assembled::Way(geometry) => self.keeps_element(ElementType::Way(), &geometry.way.tags),
+      Assembled::Multipolygon(geometry) => {
+        self.keeps_element(ElementType::Relation(), &geometry.relation.tags)
+      },
+    }
+  }
+  fn keeps_type(&self, element_type: ElementType) -> bool {
+    self.types.as_ref().map(|types| types.contains(&element_type)).unwrap_or(true)
+  }
+  fn keeps_element(&self, element_type: ElementType, tags: &Tags) -> bool {
+    if !self.keeps_type(element_type) { return false }
+    self.tags.iter().all(|(k,v)| match (tags.get(k),v) {
+      (Some(_),None) => true,
+      (Some(x),Some(v)) => x == v,
+      (None,_) => false,
+    })
+  }
+}
+
+struct Options {
+  command: String,
+  file: Option<String>,
+  from: Option<Format>,
+  to: Option<Format>,
+  selection: Selection,
+  bbox: Option<BBox>,
+  complete_ways: bool,
+  lenient: bool,
+}
+
+impl Options {
+  fn parse(args: &[String]) -> Result<Self,Error> {
+    let mut args = args.iter();
+    let command = args.next().ok_or("missing command")?.clone();
+    if !["cat","stats","filter","convert"].contains(&command.as_str()) {
+      return Err(format!("unknown command {:?}", command).into());
+    }
+    let mut options = Self {
+      command,
+      file: None,
+      from: None,
+      to: None,
+      selection: Selection::default(),
+      bbox: None,
+      complete_ways: false,
+      lenient: false,
+    };
+    while let Some(arg) = args.next() {
+      let mut value = || args.next().ok_or_else(|| format!("missing value for {}", arg));
+      match arg.as_str() {
+        "--from" => options.from = Some(Format::parse(value()?)?),
+        "--to" => options.to = Some(Format::parse(value()?)?),
+        "--type" => {
+          let types = value()?.split(',').map(|t| match t {
+            "node" | "n" => Ok(ElementType::Node()),
+            "way" | "w" => Ok(ElementType::Way()),
+            "relation" | "r" => Ok(ElementType::Relation()),
+            t => Err(format!("unknown element type {:?}", t)),
+          }).collect::<Result<Vec<_>,_>>()?;
+          options.selection.types = Some(types);
+        },
+        "--tag" => {
+          let tag = value()?;
+          options.selection.tags.push(match tag.split_once('=') {
+            Some((k,v)) => (k.to_string(),Some(v.to_string())),
+            None => (tag.to_string(),None),
+          });
+        },
+        "--bbox" => {
+          let v = value()?.split(',').map(|x| x.parse::<Coord>())
+            .collect::<Result<Vec<_>,_>>().map_err(|e| format!("invalid bbox: {}", e))?;
+          match v.as_slice() {
+            [x1,y1,x2,y2] => options.bbox = Some(BBox { x1: *x1, y1: *y1, x2: *x2, y2: *y2 }),
+            _ => return Err("invalid bbox: expected W,S,E,N".into()),
+          }
+        },
+        "--complete-ways" => options.complete_ways = true,
+        "--lenient" => options.lenient = true,
+        x if x.starts_with("--") => return Err(format!("unknown option {}", x).into()),
+        x => {
+          if options.file.is_some() { return Err("more than one input file".into()) }
+          options.file = Some(x.to_string());
+        },
+      }
+    }
+    if options.complete_ways && options.bbox.is_none() {
+      return Err("--complete-ways needs --bbox".into());
+    }
+    Ok(options)
+  }
+}
+
+fn input(options: &Options) -> Result<DecodeStream,Error> {
+  let format = options.from.unwrap_or_else(|| Format::detect(&options.file));
+  if options.lenient && format != Format::O5m() && format != Format::Pbf() {
+    return Err("--lenient only applies to o5m and pbf input".into());
+  }
+  let reader: R = match options.file.as_deref() {
+    None | Some("-") => Box::new(AllowStdIo::new(std::io::stdin())),
+    Some(path) => Box::new(AllowStdIo::new(BufReader::new(std::fs::File::open(path)?))),
+  };
+  // o5m elements of other types are only scanned for their strings. with --bbox the spatial
+  // filter needs every node to place ways, and geojson output needs them to build geometry, so
+  // the types are checked later instead
+  let geojson = options.to.map(Format::is_geojson).unwrap_or(false);
+  let element_types = match options.command == "filter" && options.bbox.is_none() && !geojson {
+    true => options.selection.types.clone(),
+    false => None,
+  };
+  let decoder_options = DecoderOptions {
+    lenient: options.lenient,
+    element_types,
+    ..Default::default()
+  };
+  Ok(match format {
+    Format::O5m() => o5m_stream::decode_with_options(reader, decoder_options),
+    Format::Osm() | Format::Osc() => o5m_stream::decode_xml(reader),
+    #[cfg(feature="pbf")]
+    Format::Pbf() => o5m_stream::decode_pbf_with_options(reader, decoder_options),
+    #[cfg(not(feature="pbf"))]
+    Format::Pbf() => return Err("pbf input needs the pbf feature".into()),
+    Format::GeoJson() | Format::GeoJsonLines() => return Err("geojson cannot be read".into()),
+  })
+}
+
+// what to do with decode errors. with --lenient they are printed to stderr and skipped;
+// otherwise the stream ends at the first one, which `run` returns once the output is written
+#[derive(Clone,Default)]
+struct Report {
+  lenient: bool,
+  error: Arc<Mutex<Option<DecodeError>>>,
+}
+
+impl Report {
+  // pass the items that decoded along
+  fn stream<T: Send>(&self, stream: impl Stream<Item=Result<T,DecodeError>>+Send+Unpin)
+  -> impl Stream<Item=T>+Send+Unpin {
+    let report = self.clone();
+    stream.scan((), move |_,result| future::ready(match result {
+      Ok(x) => Some(Some(x)),
+      Err(e) if report.lenient => {
+        eprintln!("o5m: {}", e);
+        Some(None)
+      },
+      Err(e) => {
+        if let Ok(mut error) = report.error.lock() { *error = Some(e) }
+        None
+      },
+    })).filter_map(future::ready)
+  }
+  fn result(&self) -> Result<(),Error> {
+    match self.error.lock().ok().and_then(|mut error| error.take()) {
+      Some(e) => Err(e.into()),
+      None => Ok(()),
+    }
+  }
+}
+
+// write `stream` as `format`, keeping the items that `selection` keeps
+async fn output(stream: DecodeStream, format: Format, selection: Selection, report: &Report)
+-> Result<(),Error> {
+  let writer: W = Box::new(AllowStdIo::new(BufWriter::new(std::io::stdout())));
+  let stream: DecodeStream = match format.is_geojson() {
+    true => stream,
+    false => {
+      let selection = selection.clone();
+      Box::new(stream.filter(move |result| future::ready(match result {
+        Ok(data) => selection.keeps(data),
+        Err(_) => true,
+      })))
+    },
+  };
+  match format {
+    Format::O5m() => o5m_stream::encode(Box::new(report.stream(stream)), writer).await?,
+    Format::Osm() => o5m_stream::encode_xml(Box::new(report.stream(stream)), writer).await?,
+    Format::Osc() => {
+      // an o5c header in front makes the encoder write osmChange whatever the input was
+      let header = Dataset::Header(Header { format: "o5c2".to_string() });
+      let stream = report.stream(stream)
+        .filter(|data| future::ready(!matches!(data, Dataset::Header(_))));
+      o5m_stream::encode_xml(Box::new(stream::once(future::ready(header)).chain(stream)), writer)
+        .await?
+    },
+    #[cfg(feature="pbf")]
+    Format::Pbf() => o5m_stream::encode_pbf(Box::new(report.stream(stream)), writer).await?,
+    #[cfg(not(feature="pbf"))]
+    Format::Pbf() => return Err("pbf output needs the pbf feature".into()),
+    Format::GeoJson() | Format::GeoJsonLines() => {
+      let stream = o5m_stream::assemble_ways(stream, Box::new(HashMapCache::new()));
+      let stream = o5m_stream::assemble_multipolygons(stream)
+        .filter(move |result| future::ready(match result {
+          Ok(item) => selection.keeps_assembled(item),
+          Err(_) => true,
+        }));
+      let format = match format {
+        Format::GeoJson() => GeoJsonFormat::FeatureCollection(),
+        _ => GeoJsonFormat::Lines(),
+      };
+      o5m_stream::encode_geojson(Box::new(report.stream(stream)), writer, format).await?;
+    },
+  }
+  Ok(())
+}
+
+// quote strings that would be hard to read back from a line of `cat` output
+fn quote(s: &str) -> String {
+  match s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '=' || c == '"') {
+    true => format!("{:?}", s),
+    false => s.to_string(),
+  }
+}
+
+fn print_info(out: &mut impl Write, info: &Option<Info>) -> std::io::Result<()> {
+  let info = match info {
+    Some(info) => info,
+    None => return Ok(()),
+  };
+  if let Some(version) = info.version {
+    write!(out, " v{}", version)?;
+  }
+  if let Some(time) = info.timestamp {
+    write!(out, " {}", Timestamp { time })?;
+  }
+  if let Some(changeset) = info.changeset {
+    write!(out, " c{}", changeset)?;
+  }
+  if let Some(uid) = info.uid {
+    write!(out, " u{}", uid)?;
+  }
+  if let Some(user) = &info.user {
+    write!(out, " @{}", quote(user))?;
+  }
+  Ok(())
+}
+
+fn print_tags(out: &mut impl Write, tags: &Tags) -> std::io::Result<()> {
+  let mut sorted = tags.iter().collect::<Vec<_>>();
+  sorted.sort();
+  for (k,v) in sorted {
+    write!(out, " {}={}", quote(k), quote(v))?;
+  }
+  Ok(())
+}
+
+fn print(out: &mut impl Write, data: &Dataset) -> std::io::Result<()> {
+  match data {
+    Dataset::Header(header) => write!(out, "header {}", header.format)?,
+    Dataset::Timestamp(t) => write!(out, "timestamp {}", t)?,
+    Dataset::BBox(b) => write!(out, "bbox {},{},{},{}", b.x1, b.y1, b.x2, b.y2)?,
+    Dataset::Node(node) => {
+      write!(out, "node {}", node.id)?;
+      print_info(out, &node.info)?;
+      if let Some(d) = &node.data {
+        write!(out, " {},{}", d.longitude, d.latitude)?;
+      }
+      print_tags(out, &node.tags)?;
+    },
+    Dataset::Way(way) => {
+      write!(out, "way {}", way.id)?;
+      print_info(out, &way.info)?;
+      if let Some(d) = &way.data {
+        let refs = d.refs.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        write!(out, " [{}]", refs.join(","))?;
+      }
+      print_tags(out, &way.tags)?;
+    },
+    Dataset::Relation(relation) => {
+      write!(out, "relation {}", relation.id)?;
+      print_info(out, &relation.info)?;
+      if let Some(d) = &relation.data {
+        let members = d.members.iter().map(|m| {
+          let t = match m.element_type {
+            ElementType::Node() => 'n',
+            ElementType::Way() => 'w',
+            ElementType::Relation() => 'r',
+          };
+          format!("{}{}:{}", t, m.id, quote(&m.role))
+        }).collect::<Vec<_>>();
+        write!(out, " [{}]", members.join(","))?;
+      }
+      print_tags(out, &relation.tags)?;
+    },
+    Dataset::Delete(delete) => {
+      let t = match delete.element_type {
+        ElementType::Node() => "node",
+        ElementType::Way() => "way",
+        ElementType::Relation() => "relation",
+      };
+      write!(out, "delete {} {}", t, delete.id)?;
+      print_info(out, &delete.info)?;
+    },
+  }
+  writeln!(out)
+}
+
+async fn cat(stream: DecodeStream, report: &Report) -> Result<(),Error> {
+  let mut stream = report.stream(stream);
+  let mut out = BufWriter::new(std::io::stdout());
+  while let Some(data) = stream.next().await {
+    print(&mut out, &data)?;
+  }
+  out.flush()?;
+  Ok(())
+}
+
+#[derive(Default)]
+struct Range<T> {
+  count: u64,
+  min: Option<T>,
+  max: Option<T>,
+}
+
+impl<T: Copy+PartialOrd> Range<T> {
+  fn add(&mut self, x: T) {
+    self.count += 1;
+    if self.min.map(|m| x < m).unwrap_or(true) { self.min = Some(x) }
+    if self.max.map(|m| x > m).unwrap_or(true) { self.max = Some(x) }
+  }
+}
+
+async fn stats(stream: DecodeStream, report: &Report) -> Result<(),Error> {
+  let mut stream = report.stream(stream);
+  let mut header = None;
+  let mut file_bbox = None;
+  let mut file_timestamp = None;
+  let (mut nodes, mut ways, mut relations) = (Range::default(),Range::default(),Range::default());
+  let mut deletes = 0;
+  let (mut lon, mut lat) = (Range::default(),Range::default());
+  let mut timestamps = Range::default();
+  while let Some(data) = stream.next().await {
+    if let Some(time) = data.get_info().and_then(|info| info.timestamp) {
+      timestamps.add(time);
+    }
+    match data {
+      Dataset::Header(h) => header = Some(h.format),
+      Dataset::Timestamp(t) => file_timestamp = Some(t),
+      Dataset::BBox(b) => file_bbox = Some(b),
+      Dataset::Node(node) => {
+        nodes.add(node.id);
+        if let Some(d) = node.data {
+          lon.add(d.longitude);
+          lat.add(d.latitude);
+        }
+      },
+      Dataset::Way(way) => ways.add(way.id),
+      Dataset::Relation(relation) => relations.add(relation.id),
+      Dataset::Delete(_) => deletes += 1,
+    }
+  }
+  report.result()?;
+  let mut out = BufWriter::new(std::io::stdout());
+  if let Some(format) = header {
+    writeln!(out, "format      {}", format)?;
+  }
+  if let Some(t) = file_timestamp {
+    writeln!(out, "timestamp   {}", t)?;
+  }
+  if let Some(b) = file_bbox {
+    writeln!(out, "header bbox {},{},{},{}", b.x1, b.y1, b.x2, b.y2)?;
+  }
+  for (name,range) in &[("nodes",&nodes),("ways",&ways),("relations",&relations)] {
+    write!(out, "{:<11} {}", name, range.count)?;
+    if let (Some(min),Some(max)) = (range.min, range.max) {
+      write!(out, " (ids {}..{})", min, max)?;
+    }
+    writeln!(out)?;
+  }
+  writeln!(out, "deletes     {}", deletes)?;
+  if let (Some(x1),Some(y1),Some(x2),Some(y2)) = (lon.min, lat.min, lon.max, lat.max) {
+    writeln!(out, "bbox        {},{},{},{}", x1, y1, x2, y2)?;
+  }
+  if let (Some(min),Some(max)) = (timestamps.min, timestamps.max) {
+    writeln!(out, "timestamps  {}..{}", Timestamp { time: min }, Timestamp { time: max })?;
+  }
+  out.flush()?;
+  Ok(())
+}
+
+// the spatial part of `filter`. the selection is applied by `output`
+fn filter(stream: DecodeStream, options: &Options) -> DecodeStream {
+  match &options.bbox {
+    Some(bbox) => {
+      let mut spatial = SpatialFilter::new(Area::BBox(bbox.clone()));
+      spatial.complete_ways = options.complete_ways;
+      o5m_stream::filter(stream, spatial)
+    },
+    None => stream,
+  }
+}
+
+fn run(options: Options) -> Result<(),Error> {
+  let stream = input(&options)?;
+  let report = Report { lenient: options.lenient, ..Default::default() };
+  block_on(async {
+    match options.command.as_str() {
+      "cat" => cat(stream, &report).await,
+      "stats" => stats(stream, &report).await,
+      "filter" => {
+        let format = options.to.unwrap_or(Format::O5m());
+        output(filter(stream, &options), format, options.selection.clone(), &report).await
+      },
+      "convert" => {
+        let format = options.to.ok_or("convert needs --to FORMAT")?;
+        output(stream, format, Selection::default(), &report).await
+      },
+      _ => unreachable!(),
+    }
+  })?;
+  report.result()
+}
+
+// whether `e` comes from writing to a pipe that was closed, such as when the output goes to head
+fn is_broken_pipe(e: &(dyn std::error::Error+'static)) -> bool {
+  if let Some(e) = e.downcast_ref::<std::io::Error>() {
+    return e.kind() == std::io::ErrorKind::BrokenPipe;
+  }
+  e.source().map(is_broken_pipe).unwrap_or(false)
+}
+
+fn main() {
+  let args = std::env::args().skip(1).collect::<Vec<String>>();
+  if args.is_empty() || args.iter().any(|a| a == "-h" || a == "--help") {
+    println!("{}", USAGE);
+    return;
+  }
+  let options = match Options::parse(&args) {
+    Ok(options) => options,
+    Err(e) => {
+      eprintln!("o5m: {}\n\n{}", e, USAGE);
+      std::process::exit(1);
+    },
+  };
+  if let Err(e) = run(options) {
+    if is_broken_pipe(e.as_ref()) { return }
+    eprintln!("o5m: {}", e);
+    std::process::exit(1);
+  }
+}
@@ -0,0 +1,79 @@
+use std::convert::TryFrom;
+
+/// Longitude or latitude as a fixed-point number of 1e-7 degrees, the resolution that o5m, pbf
+/// and the OSM database store.
+///
+/// `to_degrees` gives the nearest `f64`, and `from_degrees` turns that value back into the same
+/// `Coord`. `Display` writes the exact decimal value and `FromStr` reads one, rounding anything
+/// past the 7th decimal place.
+#[derive(Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash,Debug,Default)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize), serde(transparent))]
+pub struct Coord(pub i32);
+
+impl Coord {
+  /// Number of units in one degree.
+  pub const SCALE: i32 = 10_000_000;
+  pub fn to_degrees(self) -> f64 {
+    self.0 as f64 / Self::SCALE as f64
+  }
+  /// Nearest coordinate to `degrees`. Values out of the `i32` range saturate.
+  pub fn from_degrees(degrees: f64) -> Self {
+    Self((degrees * Self::SCALE as f64).round() as i32)
+  }
+}
+
+impl From<i32> for Coord {
+  fn from(x: i32) -> Self { Self(x) }
+}
+
+impl From<Coord> for i32 {
+  fn from(c: Coord) -> Self { c.0 }
+}
+
+/// Formats the exact decimal value with trailing zeros removed, such as `-0.1234` or `52`.
+impl std::fmt::Display for Coord {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let n = (self.0 as i64).abs();
+    if self.0 < 0 { write!(f, "-")? }
+    write!(f, "{}", n / Self::SCALE as i64)?;
+    let frac = n % Self::SCALE as i64;
+    if frac != 0 {
+      let digits = format!("{:07}", frac);
+      write!(f, ".{}", digits.trim_end_matches('0'))?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(thiserror::Error,Debug)]
+#[error("invalid coordinate {input:?}")]
+pub struct ParseCoordError {
+  pub input: String,
+}
+
+/// Parses a decimal number of degrees without going through floating point.
+impl std::str::FromStr for Coord {
+  type Err = ParseCoordError;
+  fn from_str(input: &str) -> Result<Self,Self::Err> {
+    let error = || ParseCoordError { input: input.to_string() };
+    let (negative,s) = match input.strip_prefix('-') {
+      Some(s) => (true,s),
+      None => (false,input.strip_prefix('+').unwrap_or(input)),
+    };
+    let (int,frac) = match s.find('.') {
+      Some(i) => (&s[..i],&s[i+1..]),
+      None => (s,""),
+    };
+    if int.is_empty() && frac.is_empty() { return Err(error()) }
+    if !int.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit()) { return Err(error()) }
+    let mut x: i64 = if int.is_empty() { 0 } else { int.parse().map_err(|_| error())? };
+    for i in 0..7 {
+      x = x.checked_mul(10).ok_or_else(error)?
+        + frac.as_bytes().get(i).map(|b| (b - b'0') as i64).unwrap_or(0);
+    }
+    if frac.as_bytes().get(7).map(|b| *b >= b'5').unwrap_or(false) {
+      x += 1;
+    }
+    i32::try_from(if negative { -x } else { x }).map(Self).map_err(|_| error())
+  }
+}
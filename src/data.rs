@@ -1,8 +1,14 @@
+use crate::Coord;
+
 #[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
+#[cfg_attr(feature="serde", serde(rename_all="lowercase"))]
 pub enum Dataset {
+  Header(Header),
   Node(Node),
   Way(Way),
   Relation(Relation),
+  Delete(Delete),
   BBox(BBox),
   Timestamp(Timestamp),
 }
@@ -12,6 +18,16 @@ impl Dataset {
       Self::Node(node) => Some(node.id),
       Self::Way(way) => Some(way.id),
       Self::Relation(relation) => Some(relation.id),
+      Self::Delete(delete) => Some(delete.id),
+      _ => None,
+    }
+  }
+  pub fn get_type(&self) -> Option<ElementType> {
+    match self {
+      Self::Node(_) => Some(ElementType::Node()),
+      Self::Way(_) => Some(ElementType::Way()),
+      Self::Relation(_) => Some(ElementType::Relation()),
+      Self::Delete(delete) => Some(delete.element_type.clone()),
       _ => None,
     }
   }
@@ -20,18 +36,23 @@ impl Dataset {
       Self::Node(node) => node.info.clone(),
       Self::Way(way) => way.info.clone(),
       Self::Relation(relation) => relation.info.clone(),
+      Self::Delete(delete) => delete.info.clone(),
       _ => None,
     }
   }
 }
 
 #[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
+#[cfg_attr(feature="serde", serde(from="serde_repr::DatasetType", into="serde_repr::DatasetType"))]
 pub enum DatasetType {
   Node(), Way(), Relation(), BBox(), Timestamp(),
   Header(), Sync(), Jump(), Reset(),
 }
 
-#[derive(Clone,PartialEq,Debug)]
+#[derive(Clone,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
+#[cfg_attr(feature="serde", serde(from="serde_repr::ElementType", into="serde_repr::ElementType"))]
 pub enum ElementType {
   Node(), Way(), Relation(),
 }
@@ -46,6 +67,7 @@ pub trait Element {
 }
 
 #[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
 pub struct Info {
   pub version: Option<u64>,
   pub timestamp: Option<i64>,
@@ -70,6 +92,7 @@ impl Default for Info {
 }
 
 #[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
 pub struct Node {
   pub id: u64,
   pub info: Option<Info>,
@@ -77,16 +100,17 @@ pub struct Node {
   pub tags: Tags,
 }
 #[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
 pub struct NodeData {
-  pub longitude: i32,
-  pub latitude: i32,
+  pub longitude: Coord,
+  pub latitude: Coord,
 }
 impl NodeData {
-  pub fn get_longitude(&self) -> f32 {
-    (self.longitude as f32) / 1.0e7
+  pub fn get_longitude(&self) -> f64 {
+    self.longitude.to_degrees()
   }
-  pub fn get_latitude(&self) -> f32 {
-    (self.latitude as f32) / 1.0e7
+  pub fn get_latitude(&self) -> f64 {
+    self.latitude.to_degrees()
   }
 }
 impl Element for Node {
@@ -99,6 +123,7 @@ impl Element for Node {
 }
 
 #[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
 pub struct Way {
   pub id: u64,
   pub info: Option<Info>,
@@ -106,6 +131,7 @@ pub struct Way {
   pub tags: Tags,
 }
 #[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
 pub struct WayData {
   pub refs: Vec<u64>
 }
@@ -119,6 +145,7 @@ impl Element for Way {
 }
 
 #[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
 pub struct Relation {
   pub id: u64,
   pub info: Option<Info>,
@@ -126,10 +153,12 @@ pub struct Relation {
   pub tags: Tags,
 }
 #[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
 pub struct RelationData {
   pub members: Vec<RelationMember>
 }
 #[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
 pub struct RelationMember {
   pub id: u64,
   pub element_type: ElementType,
@@ -144,24 +173,141 @@ impl Element for Relation {
   fn get_tags(&'_ self) -> &'_ Tags { &self.tags }
 }
 
+/// Deletion of an element, as found in o5c change files. Every other element in a change file
+/// is a creation or a modification; the format does not tell those two apart.
+#[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
+pub struct Delete {
+  pub id: u64,
+  pub element_type: ElementType,
+  pub info: Option<Info>,
+}
+
+/// File header. `format` is "o5m2" for data files and "o5c2" for change files.
 #[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
+pub struct Header {
+  pub format: String,
+}
+impl Header {
+  pub fn is_change(&self) -> bool {
+    self.format.starts_with("o5c")
+  }
+}
+
+#[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
 pub struct BBox {
-  pub x1: i32,
-  pub y1: i32,
-  pub x2: i32,
-  pub y2: i32,
+  pub x1: Coord,
+  pub y1: Coord,
+  pub x2: Coord,
+  pub y2: Coord,
 }
 impl BBox {
-  pub fn get_x1(&self) -> f32 { self.x1 as f32 / 1.0e7 }
-  pub fn get_y1(&self) -> f32 { self.y1 as f32 / 1.0e7 }
-  pub fn get_x2(&self) -> f32 { self.x2 as f32 / 1.0e7 }
-  pub fn get_y2(&self) -> f32 { self.y2 as f32 / 1.0e7 }
-  pub fn get_bounds(&self) -> (f32,f32,f32,f32) {
+  pub fn get_x1(&self) -> f64 { self.x1.to_degrees() }
+  pub fn get_y1(&self) -> f64 { self.y1.to_degrees() }
+  pub fn get_x2(&self) -> f64 { self.x2.to_degrees() }
+  pub fn get_y2(&self) -> f64 { self.y2.to_degrees() }
+  pub fn get_bounds(&self) -> (f64,f64,f64,f64) {
     (self.get_x1(),self.get_y1(),self.get_x2(),self.get_y2())
   }
+  /// Whether `point` lies inside the box or on its edge.
+  /// A box with `x1 > x2` crosses the antimeridian.
+  pub fn contains(&self, point: &NodeData) -> bool {
+    let x = match self.x1 <= self.x2 {
+      true => self.x1 <= point.longitude && point.longitude <= self.x2,
+      false => self.x1 <= point.longitude || point.longitude <= self.x2,
+    };
+    x && self.y1 <= point.latitude && point.latitude <= self.y2
+  }
 }
 
 #[derive(Clone,PartialEq,Debug)]
+#[cfg_attr(feature="serde", derive(serde::Serialize,serde::Deserialize))]
 pub struct Timestamp {
   pub time: i64,
 }
+/// Formats as an ISO 8601 UTC timestamp such as `2021-03-04T05:06:07Z`.
+impl std::fmt::Display for Timestamp {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let days = self.time.div_euclid(86400);
+    let secs = self.time.rem_euclid(86400);
+    // days to civil date, from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146_096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2) / 153;
+    let day = doy - (153*mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era*400 + if month <= 2 { 1 } else { 0 };
+    write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+      year, month, day, secs/3600, (secs/60)%60, secs%60)
+  }
+}
+
+// unit variants for the enums above, so that they serialize as plain strings such as "node"
+// instead of the empty arrays that their tuple variants would give
+#[cfg(feature="serde")]
+mod serde_repr {
+  #[derive(serde::Serialize,serde::Deserialize)]
+  #[serde(rename="DatasetType", rename_all="lowercase")]
+  pub enum DatasetType { Node, Way, Relation, BBox, Timestamp, Header, Sync, Jump, Reset }
+
+  impl From<super::DatasetType> for DatasetType {
+    fn from(t: super::DatasetType) -> Self {
+      match t {
+        super::DatasetType::Node() => Self::Node,
+        super::DatasetType::Way() => Self::Way,
+        super::DatasetType::Relation() => Self::Relation,
+        super::DatasetType::BBox() => Self::BBox,
+        super::DatasetType::Timestamp() => Self::Timestamp,
+        super::DatasetType::Header() => Self::Header,
+        super::DatasetType::Sync() => Self::Sync,
+        super::DatasetType::Jump() => Self::Jump,
+        super::DatasetType::Reset() => Self::Reset,
+      }
+    }
+  }
+
+  impl From<DatasetType> for super::DatasetType {
+    fn from(t: DatasetType) -> Self {
+      match t {
+        DatasetType::Node => Self::Node(),
+        DatasetType::Way => Self::Way(),
+        DatasetType::Relation => Self::Relation(),
+        DatasetType::BBox => Self::BBox(),
+        DatasetType::Timestamp => Self::Timestamp(),
+        DatasetType::Header => Self::Header(),
+        DatasetType::Sync => Self::Sync(),
+        DatasetType::Jump => Self::Jump(),
+        DatasetType::Reset => Self::Reset(),
+      }
+    }
+  }
+
+  #[derive(serde::Serialize,serde::Deserialize)]
+  #[serde(rename="ElementType", rename_all="lowercase")]
+  pub enum ElementType { Node, Way, Relation }
+
+  impl From<super::ElementType> for ElementType {
+    fn from(t: super::ElementType) -> Self {
+      match t {
+        super::ElementType::Node() => Self::Node,
+        super::ElementType::Way() => Self::Way,
+        super::ElementType::Relation() => Self::Relation,
+      }
+    }
+  }
+
+  impl From<ElementType> for super::ElementType {
+    fn from(t: ElementType) -> Self {
+      match t {
+        ElementType::Node => Self::Node(),
+        ElementType::Way => Self::Way(),
+        ElementType::Relation => Self::Relation(),
+      }
+    }
+  }
+}
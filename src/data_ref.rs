@@ -0,0 +1,264 @@
+use crate::parse::{Span,StringTable};
+use crate::{
+  Dataset,Header,Info,Node,NodeData,Way,WayData,Relation,RelationData,RelationMember,
+  Delete,BBox,Timestamp,ElementType,Tags,
+};
+
+// bytes that spans point into: the dataset being decoded and the string table
+#[derive(Clone,Copy)]
+pub(crate) struct Source<'a> {
+  pub chunk: &'a [u8],
+  pub strings: &'a StringTable,
+}
+
+impl<'a> Source<'a> {
+  pub fn get_str(&self, span: &Span) -> &'a str {
+    self.strings.get_str(self.chunk, span)
+  }
+}
+
+/// Borrowed view of a decoded dataset. Strings point into the input and the decoder's string
+/// table, so nothing is allocated until an owned `Dataset` is built with `to_dataset`.
+#[derive(Clone,Debug)]
+pub enum DatasetRef<'a> {
+  Header(HeaderRef<'a>),
+  Node(NodeRef<'a>),
+  Way(WayRef<'a>),
+  Relation(RelationRef<'a>),
+  Delete(DeleteRef<'a>),
+  BBox(BBox),
+  Timestamp(Timestamp),
+}
+impl<'a> DatasetRef<'a> {
+  pub fn get_id(&self) -> Option<u64> {
+    match self {
+      Self::Node(node) => Some(node.id),
+      Self::Way(way) => Some(way.id),
+      Self::Relation(relation) => Some(relation.id),
+      Self::Delete(delete) => Some(delete.id),
+      _ => None,
+    }
+  }
+  pub fn get_type(&self) -> Option<ElementType> {
+    match self {
+      Self::Node(_) => Some(ElementType::Node()),
+      Self::Way(_) => Some(ElementType::Way()),
+      Self::Relation(_) => Some(ElementType::Relation()),
+      Self::Delete(delete) => Some(delete.element_type.clone()),
+      _ => None,
+    }
+  }
+  pub fn to_dataset(&self) -> Dataset {
+    match self {
+      Self::Header(header) => Dataset::Header(header.to_header()),
+      Self::Node(node) => Dataset::Node(node.to_node()),
+      Self::Way(way) => Dataset::Way(way.to_way()),
+      Self::Relation(relation) => Dataset::Relation(relation.to_relation()),
+      Self::Delete(delete) => Dataset::Delete(delete.to_delete()),
+      Self::BBox(bbox) => Dataset::BBox(bbox.clone()),
+      Self::Timestamp(timestamp) => Dataset::Timestamp(timestamp.clone()),
+    }
+  }
+}
+
+#[derive(Clone,PartialEq,Debug)]
+pub struct HeaderRef<'a> {
+  pub format: &'a str,
+}
+impl<'a> HeaderRef<'a> {
+  pub fn to_header(&self) -> Header {
+    Header { format: self.format.to_string() }
+  }
+}
+
+#[derive(Clone,PartialEq,Debug)]
+pub struct InfoRef<'a> {
+  pub version: Option<u64>,
+  pub timestamp: Option<i64>,
+  pub changeset: Option<u64>,
+  pub uid: Option<u64>,
+  pub user: Option<&'a str>,
+}
+impl<'a> InfoRef<'a> {
+  pub fn to_info(&self) -> Info {
+    Info {
+      version: self.version,
+      timestamp: self.timestamp,
+      changeset: self.changeset,
+      uid: self.uid,
+      user: self.user.map(|user| user.to_string()),
+    }
+  }
+}
+
+#[derive(Clone,Debug)]
+pub struct NodeRef<'a> {
+  pub id: u64,
+  pub info: Option<InfoRef<'a>>,
+  pub data: NodeData,
+  pub tags: TagsRef<'a>,
+}
+impl<'a> NodeRef<'a> {
+  pub fn to_node(&self) -> Node {
+    Node {
+      id: self.id,
+      info: self.info.as_ref().map(|info| info.to_info()),
+      data: Some(self.data.clone()),
+      tags: self.tags.to_tags(),
+    }
+  }
+}
+
+#[derive(Clone,Debug)]
+pub struct WayRef<'a> {
+  pub id: u64,
+  pub info: Option<InfoRef<'a>>,
+  pub refs: &'a [u64],
+  pub tags: TagsRef<'a>,
+}
+impl<'a> WayRef<'a> {
+  pub fn to_way(&self) -> Way {
+    Way {
+      id: self.id,
+      info: self.info.as_ref().map(|info| info.to_info()),
+      data: Some(WayData { refs: self.refs.to_vec() }),
+      tags: self.tags.to_tags(),
+    }
+  }
+}
+
+#[derive(Clone,Debug)]
+pub struct RelationRef<'a> {
+  pub id: u64,
+  pub info: Option<InfoRef<'a>>,
+  pub members: MembersRef<'a>,
+  pub tags: TagsRef<'a>,
+}
+impl<'a> RelationRef<'a> {
+  pub fn to_relation(&self) -> Relation {
+    Relation {
+      id: self.id,
+      info: self.info.as_ref().map(|info| info.to_info()),
+      data: Some(RelationData { members: self.members.to_members() }),
+      tags: self.tags.to_tags(),
+    }
+  }
+}
+
+#[derive(Clone,PartialEq,Debug)]
+pub struct DeleteRef<'a> {
+  pub id: u64,
+  pub element_type: ElementType,
+  pub info: Option<InfoRef<'a>>,
+}
+impl<'a> DeleteRef<'a> {
+  pub fn to_delete(&self) -> Delete {
+    Delete {
+      id: self.id,
+      element_type: self.element_type.clone(),
+      info: self.info.as_ref().map(|info| info.to_info()),
+    }
+  }
+}
+
+/// Tags of an element as borrowed key and value pairs, in the order they were encoded.
+#[derive(Clone,Copy)]
+pub struct TagsRef<'a> {
+  pub(crate) source: Source<'a>,
+  pub(crate) spans: &'a [(Span,Span)],
+}
+impl<'a> TagsRef<'a> {
+  pub fn iter(&self) -> TagsIter<'a> {
+    TagsIter { source: self.source, spans: self.spans.iter() }
+  }
+  pub fn len(&self) -> usize { self.spans.len() }
+  pub fn is_empty(&self) -> bool { self.spans.is_empty() }
+  /// Value of the last tag with `key`, which is the one an owned `Tags` map would keep.
+  pub fn get(&self, key: &str) -> Option<&'a str> {
+    self.iter().filter(|(k,_)| *k == key).last().map(|(_,v)| v)
+  }
+  pub fn to_tags(&self) -> Tags {
+    self.iter().map(|(k,v)| (k.to_string(),v.to_string())).collect()
+  }
+}
+impl<'a> IntoIterator for TagsRef<'a> {
+  type Item = (&'a str,&'a str);
+  type IntoIter = TagsIter<'a>;
+  fn into_iter(self) -> Self::IntoIter { self.iter() }
+}
+impl<'a> std::fmt::Debug for TagsRef<'a> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_map().entries(self.iter()).finish()
+  }
+}
+
+pub struct TagsIter<'a> {
+  source: Source<'a>,
+  spans: std::slice::Iter<'a,(Span,Span)>,
+}
+impl<'a> Iterator for TagsIter<'a> {
+  type Item = (&'a str,&'a str);
+  fn next(&mut self) -> Option<Self::Item> {
+    self.spans.next().map(|(k,v)| (self.source.get_str(k),self.source.get_str(v)))
+  }
+  fn size_hint(&self) -> (usize,Option<usize>) { self.spans.size_hint() }
+}
+
+#[derive(Clone,PartialEq,Debug)]
+pub struct RelationMemberRef<'a> {
+  pub id: u64,
+  pub element_type: ElementType,
+  pub role: &'a str,
+}
+impl<'a> RelationMemberRef<'a> {
+  pub fn to_member(&self) -> RelationMember {
+    RelationMember {
+      id: self.id,
+      element_type: self.element_type.clone(),
+      role: self.role.to_string(),
+    }
+  }
+}
+
+/// Members of a relation with borrowed roles.
+#[derive(Clone,Copy)]
+pub struct MembersRef<'a> {
+  pub(crate) source: Source<'a>,
+  pub(crate) members: &'a [(u64,ElementType,Span)],
+}
+impl<'a> MembersRef<'a> {
+  pub fn iter(&self) -> MembersIter<'a> {
+    MembersIter { source: self.source, members: self.members.iter() }
+  }
+  pub fn len(&self) -> usize { self.members.len() }
+  pub fn is_empty(&self) -> bool { self.members.is_empty() }
+  pub fn to_members(&self) -> Vec<RelationMember> {
+    self.iter().map(|m| m.to_member()).collect()
+  }
+}
+impl<'a> IntoIterator for MembersRef<'a> {
+  type Item = RelationMemberRef<'a>;
+  type IntoIter = MembersIter<'a>;
+  fn into_iter(self) -> Self::IntoIter { self.iter() }
+}
+impl<'a> std::fmt::Debug for MembersRef<'a> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_list().entries(self.iter()).finish()
+  }
+}
+
+pub struct MembersIter<'a> {
+  source: Source<'a>,
+  members: std::slice::Iter<'a,(u64,ElementType,Span)>,
+}
+impl<'a> Iterator for MembersIter<'a> {
+  type Item = RelationMemberRef<'a>;
+  fn next(&mut self) -> Option<Self::Item> {
+    self.members.next().map(|(id,element_type,role)| RelationMemberRef {
+      id: *id,
+      element_type: element_type.clone(),
+      role: self.source.get_str(role),
+    })
+  }
+  fn size_hint(&self) -> (usize,Option<usize>) { self.members.size_hint() }
+}
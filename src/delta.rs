@@ -0,0 +1,365 @@
+use crate::parse::{self,Span,StringTable,InfoSpan};
+use crate::data_ref::*;
+use crate::{
+  Coord,DatasetType,DecodeError,DecoderOptions,ErrorContext,ElementType,NodeData,BBox,Timestamp,
+  Header,TagFilter,
+};
+
+// only the parts of the previous dataset that serve as a delta base for the next one
+#[derive(Clone,PartialEq,Debug)]
+pub(crate) enum Prev { Node(i32,i32), Way(u64), Relation(u64), Other() }
+
+// the most recently parsed dataset. strings stay in the frame or the string table as spans and
+// the variable length parts are in the scratch vectors of `DeltaState`.
+enum Parsed {
+  Header(),
+  Node(u64,Option<InfoSpan>,NodeData),
+  Way(u64,Option<InfoSpan>),
+  Relation(u64,Option<InfoSpan>),
+  Delete(u64,ElementType,Option<InfoSpan>),
+  BBox(BBox),
+  Timestamp(Timestamp),
+}
+
+// delta coding and string table state carried from one dataset to the next
+pub(crate) struct DeltaState {
+  element_types: Option<Vec<ElementType>>,
+  tag_filter: Option<TagFilter>,
+  // keep the delta base of elements that are not emitted
+  base: bool,
+  pub strings: StringTable,
+  pub header: Option<Header>,
+  pub jump: Option<u64>,
+  pub last_id: Option<u64>,
+  prev_id: Option<u64>,
+  prev_timestamp: i64,
+  prev_changeset: u64,
+  prev: Option<Prev>,
+  parsed: Option<Parsed>,
+  tags: Vec<(Span,Span)>,
+  refs: Vec<u64>,
+  members: Vec<(u64,ElementType,Span)>,
+}
+
+impl DeltaState {
+  pub fn new(options: &DecoderOptions) -> Self {
+    Self {
+      element_types: options.element_types.clone(),
+      tag_filter: options.tag_filter.clone(),
+      base: false,
+      strings: StringTable::new(),
+      header: None,
+      jump: None,
+      last_id: None,
+      prev_id: None,
+      prev_timestamp: 0,
+      prev_changeset: 0,
+      prev: None,
+      parsed: None,
+      tags: vec![],
+      refs: vec![],
+      members: vec![],
+    }
+  }
+  // state that only follows the delta base and string table from one dataset to the next,
+  // without emitting any
+  pub fn tracker() -> Self {
+    Self {
+      element_types: Some(vec![]),
+      base: true,
+      ..Self::new(&DecoderOptions::default())
+    }
+  }
+  // state for `options` that carries on from the delta and string state of `self`
+  pub fn resume(&self, options: &DecoderOptions) -> Self {
+    Self {
+      strings: self.strings.clone(),
+      last_id: self.last_id,
+      prev_id: self.prev_id,
+      prev_timestamp: self.prev_timestamp,
+      prev_changeset: self.prev_changeset,
+      prev: self.prev.clone(),
+      ..Self::new(options)
+    }
+  }
+  // a 0xff reset byte clears all delta and string state
+  pub fn reset(&mut self) {
+    self.strings.clear();
+    self.prev = None;
+    self.prev_id = None;
+    self.prev_timestamp = 0;
+    self.prev_changeset = 0;
+  }
+  pub fn parsed_type(&self) -> Option<ElementType> {
+    match self.parsed.as_ref()? {
+      Parsed::Node(..) => Some(ElementType::Node()),
+      Parsed::Way(..) => Some(ElementType::Way()),
+      Parsed::Relation(..) => Some(ElementType::Relation()),
+      Parsed::Delete(_,element_type,_) => Some(element_type.clone()),
+      _ => None,
+    }
+  }
+  fn emits(&self, element_type: &ElementType) -> bool {
+    match &self.element_types {
+      Some(types) => types.contains(element_type),
+      None => true,
+    }
+  }
+  // scan the body of an element that is not emitted for the strings it adds to the table.
+  // its coordinates, refs and member ids are only a delta base for elements of the same type,
+  // which are not emitted either, so they are skipped unless `base` is set.
+  fn skip(&mut self, element_type: &ElementType, buf: &[u8], offset: usize, id: u64,
+  info: &Option<InfoSpan>) -> Result<bool,DecodeError> {
+    let mut offset = offset;
+    if offset < buf.len() {
+      let mut prev = Prev::Other();
+      match element_type {
+        ElementType::Node() => {
+          let (plon,plat) = match &self.prev {
+            Some(Prev::Node(lon,lat)) => (*lon,*lat),
+            _ => (0,0),
+          };
+          let (s,lon) = parse::signed(&buf[offset..])?;
+          offset += s;
+          let (s,lat) = parse::signed(&buf[offset..])?;
+          offset += s;
+          let lon = lon.wrapping_add(plon as i64) as i32;
+          let lat = lat.wrapping_add(plat as i64) as i32;
+          prev = Prev::Node(lon,lat);
+        },
+        ElementType::Way() if self.base => {
+          let (s,reflen) = parse::unsigned(&buf[offset..])?;
+          let prev_ref = match &self.prev {
+            Some(Prev::Way(r)) => *r,
+            _ => 0,
+          };
+          self.refs.clear();
+          offset = parse::refs(buf, offset + s, reflen as usize, prev_ref, &mut self.refs)?;
+          prev = Prev::Way(self.refs.last().copied().unwrap_or(0));
+        },
+        ElementType::Way() => {
+          let (s,reflen) = parse::unsigned(&buf[offset..])?;
+          offset = (offset + s).saturating_add(reflen as usize);
+        },
+        ElementType::Relation() if self.base => {
+          let (s,reflen) = parse::unsigned(&buf[offset..])?;
+          let prev_id = match &self.prev {
+            Some(Prev::Relation(id)) => *id,
+            _ => 0,
+          };
+          self.members.clear();
+          offset = parse::members(
+            buf, offset + s, reflen as usize, prev_id, &mut self.strings, Some(&mut self.members)
+          )?;
+          prev = Prev::Relation(self.members.last().map(|m| m.0).unwrap_or(0));
+        },
+        ElementType::Relation() => {
+          let (s,reflen) = parse::unsigned(&buf[offset..])?;
+          offset = parse::members(buf, offset + s, reflen as usize, 0, &mut self.strings, None)?;
+        },
+      }
+      parse::tags(buf, offset, &mut self.strings, None, None)?;
+      self.prev = Some(if self.base { prev } else { Prev::Other() });
+    }
+    self.update(id, info);
+    Ok(false)
+  }
+  fn update(&mut self, id: u64, info: &Option<InfoSpan>) {
+    self.prev_id = Some(id);
+    self.last_id = Some(id);
+    if let Some(info) = info {
+      self.prev_timestamp = info.timestamp.unwrap_or(0);
+      self.prev_changeset = info.changeset.unwrap_or(0);
+    }
+  }
+  // parse `buf`, the payload of a dataset of `data_type` that starts at byte `frame_offset`.
+  // returns whether the dataset can be returned from `view`.
+  pub fn parse(&mut self, data_type: &Option<DatasetType>, buf: &[u8], frame_offset: u64)
+  -> Result<bool,DecodeError> {
+    self.parsed = None;
+    self.strings.start();
+    let parsed = match data_type {
+      Some(DatasetType::Node()) => {
+        let (mut offset,id,info) = parse::info(
+          buf, 0, self.prev_id, self.prev_timestamp, self.prev_changeset, &mut self.strings
+        )?;
+        if !self.emits(&ElementType::Node()) {
+          return self.skip(&ElementType::Node(), buf, offset, id, &info);
+        }
+        if offset == buf.len() {
+          self.update(id, &info);
+          Parsed::Delete(id, ElementType::Node(), info)
+        } else {
+          let (plon,plat) = match &self.prev {
+            Some(Prev::Node(lon,lat)) => (*lon,*lat),
+            _ => (0,0),
+          };
+          let longitude = {
+            let (s,x) = parse::signed(&buf[offset..])?;
+            offset += s;
+            Coord(x.wrapping_add(plon as i64) as i32)
+          };
+          let latitude = {
+            let (s,x) = parse::signed(&buf[offset..])?;
+            offset += s;
+            Coord(x.wrapping_add(plat as i64) as i32)
+          };
+          self.tags.clear();
+          let filter = self.tag_filter.as_ref();
+          parse::tags(buf, offset, &mut self.strings, Some(&mut self.tags), filter)?;
+          self.update(id, &info);
+          self.prev = Some(Prev::Node(longitude.0,latitude.0));
+          Parsed::Node(id, info, NodeData { longitude, latitude })
+        }
+      },
+      Some(DatasetType::Way()) => {
+        let (mut offset,id,info) = parse::info(
+          buf, 0, self.prev_id, self.prev_timestamp, self.prev_changeset, &mut self.strings
+        )?;
+        if !self.emits(&ElementType::Way()) {
+          return self.skip(&ElementType::Way(), buf, offset, id, &info);
+        }
+        if offset == buf.len() {
+          self.update(id, &info);
+          Parsed::Delete(id, ElementType::Way(), info)
+        } else {
+          // reflen is the number of BYTES, not the number of refs
+          let (s,reflen) = parse::unsigned(&buf[offset..])?;
+          offset += s;
+          let prev_ref = match &self.prev {
+            Some(Prev::Way(r)) => *r,
+            _ => 0,
+          };
+          self.refs.clear();
+          offset = parse::refs(buf, offset, reflen as usize, prev_ref, &mut self.refs)?;
+          self.tags.clear();
+          let filter = self.tag_filter.as_ref();
+          parse::tags(buf, offset, &mut self.strings, Some(&mut self.tags), filter)?;
+          self.update(id, &info);
+          self.prev = Some(Prev::Way(self.refs.last().copied().unwrap_or(0)));
+          Parsed::Way(id, info)
+        }
+      },
+      Some(DatasetType::Relation()) => {
+        let (mut offset,id,info) = parse::info(
+          buf, 0, self.prev_id, self.prev_timestamp, self.prev_changeset, &mut self.strings
+        )?;
+        if !self.emits(&ElementType::Relation()) {
+          return self.skip(&ElementType::Relation(), buf, offset, id, &info);
+        }
+        if offset == buf.len() {
+          self.update(id, &info);
+          Parsed::Delete(id, ElementType::Relation(), info)
+        } else {
+          // reflen is the number of BYTES, not the number of refs
+          let (s,reflen) = parse::unsigned(&buf[offset..])?;
+          offset += s;
+          let prev_id = match &self.prev {
+            Some(Prev::Relation(id)) => *id,
+            _ => 0,
+          };
+          self.members.clear();
+          offset = parse::members(
+            buf, offset, reflen as usize, prev_id, &mut self.strings, Some(&mut self.members)
+          )?;
+          self.tags.clear();
+          let filter = self.tag_filter.as_ref();
+          parse::tags(buf, offset, &mut self.strings, Some(&mut self.tags), filter)?;
+          self.update(id, &info);
+          self.prev = Some(Prev::Relation(self.members.last().map(|m| m.0).unwrap_or(0)));
+          Parsed::Relation(id, info)
+        }
+      },
+      Some(DatasetType::Timestamp()) => {
+        let (_,time) = parse::signed(buf)?;
+        self.prev = Some(Prev::Other());
+        Parsed::Timestamp(Timestamp { time })
+      },
+      Some(DatasetType::BBox()) => {
+        let mut offset = 0;
+        let (s,x1) = parse::signed(&buf[offset..])?;
+        offset += s;
+        let (s,y1) = parse::signed(&buf[offset..])?;
+        offset += s;
+        let (s,x2) = parse::signed(&buf[offset..])?;
+        offset += s;
+        let (_,y2) = parse::signed(&buf[offset..])?;
+        self.prev = Some(Prev::Other());
+        Parsed::BBox(BBox {
+          x1: Coord(x1 as i32),
+          y1: Coord(y1 as i32),
+          x2: Coord(x2 as i32),
+          y2: Coord(y2 as i32),
+        })
+      },
+      Some(DatasetType::Header()) => {
+        let format = std::str::from_utf8(buf)
+          .map_err(|e| DecodeError::StringEncodingError {
+            source: Box::new(e.into()),
+            context: ErrorContext::default(),
+          })?;
+        self.header = Some(Header { format: format.to_string() });
+        Parsed::Header()
+      },
+      Some(DatasetType::Jump()) => {
+        // forward and backward distances from the start of this dataset to the neighbouring
+        // jump datasets, as 4-byte big-endian integers. 0 means there is no next jump.
+        if buf.len() >= 4 {
+          let forward = u32::from_be_bytes([buf[0],buf[1],buf[2],buf[3]]);
+          self.jump = match forward {
+            0 => None,
+            x => Some(frame_offset + (x as u64)),
+          };
+        }
+        return Ok(false);
+      },
+      Some(DatasetType::Sync()) => return Ok(false),
+      Some(DatasetType::Reset()) => return Ok(false),
+      None => return Ok(false),
+    };
+    self.parsed = Some(parsed);
+    Ok(true)
+  }
+  // borrowed view of the last parsed dataset, whose payload is `buf`
+  pub fn view<'a>(&'a self, buf: &'a [u8]) -> Option<DatasetRef<'a>> {
+    let source = Source { chunk: buf, strings: &self.strings };
+    let info = |info: &Option<InfoSpan>| info.as_ref().map(|info| InfoRef {
+      version: Some(info.version),
+      timestamp: info.timestamp,
+      changeset: info.changeset,
+      uid: info.uid,
+      user: info.user.as_ref().map(|user| source.get_str(user)),
+    });
+    let tags = TagsRef { source, spans: &self.tags };
+    Some(match self.parsed.as_ref()? {
+      Parsed::Header() => DatasetRef::Header(HeaderRef {
+        format: std::str::from_utf8(buf).unwrap_or_default(),
+      }),
+      Parsed::Node(id,i,data) => DatasetRef::Node(NodeRef {
+        id: *id,
+        info: info(i),
+        data: data.clone(),
+        tags,
+      }),
+      Parsed::Way(id,i) => DatasetRef::Way(WayRef {
+        id: *id,
+        info: info(i),
+        refs: &self.refs,
+        tags,
+      }),
+      Parsed::Relation(id,i) => DatasetRef::Relation(RelationRef {
+        id: *id,
+        info: info(i),
+        members: MembersRef { source, members: &self.members },
+        tags,
+      }),
+      Parsed::Delete(id,element_type,i) => DatasetRef::Delete(DeleteRef {
+        id: *id,
+        element_type: element_type.clone(),
+        info: info(i),
+      }),
+      Parsed::BBox(bbox) => DatasetRef::BBox(bbox.clone()),
+      Parsed::Timestamp(timestamp) => DatasetRef::Timestamp(timestamp.clone()),
+    })
+  }
+}
@@ -0,0 +1,413 @@
+use futures::{prelude::*,stream::Stream,io};
+use std::collections::{HashMap,VecDeque};
+use crate::{Dataset,ElementType,ErrorTrace,Info,Tags,Error};
+use crate::delta::Prev;
+
+#[derive(thiserror::Error,Debug)]
+pub enum EncodeError {
+  #[error("{info:?} contains a 0x00 byte and cannot be encoded")]
+  UnexpectedNullByte {
+    info: String,
+    backtrace: ErrorTrace,
+  },
+  #[error("{info:?} contains the control character 0x{byte:02x} that xml cannot represent")]
+  UnexpectedControlCharacter {
+    info: String,
+    byte: u8,
+    backtrace: ErrorTrace,
+  },
+  #[error("{info}")]
+  UnsupportedDataset {
+    info: String,
+    backtrace: ErrorTrace,
+  },
+  #[error("stream write error {source:?}")]
+  StreamWriteError { #[source] source: Box<Error> },
+}
+
+// mirror of the decoder's back-reference table so that the indexes written by the encoder
+// resolve to the same strings on the decoding side
+struct StringTable {
+  index: HashMap<(Vec<u8>,Vec<u8>),usize>,
+  order: VecDeque<(Vec<u8>,Vec<u8>)>,
+  count: usize,
+}
+
+impl StringTable {
+  fn new() -> Self {
+    Self { index: HashMap::new(), order: VecDeque::new(), count: 0 }
+  }
+  fn get(&self, pair: &(Vec<u8>,Vec<u8>)) -> Option<usize> {
+    self.index.get(pair).map(|n| self.count - n + 1)
+  }
+  fn push(&mut self, pair: (Vec<u8>,Vec<u8>)) {
+    self.count += 1;
+    self.index.insert(pair.clone(), self.count);
+    self.order.push_front(pair);
+    if self.order.len() > 15_000 {
+      if let Some(p) = self.order.pop_back() { self.index.remove(&p); }
+    }
+  }
+  fn clear(&mut self) {
+    self.index.clear();
+    self.order.clear();
+    self.count = 0;
+  }
+}
+
+/// Options for `Encoder::with_options` and `encode_with_options`.
+#[derive(Clone,PartialEq,Debug,Default)]
+pub struct EncoderOptions {
+  /// Also write a 0xff reset after every this many elements and deletes, so that
+  /// `ParallelDecoder` can split the output between threads. A few thousand is a good start:
+  /// every reset clears the string table and delta state, so smaller sections make the file
+  /// larger. With `None`, the encoder only resets when the element type changes.
+  pub reset_interval: Option<usize>,
+}
+
+/// Incremental o5m encoder. Each call to `write_dataset` appends one frame to `buf`, emitting
+/// the file header and the 0xff reset markers between sections as needed.
+///
+/// Decoding the output gives back the same datasets, except that the output always starts with
+/// a header: a stream without a `Dataset::Header` reads back with an "o5m2" header in front.
+/// `Info` fields that o5m cannot leave out differ too. Info without a version is not written at all, and neither is anything after a
+/// missing timestamp. With a version and a timestamp the author is always written, so a
+/// missing `uid` or `user` reads back as `Some(0)` or `Some("")`. Datasets that would read back
+/// as something else are an `EncodeError::UnsupportedDataset`: a header after the start of the
+/// stream, a node, way or relation without data, which o5m only writes as a delete, and a
+/// `Dataset::Delete` in a stream that did not start with an o5c header.
+pub struct Encoder {
+  options: EncoderOptions,
+  // elements and deletes written since the last reset
+  count: usize,
+  begun: bool,
+  // the stream started with an o5c header, so deletes can be written
+  change: bool,
+  strings: StringTable,
+  element_type: Option<ElementType>,
+  prev: Option<Prev>,
+  prev_id: Option<u64>,
+  prev_timestamp: i64,
+  prev_changeset: u64,
+}
+
+impl Encoder {
+  pub fn new() -> Self {
+    Self::with_options(EncoderOptions::default())
+  }
+  pub fn with_options(options: EncoderOptions) -> Self {
+    Self {
+      options,
+      count: 0,
+      begun: false,
+      change: false,
+      strings: StringTable::new(),
+      element_type: None,
+      prev: None,
+      prev_id: None,
+      prev_timestamp: 0,
+      prev_changeset: 0,
+    }
+  }
+  /// Write the leading reset byte and the "o5m2" header dataset if they have not been written.
+  /// A `Dataset::Header` passed to `write_dataset` before anything else replaces the default
+  /// header.
+  pub fn begin(&mut self, buf: &mut Vec<u8>) {
+    if self.begun { return }
+    self.begun = true;
+    buf.push(0xff);
+    buf.push(0xe0);
+    unsigned(buf, 4);
+    buf.extend_from_slice(b"o5m2");
+  }
+  /// Write a 0xff reset marker and clear all delta and string state.
+  pub fn reset(&mut self, buf: &mut Vec<u8>) {
+    self.begin(buf);
+    buf.push(0xff);
+    self.count = 0;
+    self.strings.clear();
+    self.element_type = None;
+    self.prev = None;
+    self.prev_id = None;
+    self.prev_timestamp = 0;
+    self.prev_changeset = 0;
+  }
+  pub fn write_dataset(&mut self, dataset: &Dataset, buf: &mut Vec<u8>) -> Result<(),EncodeError> {
+    let unsupported = match dataset {
+      Dataset::Header(header) if self.begun => {
+        Some(format!("header {:?} after the start of the stream", header.format))
+      },
+      Dataset::Node(node) if node.data.is_none() => Some(format!("node {} without data", node.id)),
+      Dataset::Way(way) if way.data.is_none() => Some(format!("way {} without data", way.id)),
+      Dataset::Relation(relation) if relation.data.is_none() => {
+        Some(format!("relation {} without data", relation.id))
+      },
+      Dataset::Delete(delete) if !self.change => {
+        Some(format!("delete of {:?} {} in an o5m stream without an o5c header",
+          delete.element_type, delete.id))
+      },
+      _ => None,
+    };
+    if let Some(info) = unsupported {
+      return Err(EncodeError::UnsupportedDataset { info, backtrace: ErrorTrace::capture() });
+    }
+    if let (false,Dataset::Header(header)) = (self.begun,dataset) {
+      self.change = header.is_change();
+      self.begun = true;
+      buf.push(0xff);
+    }
+    self.begin(buf);
+    let element_type = match dataset {
+      Dataset::Node(_) => Some(ElementType::Node()),
+      Dataset::Way(_) => Some(ElementType::Way()),
+      Dataset::Relation(_) => Some(ElementType::Relation()),
+      Dataset::Delete(delete) => Some(delete.element_type.clone()),
+      _ => None,
+    };
+    if element_type.is_some() && self.element_type.is_some() && element_type != self.element_type {
+      self.reset(buf);
+    }
+    if element_type.is_some() {
+      let interval = self.options.reset_interval.filter(|n| *n > 0);
+      if interval.map(|n| self.count >= n).unwrap_or(false) {
+        self.reset(buf);
+      }
+      self.count += 1;
+      self.element_type = element_type;
+    }
+    let mut body = vec![];
+    let (b,prev) = match dataset {
+      Dataset::Header(header) => {
+        body.extend_from_slice(header.format.as_bytes());
+        (0xe0,None)
+      },
+      Dataset::Node(node) => {
+        self.info(&mut body, node.id, &node.info)?;
+        let prev = match &node.data {
+          None => None,
+          Some(data) => {
+            let (plon,plat) = match &self.prev {
+              Some(Prev::Node(lon,lat)) => (*lon,*lat),
+              _ => (0,0),
+            };
+            signed(&mut body, (data.longitude.0 as i64) - (plon as i64));
+            signed(&mut body, (data.latitude.0 as i64) - (plat as i64));
+            self.tags(&mut body, &node.tags)?;
+            Some(Prev::Node(data.longitude.0,data.latitude.0))
+          },
+        };
+        (0x10,prev)
+      },
+      Dataset::Way(way) => {
+        self.info(&mut body, way.id, &way.info)?;
+        let prev = match &way.data {
+          None => None,
+          Some(data) => {
+            let mut prev_ref = match &self.prev {
+              Some(Prev::Way(r)) => *r,
+              _ => 0,
+            };
+            let mut refs = vec![];
+            for r in data.refs.iter() {
+              signed(&mut refs, (*r as i64).wrapping_sub(prev_ref as i64));
+              prev_ref = *r;
+            }
+            // reflen is the number of BYTES, not the number of refs
+            unsigned(&mut body, refs.len() as u64);
+            body.extend_from_slice(&refs);
+            self.tags(&mut body, &way.tags)?;
+            Some(Prev::Way(data.refs.last().copied().unwrap_or(0)))
+          },
+        };
+        (0x11,prev)
+      },
+      Dataset::Relation(relation) => {
+        self.info(&mut body, relation.id, &relation.info)?;
+        let prev = match &relation.data {
+          None => None,
+          Some(data) => {
+            let mut prev_id = match &self.prev {
+              Some(Prev::Relation(id)) => *id,
+              _ => 0,
+            };
+            let mut refs = vec![];
+            for m in data.members.iter() {
+              signed(&mut refs, (m.id as i64).wrapping_sub(prev_id as i64));
+              prev_id = m.id;
+              let mut mstring = vec![match m.element_type {
+                ElementType::Node() => 0x30,
+                ElementType::Way() => 0x31,
+                ElementType::Relation() => 0x32,
+              }];
+              mstring.extend_from_slice(m.role.as_bytes());
+              check_null(&mstring, "relation member role")?;
+              self.string(&mut refs, mstring, vec![], false);
+            }
+            // reflen is the number of BYTES, not the number of refs
+            unsigned(&mut body, refs.len() as u64);
+            body.extend_from_slice(&refs);
+            self.tags(&mut body, &relation.tags)?;
+            Some(Prev::Relation(data.members.last().map(|m| m.id).unwrap_or(0)))
+          },
+        };
+        (0x12,prev)
+      },
+      Dataset::Delete(delete) => {
+        // a deleted element is only its id and info, with no body
+        self.info(&mut body, delete.id, &delete.info)?;
+        let b = match delete.element_type {
+          ElementType::Node() => 0x10,
+          ElementType::Way() => 0x11,
+          ElementType::Relation() => 0x12,
+        };
+        (b,None)
+      },
+      Dataset::Timestamp(timestamp) => {
+        signed(&mut body, timestamp.time);
+        (0xdc,Some(Prev::Other()))
+      },
+      Dataset::BBox(bbox) => {
+        signed(&mut body, bbox.x1.0 as i64);
+        signed(&mut body, bbox.y1.0 as i64);
+        signed(&mut body, bbox.x2.0 as i64);
+        signed(&mut body, bbox.y2.0 as i64);
+        (0xdb,Some(Prev::Other()))
+      },
+    };
+    buf.push(b);
+    unsigned(buf, body.len() as u64);
+    buf.extend_from_slice(&body);
+    if prev.is_some() {
+      self.prev = prev;
+    }
+    if let Some(id) = dataset.get_id() {
+      self.prev_id = Some(id);
+    }
+    Ok(())
+  }
+  fn info(&mut self, buf: &mut Vec<u8>, id: u64, info: &Option<Info>) -> Result<(),EncodeError> {
+    signed(buf, (id as i64).wrapping_sub(self.prev_id.unwrap_or(0) as i64));
+    let info = match info {
+      Some(info) if info.version.unwrap_or(0) != 0 => info,
+      _ => {
+        unsigned(buf, 0);
+        return Ok(());
+      },
+    };
+    unsigned(buf, info.version.unwrap_or(0));
+    let timestamp = info.timestamp.unwrap_or(0);
+    signed(buf, timestamp.wrapping_sub(self.prev_timestamp));
+    if timestamp == 0 {
+      self.prev_timestamp = 0;
+      self.prev_changeset = 0;
+      return Ok(());
+    }
+    let changeset = info.changeset.unwrap_or(0);
+    signed(buf, (changeset as i64).wrapping_sub(self.prev_changeset as i64));
+    self.prev_timestamp = timestamp;
+    self.prev_changeset = changeset;
+    let mut uid_bytes = vec![];
+    unsigned(&mut uid_bytes, info.uid.unwrap_or(0));
+    let user = info.user.as_deref().unwrap_or("").as_bytes();
+    check_null(user, "user")?;
+    self.string(buf, uid_bytes, user.to_vec(), true);
+    Ok(())
+  }
+  fn tags(&mut self, buf: &mut Vec<u8>, tags: &Tags) -> Result<(),EncodeError> {
+    for (key,value) in tags.iter() {
+      check_null(key.as_bytes(), "tag key")?;
+      check_null(value.as_bytes(), "tag value")?;
+      self.string(buf, key.as_bytes().to_vec(), value.as_bytes().to_vec(), true);
+    }
+    Ok(())
+  }
+  // write a string or string pair either as a back-reference or inline
+  fn string(&mut self, buf: &mut Vec<u8>, a: Vec<u8>, b: Vec<u8>, pair: bool) {
+    let key = (a,b);
+    if let Some(i) = self.strings.get(&key) {
+      unsigned(buf, i as u64);
+      return;
+    }
+    buf.push(0x00);
+    buf.extend_from_slice(&key.0);
+    buf.push(0x00);
+    if pair {
+      buf.extend_from_slice(&key.1);
+      buf.push(0x00);
+    }
+    if key.0.len() + key.1.len() <= 250 {
+      self.strings.push(key);
+    }
+  }
+}
+
+impl Default for Encoder {
+  fn default() -> Self { Self::new() }
+}
+
+fn check_null(bytes: &[u8], info: &str) -> Result<(),EncodeError> {
+  if bytes.contains(&0x00) {
+    return Err(EncodeError::UnexpectedNullByte {
+      info: info.to_string(),
+      backtrace: ErrorTrace::capture(),
+    });
+  }
+  Ok(())
+}
+
+fn signed(buf: &mut Vec<u8>, x: i64) {
+  let z = if x < 0 { ((!(x as u64)) << 1) | 1 } else { (x as u64) << 1 };
+  unsigned(buf, z);
+}
+
+fn unsigned(buf: &mut Vec<u8>, x: u64) {
+  let mut x = x;
+  while x >= 0x80 {
+    buf.push(((x & 0x7f) as u8) | 0x80);
+    x >>= 7;
+  }
+  buf.push(x as u8);
+}
+
+/// Write the `Dataset` items from `stream` to `writer` as o5m.
+/// Any `futures::io::AsyncWrite` works here, including async-std writers.
+pub async fn encode(
+  stream: Box<dyn Stream<Item=Dataset>+Send+Unpin>,
+  writer: Box<dyn io::AsyncWrite+Send+Unpin>,
+) -> Result<(),EncodeError> {
+  encode_with_options(stream, writer, EncoderOptions::default()).await
+}
+
+/// Like `encode`, with `options` to control how the output is written.
+pub async fn encode_with_options(
+  mut stream: Box<dyn Stream<Item=Dataset>+Send+Unpin>,
+  mut writer: Box<dyn io::AsyncWrite+Send+Unpin>,
+  options: EncoderOptions,
+) -> Result<(),EncodeError> {
+  let mut encoder = Encoder::with_options(options);
+  let mut buf = vec![];
+  while let Some(dataset) = stream.next().await {
+    encoder.write_dataset(&dataset, &mut buf)?;
+    if buf.len() >= 4096 {
+      writer.write_all(&buf).await
+        .map_err(|e| EncodeError::StreamWriteError { source: Box::new(e.into()) })?;
+      buf.clear();
+    }
+  }
+  encoder.begin(&mut buf);
+  writer.write_all(&buf).await
+    .map_err(|e| EncodeError::StreamWriteError { source: Box::new(e.into()) })?;
+  writer.flush().await
+    .map_err(|e| EncodeError::StreamWriteError { source: Box::new(e.into()) })?;
+  Ok(())
+}
+
+/// Write the `Dataset` items from `stream` to the tokio `writer` as o5m.
+#[cfg(feature="tokio")]
+pub async fn encode_tokio(
+  stream: Box<dyn Stream<Item=Dataset>+Send+Unpin>,
+  writer: Box<dyn tokio::io::AsyncWrite+Send+Unpin>,
+) -> Result<(),EncodeError> {
+  use tokio_util::compat::TokioAsyncWriteCompatExt;
+  encode(stream, Box::new(writer.compat_write())).await
+}
@@ -0,0 +1,212 @@
+use futures::prelude::*;
+use std::collections::{HashSet,VecDeque};
+use crate::{BBox,Dataset,DecodeItem,DecodeStream,ElementType,NodeData};
+
+/// Region that `filter` keeps nodes from.
+#[derive(Clone,PartialEq,Debug)]
+pub enum Area {
+  BBox(BBox),
+  /// Closed ring of points. The last point connects back to the first.
+  Polygon(Vec<NodeData>),
+}
+
+impl Area {
+  /// Whether `point` lies inside the area. Computed on the fixed-point coordinates without
+  /// rounding, so results match the input exactly.
+  pub fn contains(&self, point: &NodeData) -> bool {
+    match self {
+      Self::BBox(bbox) => bbox.contains(point),
+      Self::Polygon(ring) => ring_contains(ring, point),
+    }
+  }
+}
+
+// even-odd test of `point` against the ring, which may or may not repeat its first point
+pub(crate) fn ring_contains(ring: &[NodeData], point: &NodeData) -> bool {
+  let (x,y) = (point.longitude.0 as i128, point.latitude.0 as i128);
+  let mut inside = false;
+  for (i,a) in ring.iter().enumerate() {
+    let b = &ring[(i+1) % ring.len()];
+    let (ax,ay) = (a.longitude.0 as i128, a.latitude.0 as i128);
+    let (bx,by) = (b.longitude.0 as i128, b.latitude.0 as i128);
+    if (ay > y) == (by > y) { continue }
+    // x < ax + (y-ay)*(bx-ax)/(by-ay), multiplied out to stay in integers
+    let lhs = (x-ax)*(by-ay);
+    let rhs = (y-ay)*(bx-ax);
+    if (by > ay && lhs < rhs) || (by < ay && lhs > rhs) {
+      inside = !inside;
+    }
+  }
+  inside
+}
+
+/// Settings for `filter`.
+///
+/// Both `complete_ways` and `complete_relations` read the input once, so they keep datasets in
+/// memory until they know which ones to keep. `complete_ways` holds every node until the first
+/// relation, along with the ways that reach into the area. `complete_relations` holds all
+/// nodes, ways and relations until the end of the input. On a large extract that is most of
+/// the input, so run it on a smaller cut or on a machine with room for the whole file.
+#[derive(Clone,PartialEq,Debug)]
+pub struct SpatialFilter {
+  pub area: Area,
+  /// Also keep the nodes outside of the area that belong to a kept way.
+  pub complete_ways: bool,
+  /// Also keep every node and way member of a kept relation, with all of the nodes of those ways.
+  pub complete_relations: bool,
+}
+
+impl SpatialFilter {
+  pub fn new(area: Area) -> Self {
+    Self { area, complete_ways: false, complete_relations: false }
+  }
+}
+
+struct FilterState {
+  stream: DecodeStream,
+  options: SpatialFilter,
+  nodes: HashSet<u64>,
+  ways: HashSet<u64>,
+  relations: HashSet<u64>,
+  // items held back until the sets that decide whether to keep them are complete. errors are
+  // held too, so that they come out in the same order as the input
+  held: Vec<DecodeItem>,
+  holding: bool,
+  output: VecDeque<DecodeItem>,
+  done: bool,
+}
+
+impl FilterState {
+  fn new(stream: DecodeStream, options: SpatialFilter) -> Self {
+    let holding = options.complete_ways || options.complete_relations;
+    Self {
+      stream,
+      options,
+      nodes: HashSet::new(),
+      ways: HashSet::new(),
+      relations: HashSet::new(),
+      held: vec![],
+      holding,
+      output: VecDeque::new(),
+      done: false,
+    }
+  }
+  async fn next_item(&mut self) -> Option<DecodeItem> {
+    loop {
+      if let Some(item) = self.output.pop_front() {
+        return Some(item);
+      }
+      if self.done { return None }
+      match self.stream.next().await {
+        None => {
+          self.release();
+          self.done = true;
+        },
+        Some(Err(e)) if self.holding => self.held.push(Err(e)),
+        Some(Err(e)) => return Some(Err(e)),
+        Some(Ok(data)) => self.push(data),
+      }
+    }
+  }
+  fn push(&mut self, data: Dataset) {
+    // with only complete_ways, the ways are known once the first relation arrives
+    if self.holding && !self.options.complete_relations
+    && matches!(data.get_type(), Some(ElementType::Relation())) {
+      self.release();
+    }
+    self.scan(&data);
+    // without complete_relations, a way outside the area is never kept
+    let dropped = !self.options.complete_relations
+      && matches!(&data, Dataset::Way(way) if !self.ways.contains(&way.id));
+    if self.holding && !dropped {
+      self.held.push(Ok(data));
+    } else if self.keeps(&data) {
+      self.output.push_back(Ok(data));
+    }
+  }
+  // record the ids of elements that are inside the area or reference something inside it
+  fn scan(&mut self, data: &Dataset) {
+    match data {
+      Dataset::Node(node) => {
+        let inside = node.data.as_ref().map(|d| self.options.area.contains(d)).unwrap_or(false);
+        if inside { self.nodes.insert(node.id); }
+      },
+      Dataset::Way(way) => {
+        let inside = way.data.iter().flat_map(|d| d.refs.iter()).any(|r| self.nodes.contains(r));
+        if inside { self.ways.insert(way.id); }
+      },
+      Dataset::Relation(relation) => {
+        let inside = relation.data.iter().flat_map(|d| d.members.iter())
+          .any(|m| match m.element_type {
+            ElementType::Node() => self.nodes.contains(&m.id),
+            ElementType::Way() => self.ways.contains(&m.id),
+            ElementType::Relation() => self.relations.contains(&m.id),
+          });
+        if inside { self.relations.insert(relation.id); }
+      },
+      _ => {},
+    }
+  }
+  fn keeps(&self, data: &Dataset) -> bool {
+    match data {
+      Dataset::Node(node) => self.nodes.contains(&node.id),
+      Dataset::Way(way) => self.ways.contains(&way.id),
+      Dataset::Relation(relation) => self.relations.contains(&relation.id),
+      // deletions carry no location
+      _ => true,
+    }
+  }
+  // add the members of kept relations and the nodes of kept ways, then emit the held datasets
+  fn release(&mut self) {
+    if !self.holding { return }
+    self.holding = false;
+    let mut complete = HashSet::new();
+    if self.options.complete_relations {
+      for data in self.held.iter() {
+        let relation = match data {
+          Ok(Dataset::Relation(relation)) if self.relations.contains(&relation.id) => relation,
+          _ => continue,
+        };
+        for m in relation.data.iter().flat_map(|d| d.members.iter()) {
+          match m.element_type {
+            ElementType::Node() => { self.nodes.insert(m.id); },
+            ElementType::Way() => { complete.insert(m.id); },
+            ElementType::Relation() => {},
+          }
+        }
+      }
+    }
+    if self.options.complete_ways {
+      complete.extend(self.ways.iter().copied());
+    }
+    for data in self.held.iter() {
+      match data {
+        Ok(Dataset::Way(way)) if complete.contains(&way.id) => {
+          self.nodes.extend(way.data.iter().flat_map(|d| d.refs.iter()));
+        },
+        _ => {},
+      }
+    }
+    self.ways.extend(complete);
+    for item in std::mem::take(&mut self.held) {
+      match item {
+        Ok(data) if !self.keeps(&data) => {},
+        item => self.output.push_back(item),
+      }
+    }
+  }
+}
+
+/// Keep only the nodes of `stream` inside `options.area`, the ways with at least one of those
+/// nodes and the relations with at least one kept member.
+/// Other datasets pass through unchanged. The input is expected in the usual order of nodes,
+/// then ways, then relations.
+///
+/// `complete_ways` and `complete_relations` hold datasets back, as described on
+/// `SpatialFilter`. Errors read in the meantime are held with them and come out in input order.
+pub fn filter(stream: DecodeStream, options: SpatialFilter) -> DecodeStream {
+  let state = FilterState::new(stream, options);
+  Box::new(crate::unfold::unfold(state, |mut qs| async move {
+    qs.next_item().await.map(|item| (item,qs))
+  }))
+}
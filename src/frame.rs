@@ -0,0 +1,53 @@
+use crate::{DatasetType,DecodeError,DecoderOptions,DecoderState};
+
+/// One frame of o5m input as it appears in the file: a type byte, a length and a payload.
+/// Reset bytes (0xff) and the other single-byte datasets 0xf0 to 0xfe are frames of their own
+/// with an empty payload. The end-of-file byte 0xfe is the last frame.
+#[derive(Clone,PartialEq,Debug)]
+pub struct Frame<'a> {
+  /// Type of the dataset, or `None` for a type byte this crate does not know.
+  pub data_type: Option<DatasetType>,
+  /// The type byte as it appears in the input.
+  pub type_byte: u8,
+  /// Absolute byte offset of the type byte.
+  pub offset: u64,
+  /// Absolute byte offset just past the payload, where the next frame begins.
+  pub end: u64,
+  /// Payload bytes after the length, not decoded.
+  pub data: &'a [u8],
+}
+
+/// Synchronous reader for the frame structure of o5m input from any `std::io::Read`.
+///
+/// Frames are returned as they are, without decoding the payload or keeping any delta or string
+/// state, which suits byte-level statistics, splitting a file at reset points or copying ranges.
+pub struct FrameDecoder<R: std::io::Read> {
+  reader: R,
+  state: DecoderState,
+}
+
+impl<R: std::io::Read> FrameDecoder<R> {
+  pub fn new(reader: R) -> Self {
+    Self::with_options(reader, DecoderOptions::default())
+  }
+  /// Only `lenient` applies here: input that does not begin with a reset byte is reported as a
+  /// `DecodeError::Skipped` up to the first reset byte instead of stopping.
+  pub fn with_options(reader: R, options: DecoderOptions) -> Self {
+    let mut state = DecoderState::new(options);
+    state.frames = true;
+    Self { reader, state }
+  }
+  /// Read the next frame. The payload borrows from the decoder and is valid until the next call.
+  pub fn next_frame(&mut self) -> Result<Option<Frame<'_>>,DecodeError> {
+    if !self.state.read(&mut self.reader)? {
+      return Ok(None);
+    }
+    Ok(Some(Frame {
+      data_type: self.state.data_type.clone(),
+      type_byte: self.state.type_byte,
+      offset: self.state.frame_offset,
+      end: self.state.buffer_offset + (self.state.index as u64),
+      data: self.state.payload(&self.state.buffer),
+    }))
+  }
+}
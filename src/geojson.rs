@@ -0,0 +1,198 @@
+use futures::{prelude::*,stream::Stream,io};
+use std::io::Write;
+use crate::multipolygon::area2;
+use crate::{Assembled,Dataset,EncodeError,MultipolygonGeometry,NodeData,Polygon,Tags,WayGeometry};
+
+/// Layout of the GeoJSON output.
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum GeoJsonFormat {
+  /// A single `FeatureCollection` document.
+  FeatureCollection(),
+  /// Newline-delimited GeoJSON: one feature per line, for consumers that read as they go.
+  Lines(),
+}
+
+/// Incremental GeoJSON encoder for the output of the geometry stages.
+///
+/// Nodes become `Point` features. Ways with every node location resolved become `Polygon`
+/// features when they are closed and `LineString` features otherwise. Multipolygon areas become
+/// `Polygon` or `MultiPolygon` features. Tags are written as properties and each feature's id
+/// is the element type and id, such as `"way/42"`. Everything else is left out.
+pub struct GeoJsonEncoder {
+  format: GeoJsonFormat,
+  begun: bool,
+  ended: bool,
+  count: usize,
+}
+
+impl GeoJsonEncoder {
+  pub fn new(format: GeoJsonFormat) -> Self {
+    Self { format, begun: false, ended: false, count: 0 }
+  }
+  /// Open the feature collection if it has not been opened yet.
+  pub fn begin(&mut self, buf: &mut Vec<u8>) {
+    if self.begun { return }
+    self.begun = true;
+    if self.format == GeoJsonFormat::FeatureCollection() {
+      buf.extend_from_slice(b"{\"type\":\"FeatureCollection\",\"features\":[\n");
+    }
+  }
+  /// Close the feature collection. Nothing can be written afterwards.
+  pub fn end(&mut self, buf: &mut Vec<u8>) {
+    self.begin(buf);
+    if self.ended { return }
+    self.ended = true;
+    if self.format == GeoJsonFormat::FeatureCollection() {
+      if self.count > 0 { buf.push(b'\n') }
+      buf.extend_from_slice(b"]}\n");
+    }
+  }
+  pub fn write_item(&mut self, item: &Assembled, buf: &mut Vec<u8>) -> Result<(),EncodeError> {
+    if self.ended { return Ok(()) }
+    match item {
+      Assembled::Dataset(Dataset::Node(node)) => {
+        if let Some(data) = &node.data {
+          self.feature(buf, "node", node.id, &node.tags, |buf| {
+            buf.extend_from_slice(b"{\"type\":\"Point\",\"coordinates\":");
+            point(buf, data);
+            buf.push(b'}');
+          });
+        }
+      },
+      Assembled::Dataset(_) => {},
+      Assembled::Way(geometry) => self.way(buf, geometry),
+      Assembled::Multipolygon(geometry) => self.multipolygon(buf, geometry),
+    }
+    Ok(())
+  }
+  fn way(&mut self, buf: &mut Vec<u8>, geometry: &WayGeometry) {
+    if !geometry.is_complete() { return }
+    let mut points = geometry.points.iter().flatten().cloned().collect::<Vec<_>>();
+    let way = &geometry.way;
+    if geometry.is_closed() && points.len() >= 4 {
+      if area2(&points) < 0 { points.reverse() }
+      self.feature(buf, "way", way.id, &way.tags, |buf| {
+        buf.extend_from_slice(b"{\"type\":\"Polygon\",\"coordinates\":[");
+        line(buf, &points);
+        buf.extend_from_slice(b"]}");
+      });
+    } else if points.len() >= 2 {
+      self.feature(buf, "way", way.id, &way.tags, |buf| {
+        buf.extend_from_slice(b"{\"type\":\"LineString\",\"coordinates\":");
+        line(buf, &points);
+        buf.push(b'}');
+      });
+    }
+  }
+  fn multipolygon(&mut self, buf: &mut Vec<u8>, geometry: &MultipolygonGeometry) {
+    let relation = &geometry.relation;
+    match geometry.polygons.as_slice() {
+      [] => {},
+      [p] => self.feature(buf, "relation", relation.id, &relation.tags, |buf| {
+        buf.extend_from_slice(b"{\"type\":\"Polygon\",\"coordinates\":");
+        polygon(buf, p);
+        buf.push(b'}');
+      }),
+      polygons => self.feature(buf, "relation", relation.id, &relation.tags, |buf| {
+        buf.extend_from_slice(b"{\"type\":\"MultiPolygon\",\"coordinates\":[");
+        for (i,p) in polygons.iter().enumerate() {
+          if i > 0 { buf.push(b',') }
+          polygon(buf, p);
+        }
+        buf.extend_from_slice(b"]}");
+      }),
+    }
+  }
+  fn feature(&mut self, buf: &mut Vec<u8>, kind: &str, id: u64, tags: &Tags,
+  geometry: impl FnOnce(&mut Vec<u8>)) {
+    self.begin(buf);
+    if self.format == GeoJsonFormat::FeatureCollection() && self.count > 0 {
+      buf.extend_from_slice(b",\n");
+    }
+    self.count += 1;
+    write!(buf, "{{\"type\":\"Feature\",\"id\":\"{}/{}\",\"geometry\":", kind, id).unwrap();
+    geometry(buf);
+    buf.extend_from_slice(b",\"properties\":{");
+    // sorted by key so that the output does not depend on hash map order
+    let mut sorted = tags.iter().collect::<Vec<_>>();
+    sorted.sort();
+    for (i,(k,v)) in sorted.into_iter().enumerate() {
+      if i > 0 { buf.push(b',') }
+      string(buf, k);
+      buf.push(b':');
+      string(buf, v);
+    }
+    buf.extend_from_slice(b"}}");
+    if self.format == GeoJsonFormat::Lines() {
+      buf.push(b'\n');
+    }
+  }
+}
+
+fn point(buf: &mut Vec<u8>, p: &NodeData) {
+  write!(buf, "[{},{}]", p.longitude, p.latitude).unwrap();
+}
+
+fn line(buf: &mut Vec<u8>, points: &[NodeData]) {
+  buf.push(b'[');
+  for (i,p) in points.iter().enumerate() {
+    if i > 0 { buf.push(b',') }
+    point(buf, p);
+  }
+  buf.push(b']');
+}
+
+fn polygon(buf: &mut Vec<u8>, p: &Polygon) {
+  buf.push(b'[');
+  line(buf, &p.outer.points);
+  for inner in &p.inners {
+    buf.push(b',');
+    line(buf, &inner.points);
+  }
+  buf.push(b']');
+}
+
+// `s` as a json string literal
+fn string(buf: &mut Vec<u8>, s: &str) {
+  buf.push(b'"');
+  for c in s.chars() {
+    match c {
+      '"' => buf.extend_from_slice(b"\\\""),
+      '\\' => buf.extend_from_slice(b"\\\\"),
+      '\n' => buf.extend_from_slice(b"\\n"),
+      '\r' => buf.extend_from_slice(b"\\r"),
+      '\t' => buf.extend_from_slice(b"\\t"),
+      c if (c as u32) < 0x20 => write!(buf, "\\u{:04x}", c as u32).unwrap(),
+      c => {
+        let mut bytes = [0;4];
+        buf.extend_from_slice(c.encode_utf8(&mut bytes).as_bytes());
+      },
+    }
+  }
+  buf.push(b'"');
+}
+
+/// Write the items from `stream`, the output of `assemble_ways` or `assemble_multipolygons`,
+/// to `writer` as GeoJSON in the given `format`.
+pub async fn encode_geojson(
+  mut stream: Box<dyn Stream<Item=Assembled>+Send+Unpin>,
+  mut writer: Box<dyn io::AsyncWrite+Send+Unpin>,
+  format: GeoJsonFormat,
+) -> Result<(),EncodeError> {
+  let mut encoder = GeoJsonEncoder::new(format);
+  let mut buf = vec![];
+  while let Some(item) = stream.next().await {
+    encoder.write_item(&item, &mut buf)?;
+    if buf.len() >= 4096 {
+      writer.write_all(&buf).await
+        .map_err(|e| EncodeError::StreamWriteError { source: Box::new(e.into()) })?;
+      buf.clear();
+    }
+  }
+  encoder.end(&mut buf);
+  writer.write_all(&buf).await
+    .map_err(|e| EncodeError::StreamWriteError { source: Box::new(e.into()) })?;
+  writer.flush().await
+    .map_err(|e| EncodeError::StreamWriteError { source: Box::new(e.into()) })?;
+  Ok(())
+}
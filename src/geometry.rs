@@ -0,0 +1,230 @@
+use futures::prelude::*;
+use std::collections::HashMap;
+use crate::{
+  Coord,Dataset,DatasetType,DecodeError,DecodeStream,ErrorContext,MultipolygonGeometry,NodeData,Way,
+};
+
+/// Storage for node locations, keyed by node id.
+pub trait LocationCache {
+  fn set(&mut self, id: u64, data: &NodeData) -> std::io::Result<()>;
+  fn get(&self, id: u64) -> Option<NodeData>;
+}
+
+// locations are stored as 8 bytes with the sign bits flipped,
+// so that zeroed memory reads as missing rather than as 0,0
+fn pack(data: &NodeData) -> u64 {
+  let lon = (data.longitude.0 as u32) ^ 0x8000_0000;
+  let lat = (data.latitude.0 as u32) ^ 0x8000_0000;
+  ((lon as u64) << 32) | (lat as u64)
+}
+
+fn unpack(x: u64) -> Option<NodeData> {
+  if x == 0 { return None }
+  Some(NodeData {
+    longitude: Coord((((x >> 32) as u32) ^ 0x8000_0000) as i32),
+    latitude: Coord(((x as u32) ^ 0x8000_0000) as i32),
+  })
+}
+
+/// Locations in a hash map. Best for small or sparse inputs.
+#[derive(Clone,Debug,Default)]
+pub struct HashMapCache {
+  locations: HashMap<u64,NodeData>,
+}
+
+impl HashMapCache {
+  pub fn new() -> Self {
+    Self::default()
+  }
+}
+
+impl LocationCache for HashMapCache {
+  fn set(&mut self, id: u64, data: &NodeData) -> std::io::Result<()> {
+    self.locations.insert(id, data.clone());
+    Ok(())
+  }
+  fn get(&self, id: u64) -> Option<NodeData> {
+    self.locations.get(&id).cloned()
+  }
+}
+
+/// Locations in an array indexed by node id, using 8 bytes for every id up to the largest one
+/// seen. Best for extracts with densely packed ids. Ids above `DenseCache::MAX_ID` are rejected
+/// with `std::io::ErrorKind::InvalidInput`.
+#[derive(Clone,Debug,Default)]
+pub struct DenseCache {
+  locations: Vec<u64>,
+}
+
+impl DenseCache {
+  /// Largest node id the cache accepts, well above the ids in use in OpenStreetMap today.
+  pub const MAX_ID: u64 = 1 << 36;
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Reserve room for ids below `max_id` up front.
+  pub fn with_capacity(max_id: u64) -> Self {
+    Self { locations: vec![0;max_id as usize] }
+  }
+}
+
+impl LocationCache for DenseCache {
+  fn set(&mut self, id: u64, data: &NodeData) -> std::io::Result<()> {
+    if id > Self::MAX_ID {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "node id too large"));
+    }
+    let i = id as usize;
+    if i >= self.locations.len() {
+      let len = (i+1).max(self.locations.len()*2).min(Self::MAX_ID as usize + 1);
+      self.locations.try_reserve_exact(len - self.locations.len())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::OutOfMemory, e))?;
+      self.locations.resize(len, 0);
+    }
+    self.locations[i] = pack(data);
+    Ok(())
+  }
+  fn get(&self, id: u64) -> Option<NodeData> {
+    self.locations.get(id as usize).copied().and_then(unpack)
+  }
+}
+
+/// Locations in a memory-mapped file indexed by node id, for inputs too large to keep in memory.
+/// The file grows as needed and is sparse on filesystems that support it.
+#[cfg(feature="mmap")]
+pub struct MmapCache {
+  file: std::fs::File,
+  map: memmap2::MmapMut,
+}
+
+#[cfg(feature="mmap")]
+impl MmapCache {
+  /// Create or truncate the file at `path` to hold the locations.
+  pub fn create(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+    let file = std::fs::OpenOptions::new()
+      .read(true).write(true).create(true).truncate(true)
+      .open(path)?;
+    file.set_len(8 << 20)?;
+    // the file is only accessed through this mapping while the cache exists
+    let map = unsafe { memmap2::MmapMut::map_mut(&file)? };
+    Ok(Self { file, map })
+  }
+  fn grow(&mut self, len: usize) -> std::io::Result<()> {
+    let len = len.max(self.map.len()*2) as u64;
+    self.file.set_len(len)?;
+    self.map = unsafe { memmap2::MmapMut::map_mut(&self.file)? };
+    Ok(())
+  }
+}
+
+#[cfg(feature="mmap")]
+impl LocationCache for MmapCache {
+  fn set(&mut self, id: u64, data: &NodeData) -> std::io::Result<()> {
+    let i = (id as usize).checked_mul(8).filter(|i| *i <= usize::MAX-8)
+      .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "node id too large"))?;
+    if i+8 > self.map.len() {
+      self.grow(i+8)?;
+    }
+    self.map[i..i+8].copy_from_slice(&pack(data).to_le_bytes());
+    Ok(())
+  }
+  fn get(&self, id: u64) -> Option<NodeData> {
+    let i = (id as usize).checked_mul(8)?;
+    let mut bytes = [0;8];
+    bytes.copy_from_slice(self.map.get(i..i.checked_add(8)?)?);
+    unpack(u64::from_le_bytes(bytes))
+  }
+}
+
+/// A way with the locations of its nodes.
+#[derive(Clone,PartialEq,Debug)]
+pub struct WayGeometry {
+  pub way: Way,
+  /// Location of each of the way's refs, in order, or `None` for nodes that were not seen.
+  pub points: Vec<Option<NodeData>>,
+}
+
+impl WayGeometry {
+  /// Refs that could not be resolved to a location.
+  pub fn unresolved(&self) -> Vec<u64> {
+    self.refs().iter().zip(self.points.iter())
+      .filter(|(_,p)| p.is_none())
+      .map(|(r,_)| *r)
+      .collect()
+  }
+  /// Whether every ref was resolved.
+  pub fn is_complete(&self) -> bool {
+    self.points.iter().all(|p| p.is_some())
+  }
+  /// Whether the way ends at the node it starts from.
+  pub fn is_closed(&self) -> bool {
+    let refs = self.refs();
+    refs.len() > 2 && refs.first() == refs.last()
+  }
+  fn refs(&self) -> &[u64] {
+    self.way.data.as_ref().map(|d| d.refs.as_slice()).unwrap_or(&[])
+  }
+}
+
+/// Output of the geometry stages: datasets passed through, ways with their locations
+/// and areas built from relations.
+#[derive(Clone,PartialEq,Debug)]
+pub enum Assembled {
+  Dataset(Dataset),
+  Way(WayGeometry),
+  Multipolygon(MultipolygonGeometry),
+}
+
+pub type AssembleItem = Result<Assembled,DecodeError>;
+pub type AssembleStream = Box<dyn Stream<Item=AssembleItem>+Send+Unpin>;
+
+/// Remembers the location of every node it sees and resolves the refs of later ways.
+pub struct WayAssembler {
+  cache: Box<dyn LocationCache+Send>,
+  last_id: Option<u64>,
+}
+
+impl WayAssembler {
+  pub fn new(cache: Box<dyn LocationCache+Send>) -> Self {
+    Self { cache, last_id: None }
+  }
+  /// Store the location of a node, or look up the locations of a way.
+  /// Ways without data (such as deleted ways) and all other datasets are returned unchanged.
+  pub fn assemble(&mut self, data: Dataset) -> Result<Assembled,DecodeError> {
+    match data {
+      Dataset::Node(node) => {
+        if let Some(d) = &node.data {
+          self.cache.set(node.id, d).map_err(|e| DecodeError::LocationCacheError {
+            id: node.id,
+            source: Box::new(e.into()),
+            context: ErrorContext {
+              data_type: Some(DatasetType::Node()),
+              last_id: self.last_id,
+              ..ErrorContext::default()
+            },
+          })?;
+        }
+        self.last_id = Some(node.id);
+        Ok(Assembled::Dataset(Dataset::Node(node)))
+      },
+      Dataset::Way(way) if way.data.is_some() => {
+        let points = way.data.iter()
+          .flat_map(|d| d.refs.iter())
+          .map(|r| self.cache.get(*r))
+          .collect();
+        self.last_id = Some(way.id);
+        Ok(Assembled::Way(WayGeometry { way, points }))
+      },
+      data => {
+        self.last_id = data.get_id().or(self.last_id);
+        Ok(Assembled::Dataset(data))
+      },
+    }
+  }
+}
+
+/// Resolve the locations of the ways in `stream` from the nodes that come before them,
+/// storing node locations in `cache`.
+pub fn assemble_ways(stream: DecodeStream, cache: Box<dyn LocationCache+Send>) -> AssembleStream {
+  let mut assembler = WayAssembler::new(cache);
+  Box::new(stream.map(move |item| item.and_then(|data| assembler.assemble(data))))
+}
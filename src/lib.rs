@@ -1,14 +1,47 @@
 #![warn(clippy::future_not_send)]
-#![feature(async_closure,backtrace)]
 #![doc=include_str!("../readme.md")]
 
-use async_std::{prelude::*,stream::Stream,io};
-use std::collections::VecDeque;
+use futures::{prelude::*,stream::Stream,io};
+use std::convert::TryFrom;
 
 mod unfold;
+mod coord;
+pub use coord::{Coord,ParseCoordError};
 mod data;
 pub use data::*;
 pub mod parse;
+mod data_ref;
+pub use data_ref::*;
+mod delta;
+use delta::DeltaState;
+mod frame;
+pub use frame::{Frame,FrameDecoder};
+mod parallel;
+pub use parallel::ParallelDecoder;
+mod slice;
+pub use slice::{SliceDecoder,SliceFrameDecoder};
+mod encode;
+pub use encode::{encode,encode_with_options,Encoder,EncoderOptions,EncodeError};
+#[cfg(feature="tokio")]
+pub use encode::encode_tokio;
+mod xml_encode;
+pub use xml_encode::{encode_xml,XmlEncoder};
+mod xml_decode;
+pub use xml_decode::{decode_xml,XmlDecoder};
+#[cfg(feature="pbf")]
+mod protobuf;
+#[cfg(feature="pbf")]
+mod pbf;
+#[cfg(feature="pbf")]
+pub use pbf::{decode_pbf,decode_pbf_with_options,encode_pbf,PbfDecoder,PbfEncoder};
+mod filter;
+pub use filter::{filter,Area,SpatialFilter};
+mod geometry;
+pub use geometry::*;
+mod multipolygon;
+pub use multipolygon::*;
+mod geojson;
+pub use geojson::{encode_geojson,GeoJsonEncoder,GeoJsonFormat};
 
 type Error = Box<dyn std::error::Error+Send+Sync>;
 
@@ -16,369 +49,744 @@ pub type DecodeItem = Result<Dataset,DecodeError>;
 pub type DecodeStream = Box<dyn Stream<Item=DecodeItem>+Send+Unpin>;
 
 #[derive(Clone,PartialEq,Debug)]
-enum State { Begin(), Type(), Len(), Data(), End() }
+// `End` follows the 0xfe end-of-file byte, and `Failed` the first error in strict mode. Both end
+// the input
+enum State { Begin(), Type(), Len(), Data(), End(), Failed() }
 
-use std::backtrace::Backtrace;
+#[derive(Clone,PartialEq,Debug)]
+// what `DecoderState::step` stopped at: a decoded frame, a buffer that needs to be read into, or
+// the end of the input
+enum Step { Frame(), Input(), End() }
+
+// dataset type for the type byte `b` at the start of a frame
+fn dataset_type(b: u8) -> Option<DatasetType> {
+  match b {
+    0x10 => Some(DatasetType::Node()),
+    0x11 => Some(DatasetType::Way()),
+    0x12 => Some(DatasetType::Relation()),
+    0xdb => Some(DatasetType::BBox()),
+    0xdc => Some(DatasetType::Timestamp()),
+    0xe0 => Some(DatasetType::Header()),
+    0xee => Some(DatasetType::Sync()),
+    0xef => Some(DatasetType::Jump()),
+    0xff => Some(DatasetType::Reset()),
+    _ => None,
+  }
+}
+
+/// Stack trace recorded where a `DecodeError` or `EncodeError` was created.
+/// Backtraces are only captured when the `backtrace` feature is enabled.
+#[derive(Debug)]
+pub struct ErrorTrace {
+  #[cfg(feature="backtrace")]
+  backtrace: std::backtrace::Backtrace,
+}
+
+impl ErrorTrace {
+  pub fn capture() -> Self {
+    Self {
+      #[cfg(feature="backtrace")]
+      backtrace: std::backtrace::Backtrace::capture(),
+    }
+  }
+  /// The captured backtrace, or `None` without the `backtrace` feature.
+  pub fn get(&self) -> Option<&std::backtrace::Backtrace> {
+    #[cfg(feature="backtrace")]
+    { Some(&self.backtrace) }
+    #[cfg(not(feature="backtrace"))]
+    { None }
+  }
+}
+
+impl std::fmt::Display for ErrorTrace {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.get() {
+      Some(backtrace) => backtrace.fmt(f),
+      None => Ok(()),
+    }
+  }
+}
+
+/// Where in the input a `DecodeError` happened.
+#[derive(Clone,PartialEq,Debug,Default)]
+pub struct ErrorContext {
+  /// Absolute byte offset of the frame being decoded, or of the failing byte outside a frame.
+  pub offset: Option<u64>,
+  /// Type of the dataset being decoded.
+  pub data_type: Option<DatasetType>,
+  /// Id of the last element that was decoded successfully.
+  pub last_id: Option<u64>,
+}
+
+impl std::fmt::Display for ErrorContext {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if let Some(offset) = self.offset {
+      write!(f, " at byte offset {}", offset)?;
+    }
+    if let Some(data_type) = &self.data_type {
+      write!(f, " in {:?} dataset", data_type)?;
+    }
+    if let Some(id) = self.last_id {
+      write!(f, " after id {}", id)?;
+    }
+    Ok(())
+  }
+}
 
 #[derive(thiserror::Error,Debug)]
 pub enum DecodeError {
-  #[error("string at index {index} not available")]
+  #[error("string at index {index} not available{context}")]
   StringUnavailable {
     index: usize,
-    #[backtrace] backtrace: Backtrace,
+    context: ErrorContext,
+    backtrace: ErrorTrace,
   },
-  #[error("{info:?}. expected: 0x{expected:02x}, received: 0x{received:02x}")]
+  #[error("{info:?}. expected: 0x{expected:02x}, received: 0x{received:02x}{context}")]
   UnexpectedByte {
     info: String,
     expected: u8,
     received: u8,
-    #[backtrace] backtrace: Backtrace,
+    context: ErrorContext,
+    backtrace: ErrorTrace,
   },
   #[error("expected 0x30, 0x31, or 0x32 for element type. \
-    received: 0x{received:02x}\n{backtrace}")]
+    received: 0x{received:02x}{context}\n{backtrace}")]
   UnexpectedElementType {
     received: u8,
-    #[backtrace] backtrace: Backtrace,
+    context: ErrorContext,
+    backtrace: ErrorTrace,
   },
-  #[error("stream read error {source:?}")]
-  StreamReadError { #[source] source: Box<Error> },
-  #[error("string encoding error {source:?}")]
-  StringEncodingError { #[source] source: Box<Error> },
-  #[error("unterminated signed integer\n{backtrace}")]
-  UnterminatedSignedInteger { #[backtrace] backtrace: Backtrace },
-  #[error("unterminated unsigned integer\n{backtrace}")]
-  UnterminatedUnsignedInteger { #[backtrace] backtrace: Backtrace },
+  #[error("stream read error {source:?}{context}")]
+  StreamReadError { #[source] source: Box<Error>, context: ErrorContext },
+  #[error("stream seek error {source:?}{context}")]
+  StreamSeekError { #[source] source: Box<Error>, context: ErrorContext },
+  #[error("string encoding error {source:?}{context}")]
+  StringEncodingError { #[source] source: Box<Error>, context: ErrorContext },
+  #[error("unterminated signed integer{context}\n{backtrace}")]
+  UnterminatedSignedInteger { context: ErrorContext, backtrace: ErrorTrace },
+  #[error("unterminated unsigned integer{context}\n{backtrace}")]
+  UnterminatedUnsignedInteger { context: ErrorContext, backtrace: ErrorTrace },
+  #[error("input ends inside a frame{context}\n{backtrace}")]
+  UnexpectedEof { context: ErrorContext, backtrace: ErrorTrace },
+  #[error("invalid xml: {info}{context}")]
+  InvalidXml { info: String, context: ErrorContext, backtrace: ErrorTrace },
+  #[error("invalid pbf: {info}{context}")]
+  InvalidPbf { info: String, context: ErrorContext, backtrace: ErrorTrace },
+  #[error("no frame boundary to split the input at within {limit} bytes{context}")]
+  ChunkTooLarge { limit: usize, context: ErrorContext, backtrace: ErrorTrace },
+  #[error("location cache error for node {id}: {source:?}{context}")]
+  LocationCacheError { id: u64, #[source] source: Box<Error>, context: ErrorContext },
+  #[error("skipped bytes {start}..{end} to recover from {source}")]
+  Skipped {
+    start: u64,
+    end: u64,
+    #[source] source: Box<DecodeError>,
+    context: ErrorContext,
+  },
+}
+
+impl DecodeError {
+  /// Position in the input and dataset where the error happened.
+  pub fn context(&self) -> &ErrorContext {
+    match self {
+      Self::StringUnavailable { context, .. }
+      | Self::UnexpectedByte { context, .. }
+      | Self::UnexpectedElementType { context, .. }
+      | Self::StreamReadError { context, .. }
+      | Self::StreamSeekError { context, .. }
+      | Self::StringEncodingError { context, .. }
+      | Self::UnterminatedSignedInteger { context, .. }
+      | Self::UnterminatedUnsignedInteger { context, .. }
+      | Self::UnexpectedEof { context, .. }
+      | Self::InvalidXml { context, .. }
+      | Self::InvalidPbf { context, .. }
+      | Self::ChunkTooLarge { context, .. }
+      | Self::LocationCacheError { context, .. }
+      | Self::Skipped { context, .. } => context,
+    }
+  }
+  fn with_context(mut self, c: ErrorContext) -> Self {
+    match &mut self {
+      Self::StringUnavailable { context, .. }
+      | Self::UnexpectedByte { context, .. }
+      | Self::UnexpectedElementType { context, .. }
+      | Self::StreamReadError { context, .. }
+      | Self::StreamSeekError { context, .. }
+      | Self::StringEncodingError { context, .. }
+      | Self::UnterminatedSignedInteger { context, .. }
+      | Self::UnterminatedUnsignedInteger { context, .. }
+      | Self::UnexpectedEof { context, .. }
+      | Self::InvalidXml { context, .. }
+      | Self::InvalidPbf { context, .. }
+      | Self::ChunkTooLarge { context, .. }
+      | Self::LocationCacheError { context, .. }
+      | Self::Skipped { context, .. } => *context = c,
+    }
+    self
+  }
+}
+
+/// Options for `Decoder::with_options` and `decode_with_options`.
+#[derive(Clone,PartialEq,Debug,Default)]
+pub struct DecoderOptions {
+  /// Recover from malformed datasets instead of stopping. After an error the decoder skips whole
+  /// frames by their lengths up to the next 0xff reset byte between frames, clears its delta and
+  /// string tables and keeps decoding. The skipped byte range is reported as a
+  /// `DecodeError::Skipped` item.
+  pub lenient: bool,
+  /// Element types to emit, or `None` for all of them. Elements and deletions of other types
+  /// are only scanned for the strings they add to the string table.
+  /// `cargo run --release --example element_types` measures the saving.
+  pub element_types: Option<Vec<ElementType>>,
+  /// Which tags to keep, by key. Dropped tags still update the string table but are never
+  /// copied into a `Tags` map.
+  pub tag_filter: Option<TagFilter>,
+}
+
+/// Tag keys to keep or drop while decoding. Keys are compared exactly.
+#[derive(Clone,PartialEq,Debug)]
+pub enum TagFilter {
+  /// Keep only the tags with these keys.
+  Allow(Vec<String>),
+  /// Keep every tag except the ones with these keys.
+  Deny(Vec<String>),
+}
+
+impl TagFilter {
+  pub fn keeps(&self, key: &[u8]) -> bool {
+    match self {
+      Self::Allow(keys) => keys.iter().any(|k| k.as_bytes() == key),
+      Self::Deny(keys) => !keys.iter().any(|k| k.as_bytes() == key),
+    }
+  }
 }
 
-struct Decoder {
-  reader: Box<dyn io::Read+Send+Unpin>,
+// frame state machine shared by the sync and async decoders
+struct DecoderState {
+  options: DecoderOptions,
   buffer: Vec<u8>,
   index: usize,
   buffer_len: usize,
+  buffer_offset: u64,
+  frame_offset: u64,
   state: State,
+  // stop at every frame, including resets, without decoding it
+  frames: bool,
+  type_byte: u8,
   data_type: Option<DatasetType>,
   len: usize,
   npow: u64,
+  // payload of the last frame: a range of the input when it was read in one piece, otherwise
+  // the copy in `chunk`
+  payload: Option<(usize,usize)>,
   chunk: Vec<u8>,
   size: usize,
-  strings: VecDeque<(Vec<u8>,Vec<u8>)>,
-  prev_id: Option<u64>,
-  prev_info: Option<Info>,
-  prev: Option<Dataset>,
+  skipped: Option<DecodeError>,
+  delta: DeltaState,
 }
 
-impl Decoder {
-  pub fn new(reader: Box<dyn io::Read+Send+Unpin>) -> Self {
+impl DecoderState {
+  fn new(options: DecoderOptions) -> Self {
     Self {
-      reader,
       buffer: vec![0;4096],
       index: 0,
       buffer_len: 0,
+      buffer_offset: 0,
+      frame_offset: 0,
       state: State::Begin(),
+      frames: false,
+      type_byte: 0,
       data_type: None,
       len: 0,
       npow: 1,
+      payload: None,
       chunk: vec![],
       size: 0,
-      strings: VecDeque::new(),
-      prev: None,
-      prev_id: None,
-      prev_info: None,
+      skipped: None,
+      delta: DeltaState::new(&options),
+      options,
     }
   }
-  pub async fn next_item(&mut self) -> Result<Option<Dataset>,DecodeError> {
-    loop {
-      if self.index >= self.buffer_len {
-        self.buffer_len = self.reader.read(&mut self.buffer).await
-          .map_err(|e| DecodeError::StreamReadError { source: Box::new(e.into()) })?;
-        self.index = 0;
-        if self.buffer_len == 0 { break }
+  fn context(&self) -> ErrorContext {
+    let in_frame = self.state == State::Len() || self.state == State::Data();
+    ErrorContext {
+      offset: Some(match in_frame {
+        true => self.frame_offset,
+        false => self.buffer_offset + (self.index as u64),
+      }),
+      data_type: if in_frame { self.data_type.clone() } else { None },
+      last_id: self.delta.last_id,
+    }
+  }
+  fn needs_input(&self) -> bool {
+    self.index >= self.buffer_len
+  }
+  fn filled(&mut self, n: usize) {
+    self.buffer_offset += self.buffer_len as u64;
+    self.buffer_len = n;
+    self.index = 0;
+  }
+  // discard buffered input and all delta and string state after the reader moved to `offset`
+  fn seeked(&mut self, offset: u64) {
+    self.buffer_offset = offset;
+    self.buffer_len = 0;
+    self.index = 0;
+    self.state = State::Type();
+    self.len = 0;
+    self.npow = 1;
+    self.size = 0;
+    self.chunk.clear();
+    self.delta.jump = None;
+    self.delta.reset();
+  }
+  // drop the current frame and the whole frames after it, by their lengths, up to the next
+  // reset byte on a frame boundary
+  fn skip(&mut self, e: DecodeError) {
+    self.state = State::Type();
+    self.len = 0;
+    self.npow = 1;
+    self.size = 0;
+    self.chunk.clear();
+    self.skipped = Some(e);
+  }
+  fn end_skip(&mut self, end: u64) -> Option<DecodeError> {
+    self.skipped.take().map(|e| {
+      let context = e.context().clone();
+      DecodeError::Skipped {
+        start: context.offset.unwrap_or(end),
+        end,
+        source: Box::new(e),
+        context,
       }
-      while self.index < self.buffer_len {
-        let b = self.buffer[self.index];
-        if self.state == State::Begin() && b != 0xff {
-          return Err(DecodeError::UnexpectedByte {
-            info: "first byte in frame".to_string(),
-            expected: 0xff,
-            received: b,
-            backtrace: Backtrace::capture(),
-          });
-        } else if self.state == State::Begin() {
-          self.state = State::Type();
-        } else if self.state == State::Type() && b == 0xff { // reset
-          self.state = State::Type();
-          self.prev = None;
-          self.prev_id = None;
-          self.prev_info = None;
-        } else if self.state == State::Type() {
-          self.state = State::Len();
-          self.data_type = match b {
-            0x10 => Some(DatasetType::Node()),
-            0x11 => Some(DatasetType::Way()),
-            0x12 => Some(DatasetType::Relation()),
-            0xdb => Some(DatasetType::BBox()),
-            0xdc => Some(DatasetType::Timestamp()),
-            0xe0 => Some(DatasetType::Header()),
-            0xee => Some(DatasetType::Sync()),
-            0xef => Some(DatasetType::Jump()),
-            0xff => Some(DatasetType::Reset()),
-            _ => None,
-          };
-        } else if self.state == State::Len() {
-          self.len += ((b & 0x7f) as usize) * (self.npow as usize);
-          self.npow *= 0x80;
-          if b < 0x80 {
-            self.npow = 1;
-            self.state = State::Data();
-          }
-        } else if self.state == State::Data() {
-          let j = self.buffer_len.min(self.index+self.len-self.size);
-          self.chunk.extend_from_slice(&self.buffer[self.index..j]);
-          self.size += j-self.index;
-          if self.size >= self.len {
-            let res = self.flush()?;
-            self.state = State::Type();
-            self.len = 0;
-            self.size = 0;
-            self.chunk.clear();
-            if let Some(data) = res {
-              let save = match &data {
-                Dataset::Node(node) => node.data.is_some(),
-                Dataset::Way(way) => way.data.is_some(),
-                Dataset::Relation(relation) => relation.data.is_some(),
-                _ => true,
-              };
-              if save {
-                self.prev = Some(data.clone());
-              }
-              if let Some(id) = data.get_id() {
-                self.prev_id = Some(id);
-              }
-              if let Some(info) = data.get_info() {
-                self.prev_info = Some(info);
-              }
-              self.index = j;
-              return Ok(Some(data));
-            }
-          }
-          self.index = j - 1;
-        } else if self.state == State::End() && b != 0xfe {
-          return Err(DecodeError::UnexpectedByte {
-            info: "last byte in frame".to_string(),
-            expected: 0xf3,
-            received: b,
-            backtrace: Backtrace::capture(),
-          });
-        } else if self.state == State::End() {
-          // ...
-        }
-        self.index += 1;
+    })
+  }
+  // called at the end of the input
+  fn finish(&mut self) -> Option<DecodeError> {
+    self.finish_at(self.buffer_offset + (self.buffer_len as u64))
+  }
+  // called at the end of input that stands for the input up to the absolute offset `end`. a
+  // frame that the input ends inside of is an error, or part of the skipped bytes in `lenient`
+  // mode
+  fn finish_at(&mut self, end: u64) -> Option<DecodeError> {
+    if self.state == State::Len() || self.state == State::Data() {
+      let e = DecodeError::UnexpectedEof {
+        context: self.context(),
+        backtrace: ErrorTrace::capture(),
+      };
+      if !self.options.lenient {
+        self.state = State::Failed();
+        return Some(e);
       }
+      if self.skipped.is_none() { self.skip(e) }
+      self.state = State::Type();
     }
-    Ok(None)
-  }
-  fn flush(&mut self) -> Result<Option<Dataset>,DecodeError> {
-    let mut offset = 0;
-    let buf = &self.chunk;
-    Ok(match self.data_type {
-      Some(DatasetType::Node()) => {
-        let (s,(id,info)) = parse::info(
-          &buf[offset..],
-          &self.prev_id,
-          &self.prev_info,
-          &mut self.strings
-        )?;
-        offset += s;
-        if offset == buf.len() {
-          Some(Dataset::Node(Node {
-            id,
-            info,
-            data: None,
-            tags: std::collections::HashMap::new(),
-          }))
-        } else {
-          let longitude = {
-            let (s,x) = parse::signed(&buf[offset..])?;
-            offset += s;
-            (x + (match &self.prev {
-              Some(Dataset::Node(node)) => node.data.as_ref()
-                .map(|data| data.longitude),
-              _ => None,
-            }.unwrap_or(0) as i64)) as i32
-          };
-          let latitude = {
-            let (s,x) = parse::signed(&buf[offset..])?;
-            offset += s;
-            (x + (match &self.prev {
-              Some(Dataset::Node(node)) => node.data.as_ref()
-                .map(|data| data.latitude),
-              _ => None,
-            }.unwrap_or(0) as i64)) as i32
-          };
-          let (_,tags) = parse::tags(&buf[offset..], &mut self.strings)?;
-          Some(Dataset::Node(Node {
-            id,
-            info,
-            data: Some(NodeData { longitude, latitude }),
-            tags,
-          }))
-        }
+    let e = self.end_skip(end)?;
+    self.state = State::Type();
+    Some(e)
+  }
+  // payload of the frame that `next_in` last returned true for, from the same `input`
+  fn payload<'a>(&'a self, input: &'a [u8]) -> &'a [u8] {
+    match self.payload {
+      Some((start,end)) => &input[start..end],
+      None => &self.chunk,
+    }
+  }
+  // borrowed view of the dataset that `next_buffered` last returned true for
+  fn view(&self) -> Option<DatasetRef<'_>> {
+    self.view_in(&self.buffer)
+  }
+  // borrowed view of the dataset that `next_in` last returned true for, from the same `input`
+  fn view_in<'a>(&'a self, input: &'a [u8]) -> Option<DatasetRef<'a>> {
+    self.delta.view(self.payload(input))
+  }
+  // decode from `reader` until `next_buffered` returns true. returns false at the end of the
+  // input
+  fn read(&mut self, reader: &mut impl std::io::Read) -> Result<bool,DecodeError> {
+    loop {
+      match self.step()? {
+        Step::Input() => {
+          let result = reader.read(&mut self.buffer);
+          if !self.read_into_buffer(result)? { return Ok(false) }
+        },
+        step => return Ok(step == Step::Frame()),
+      }
+    }
+  }
+  // decode from the buffered input until a frame is done or more input is needed. sync and
+  // async readers answer `Step::Input()` by reading into `buffer` and passing the result to
+  // `read_into_buffer`
+  fn step(&mut self) -> Result<Step,DecodeError> {
+    loop {
+      if self.ended() { return Ok(Step::End()) }
+      if self.needs_input() { return Ok(Step::Input()) }
+      if self.next_buffered()? { return Ok(Step::Frame()) }
+    }
+  }
+  // take the `result` of a read into `buffer`. returns false at the end of the input
+  fn read_into_buffer(&mut self, result: std::io::Result<usize>) -> Result<bool,DecodeError> {
+    let n = match result {
+      Ok(n) => n,
+      Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return Ok(true),
+      Err(e) => return Err(DecodeError::StreamReadError {
+        source: Box::new(e.into()),
+        context: self.context(),
+      }),
+    };
+    self.filled(n);
+    if n > 0 { return Ok(true) }
+    match self.finish() {
+      Some(e) => Err(e),
+      None => Ok(false),
+    }
+  }
+  fn ended(&self) -> bool {
+    self.state == State::End() || self.state == State::Failed()
+  }
+  // record the reset byte at `index` as a frame of its own
+  fn reset_frame(&mut self) -> bool {
+    self.single_frame(0xff)
+  }
+  // record the single-byte dataset `b` at `index`, which has no length or payload, as a frame
+  fn single_frame(&mut self, b: u8) -> bool {
+    self.frame_offset = self.buffer_offset + (self.index as u64);
+    self.type_byte = b;
+    self.data_type = dataset_type(b);
+    self.payload = None;
+    self.chunk.clear();
+    self.index += 1;
+    true
+  }
+  // decode from the bytes already in the buffer
+  fn next_buffered(&mut self) -> Result<bool,DecodeError> {
+    let buffer = std::mem::take(&mut self.buffer);
+    let result = self.next_in(&buffer);
+    self.buffer = buffer;
+    result
+  }
+  // decode from the first `buffer_len` bytes of `input`, which holds the input from
+  // `buffer_offset` on. returns Ok(true) when a dataset is ready for `view_in`, or in `frames`
+  // mode when a frame is ready for `payload`, and Ok(false) once the input is exhausted.
+  // without `lenient`, the first error ends the input.
+  fn next_in(&mut self, input: &[u8]) -> Result<bool,DecodeError> {
+    if self.ended() { return Ok(false) }
+    let result = self.scan(input);
+    if result.is_err() && !self.options.lenient {
+      self.state = State::Failed();
+    }
+    result
+  }
+  // decode the frame at `index` in one step when all of it is in `input`, instead of stepping
+  // `scan` through its type and length byte by byte. returns None where `scan` has to take
+  // over: at the start of the input, partway into a frame, while skipping frames after an
+  // error and when the frame runs past the end of the input.
+  #[inline]
+  fn next_whole(&mut self, input: &[u8]) -> Option<Result<bool,DecodeError>> {
+    if self.state != State::Type() || self.skipped.is_some() || self.index >= self.buffer_len {
+      return None;
+    }
+    let b = input[self.index];
+    if (0xf0..0xff).contains(&b) { return None }
+    if b == 0xff {
+      if self.frames { return Some(Ok(self.reset_frame())) }
+      self.delta.reset();
+      self.index += 1;
+      return Some(Ok(false));
+    }
+    let (start,len) = match input.get(self.index+1) {
+      Some(l) if *l < 0x80 && self.index + 1 < self.buffer_len => {
+        (self.index + 2, *l as usize)
       },
-      Some(DatasetType::Way()) => {
-        let (s,(id,info)) = parse::info(
-          &buf[offset..],
-          &self.prev_id,
-          &self.prev_info,
-          &mut self.strings
-        )?;
-        offset += s;
-        if offset == buf.len() {
-          return Ok(Some(Dataset::Way(Way {
-            id,
-            info,
-            data: None,
-            tags: std::collections::HashMap::new(),
-          })));
+      _ => {
+        let (n,len) = parse::unsigned(&input[self.index+1..self.buffer_len]).ok()?;
+        (self.index + 1 + n, usize::try_from(len).ok()?)
+      },
+    };
+    let end = start.checked_add(len)?;
+    if end > self.buffer_len { return None }
+    self.frame_offset = self.buffer_offset + (self.index as u64);
+    self.type_byte = b;
+    self.data_type = dataset_type(b);
+    self.payload = Some((start,end));
+    self.chunk.clear();
+    self.index = end;
+    if self.frames { return Some(Ok(true)) }
+    match self.delta.parse(&self.data_type, &input[start..end], self.frame_offset) {
+      Ok(ready) => Some(Ok(ready)),
+      Err(e) => {
+        self.state = State::Data();
+        let e = e.with_context(self.context());
+        if self.options.lenient {
+          self.skip(e);
+          return Some(Ok(false));
         }
-        // reflen is the number of BYTES, not the number of refs
-        let (s,reflen) = parse::unsigned(&buf[offset..])?;
-        offset += s;
-        let mut refs = vec![];
-        let mut prev_ref = match &self.prev {
-          Some(Dataset::Way(way)) => way.data.as_ref().and_then(|d| {
-            d.refs.last().copied()
-          }).unwrap_or(0),
-          _ => 0
+        self.state = State::Failed();
+        Some(Err(e))
+      },
+    }
+  }
+  fn scan(&mut self, input: &[u8]) -> Result<bool,DecodeError> {
+    loop {
+      // a frame with an empty payload is whole once its length is read, even at the end of the
+      // input
+      let b = match input[..self.buffer_len].get(self.index) {
+        Some(b) => *b,
+        None if self.state == State::Data() && self.len == 0 => 0,
+        None => break,
+      };
+      if self.state == State::Begin() && b != 0xff {
+        let e = DecodeError::UnexpectedByte {
+          info: "first byte in frame".to_string(),
+          expected: 0xff,
+          received: b,
+          context: self.context(),
+          backtrace: ErrorTrace::capture(),
         };
-        let ref_end = offset + reflen as usize;
-        while offset < ref_end {
-          let (s,x) = parse::signed(&buf[offset..])?;
-          offset += s;
-          let r = (x + (prev_ref as i64)) as u64;
-          refs.push(r);
-          prev_ref = r;
+        if !self.options.lenient { return Err(e) }
+        // read the input as frames from here on until a reset byte comes up between them
+        self.skip(e);
+        continue;
+      } else if self.state == State::Begin() {
+        self.state = State::Type();
+        if self.frames { return Ok(self.reset_frame()) }
+      } else if self.state == State::Type() && (0xf0..0xff).contains(&b) {
+        // datasets 0xf0 to 0xfe are a single byte with no length. 0xfe marks the end of the
+        // file, and anything after it is ignored
+        if b == 0xfe {
+          self.state = State::End();
+          let end = self.buffer_offset + (self.index as u64);
+          if let Some(e) = self.end_skip(end) {
+            self.index += 1;
+            return Err(e);
+          }
         }
-        let (_,tags) = parse::tags(&buf[offset..], &mut self.strings)?;
-        Some(Dataset::Way(Way {
-          id,
-          info,
-          data: Some(WayData { refs }),
-          tags
-        }))
-      },
-      Some(DatasetType::Relation()) => {
-        let (s,(id,info)) = parse::info(
-          &buf[offset..],
-          &self.prev_id,
-          &self.prev_info,
-          &mut self.strings
-        )?;
-        offset += s;
-        if offset == buf.len() {
-          return Ok(Some(Dataset::Relation(Relation {
-            id,
-            info,
-            data: None,
-            tags: std::collections::HashMap::new(),
-          })));
+        if self.frames && self.skipped.is_none() { return Ok(self.single_frame(b)) }
+        self.index += 1;
+        if b == 0xfe { return Ok(false) }
+        continue;
+      } else if self.state == State::Type() && b == 0xff && self.skipped.is_some() {
+        self.delta.reset();
+        let end = self.buffer_offset + (self.index as u64);
+        // in `frames` mode the reset byte is returned as a frame next
+        if !self.frames { self.index += 1 }
+        if let Some(e) = self.end_skip(end) { return Err(e) }
+        continue;
+      } else if self.state == State::Type() && b == 0xff { // reset
+        if self.frames { return Ok(self.reset_frame()) }
+        self.delta.reset();
+      } else if self.state == State::Type() {
+        self.state = State::Len();
+        self.frame_offset = self.buffer_offset + (self.index as u64);
+        self.type_byte = b;
+        self.data_type = dataset_type(b);
+      } else if self.state == State::Len() {
+        let digit = ((b & 0x7f) as usize).saturating_mul(self.npow as usize);
+        self.len = self.len.saturating_add(digit);
+        self.npow = self.npow.saturating_mul(0x80);
+        if b < 0x80 {
+          self.npow = 1;
+          self.state = State::Data();
+          // the previous frame stays in `chunk` until here so that its view remains valid
+          self.payload = None;
+          self.chunk.clear();
+          if self.frames && self.len == 0 && self.skipped.is_none() {
+            self.state = State::Type();
+            self.index += 1;
+            return Ok(true);
+          }
         }
-        // reflen is the number of BYTES, not the number of refs
-        let (s,reflen) = parse::unsigned(&buf[offset..])?;
-        offset += s;
-        let mut members = vec![];
-        let mut prev_id = match &self.prev {
-          Some(Dataset::Relation(rel)) => rel.data.as_ref().and_then(|d| {
-            d.members.last().map(|m| m.id)
-          }).unwrap_or(0),
-          _ => 0
+      } else if self.state == State::Data() {
+        let payload = if self.size == 0 && self.len <= self.buffer_len - self.index {
+          // the whole payload is in the input, so it is parsed where it is
+          let (start,end) = (self.index,self.index+self.len);
+          self.payload = Some((start,end));
+          self.index = end;
+          &input[start..end]
+        } else {
+          let j = self.buffer_len.min(self.index.saturating_add(self.len-self.size));
+          self.chunk.extend_from_slice(&input[self.index..j]);
+          self.size += j-self.index;
+          self.index = j;
+          if self.size < self.len { continue }
+          &self.chunk[..]
         };
-        let ref_end = offset + reflen as usize;
-        while offset < ref_end {
-          let m_id = {
-            let (s,x) = parse::signed(&buf[offset..])?;
-            offset += s;
-            (x + (prev_id as i64)) as u64
-          };
-          prev_id = m_id;
-          let mstring = {
-            let (s,x) = parse::unsigned(&buf[offset..])?;
-            offset += s;
-            if x == 0 {
-              let i = offset + buf[offset..].iter()
-                .position(|p| *p == 0x00).unwrap_or(buf.len()-offset);
-              let mbytes = &buf[offset..i];
-              offset = i+1;
-              if mbytes.len() <= 250 {
-                self.strings.push_front((mbytes.to_vec(),vec![]));
-                if self.strings.len() > 15_000 { self.strings.pop_back(); }
-              }
-              mbytes
-            } else {
-              let pair = self.strings.get((x as usize)-1);
-              if pair.is_none() {
-                return Err(DecodeError::StringUnavailable {
-                  index: x as usize,
-                  backtrace: Backtrace::capture(),
-                });
-              }
-              &pair.unwrap().0
-            }
-          };
-          members.push(RelationMember {
-            id: m_id,
-            element_type: match mstring[0] {
-              0x30 => ElementType::Node(),
-              0x31 => ElementType::Way(),
-              0x32 => ElementType::Relation(),
-              x => return Err(DecodeError::UnexpectedElementType {
-                received: x,
-                backtrace: Backtrace::capture(),
-              }),
+        // frames are dropped while recovering from an error and returned as they are in
+        // `frames` mode
+        let ready = if self.skipped.is_some() {
+          false
+        } else if self.frames {
+          true
+        } else {
+          match self.delta.parse(&self.data_type, payload, self.frame_offset) {
+            Ok(ready) => ready,
+            Err(e) if self.options.lenient => {
+              let e = e.with_context(self.context());
+              self.skip(e);
+              continue;
             },
-            role: String::from_utf8(mstring[1..].to_vec())
-              .map_err(|e| DecodeError::StringEncodingError { source: Box::new(e.into()) })?,
-          });
-        }
-        let (_,tags) = parse::tags(&buf[offset..], &mut self.strings)?;
-        Some(Dataset::Relation(Relation {
-          id,
-          info,
-          data: Some(RelationData { members }),
-          tags
-        }))
-      },
-      Some(DatasetType::Timestamp()) => {
-        let (_,time) = parse::signed(&buf[offset..])?;
-        Some(Dataset::Timestamp(Timestamp { time }))
-      },
-      Some(DatasetType::BBox()) => {
-        let (s,x1) = parse::signed(&buf[offset..])?;
-        offset += s;
-        let (s,y1) = parse::signed(&buf[offset..])?;
-        offset += s;
-        let (s,x2) = parse::signed(&buf[offset..])?;
-        offset += s;
-        let (_,y2) = parse::signed(&buf[offset..])?;
-        Some(Dataset::BBox(BBox {
-          x1: x1 as i32,
-          y1: y1 as i32,
-          x2: x2 as i32,
-          y2: y2 as i32,
-        }))
-      },
-      Some(DatasetType::Header()) => None,
-      Some(DatasetType::Sync()) => None,
-      Some(DatasetType::Jump()) => None,
-      Some(DatasetType::Reset()) => None,
-      None => None,
-    })
+            Err(e) => return Err(e.with_context(self.context())),
+          }
+        };
+        self.state = State::Type();
+        self.len = 0;
+        self.size = 0;
+        if ready { return Ok(true) }
+        continue;
+      }
+      self.index += 1;
+    }
+    Ok(false)
+  }
+}
+
+/// Synchronous decoder that reads o5m from any `std::io::Read`
+/// and iterates over fallible `Dataset` items.
+/// Unless `DecoderOptions::lenient` is set, iteration ends after the first decoding error.
+pub struct Decoder<R: std::io::Read> {
+  reader: R,
+  state: DecoderState,
+  // a dataset was read ahead by `header` or `seek_to` and not returned yet
+  pending: bool,
+  started: bool,
+}
+
+impl<R: std::io::Read> Decoder<R> {
+  pub fn new(reader: R) -> Self {
+    Self::with_options(reader, DecoderOptions::default())
+  }
+  pub fn with_options(reader: R, options: DecoderOptions) -> Self {
+    Self { reader, state: DecoderState::new(options), pending: false, started: false }
+  }
+  /// Read the header dataset at the start of the input without consuming it,
+  /// so that the format can be checked before the first element.
+  /// Returns `None` if the input does not begin with a header.
+  pub fn header(&mut self) -> Result<Option<Header>,DecodeError> {
+    if !self.started && !self.pending {
+      self.pending = self.read_frame()?;
+    }
+    Ok(self.state.delta.header.clone())
+  }
+  pub fn next_item(&mut self) -> Result<Option<Dataset>,DecodeError> {
+    Ok(self.next_ref()?.map(|data| data.to_dataset()))
+  }
+  /// Decode the next dataset without allocating. The returned view borrows from the decoder
+  /// and is valid until the next call. Use `DatasetRef::to_dataset` to keep a copy.
+  pub fn next_ref(&mut self) -> Result<Option<DatasetRef<'_>>,DecodeError> {
+    self.started = true;
+    if !std::mem::take(&mut self.pending) && !self.read_frame()? {
+      return Ok(None);
+    }
+    Ok(self.state.view())
+  }
+  /// Byte offset in the input of the most recently started dataset.
+  pub fn offset(&self) -> u64 {
+    self.state.frame_offset
+  }
+  // returns whether a dataset was decoded, or false at the end of the input
+  fn read_frame(&mut self) -> Result<bool,DecodeError> {
+    self.state.read(&mut self.reader)
+  }
+}
+
+impl<R: std::io::Read+std::io::Seek> Decoder<R> {
+  /// Skip ahead to the first element of `element_type` by following the offsets stored in jump
+  /// (0xef) datasets, which writers place at the start of the node, way and relation sections.
+  /// The section a jump belongs to is only known once the element after it is decoded, so that
+  /// element is read before the jump is taken. A jump that points backwards, past the end of
+  /// the input or anywhere but a jump or reset dataset is ignored.
+  /// Without usable jump datasets the elements in between are decoded and discarded.
+  /// Returns `false` if the input holds no elements of `element_type` past the current position.
+  /// The first matching element is returned by the next call to `next_item`.
+  pub fn seek_to(&mut self, element_type: ElementType) -> Result<bool,DecodeError> {
+    self.started = true;
+    loop {
+      if !std::mem::take(&mut self.pending) && !self.read_frame()? {
+        return Ok(false);
+      }
+      match self.state.delta.parsed_type() {
+        Some(t) if t >= element_type => {
+          self.pending = true;
+          return Ok(t == element_type);
+        },
+        Some(_) => {
+          let frame_offset = self.state.frame_offset;
+          if let Some(offset) = self.state.delta.jump.take().filter(|x| *x > frame_offset) {
+            let landed = self.jump(offset).map_err(|e| DecodeError::StreamSeekError {
+              source: Box::new(e.into()),
+              context: self.state.context(),
+            })?;
+            if landed {
+              self.state.seeked(offset);
+            }
+          }
+        },
+        None => {},
+      }
+    }
+  }
+  // move the reader to `offset` if a jump or reset dataset starts there, or leave it where it
+  // was otherwise. returns whether the reader moved.
+  fn jump(&mut self, offset: u64) -> std::io::Result<bool> {
+    let position = self.reader.stream_position()?;
+    self.reader.seek(std::io::SeekFrom::Start(offset))?;
+    let mut byte = [0];
+    let landed = match self.reader.read_exact(&mut byte) {
+      Ok(()) => byte[0] == 0xef || byte[0] == 0xff,
+      Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => false,
+      Err(e) => return Err(e),
+    };
+    self.reader.seek(std::io::SeekFrom::Start(if landed { offset } else { position }))?;
+    Ok(landed)
+  }
+}
+
+impl<R: std::io::Read> Iterator for Decoder<R> {
+  type Item = DecodeItem;
+  fn next(&mut self) -> Option<Self::Item> {
+    match self.next_item() {
+      Ok(None) => None,
+      Ok(Some(x)) => Some(Ok(x)),
+      Err(e) => Some(Err(e)),
+    }
+  }
+}
+
+struct AsyncDecoder {
+  reader: Box<dyn io::AsyncRead+Send+Unpin>,
+  state: DecoderState,
+}
+
+impl AsyncDecoder {
+  pub fn new(reader: Box<dyn io::AsyncRead+Send+Unpin>, options: DecoderOptions) -> Self {
+    Self { reader, state: DecoderState::new(options) }
+  }
+  pub async fn next_item(&mut self) -> Result<Option<Dataset>,DecodeError> {
+    loop {
+      match self.state.step()? {
+        Step::Input() => {
+          let result = self.reader.read(&mut self.state.buffer).await;
+          if !self.state.read_into_buffer(result)? { return Ok(None) }
+        },
+        Step::Frame() => return Ok(self.state.view().map(|data| data.to_dataset())),
+        Step::End() => return Ok(None),
+      }
+    }
   }
 }
 
 /// Transform the given binary stream `reader` into an stream of fallible `Dataset` items.
-pub fn decode(reader: Box<dyn io::Read+Send+Unpin>) -> DecodeStream {
-  let state = Decoder::new(reader);
-  Box::new(unfold::unfold(state, async move |mut qs| {
+/// The header dataset, when present, is the first item.
+/// Any `futures::io::AsyncRead` works here, including async-std readers.
+pub fn decode(reader: Box<dyn io::AsyncRead+Send+Unpin>) -> DecodeStream {
+  decode_with_options(reader, DecoderOptions::default())
+}
+
+/// Like `decode`, with `options` to control how the input is decoded.
+pub fn decode_with_options(
+  reader: Box<dyn io::AsyncRead+Send+Unpin>,
+  options: DecoderOptions,
+) -> DecodeStream {
+  let state = AsyncDecoder::new(reader, options);
+  Box::new(unfold::unfold(state, |mut qs| async move {
     match qs.next_item().await {
       Ok(None) => None,
       Ok(Some(x)) => Some((Ok(x),qs)),
@@ -386,3 +794,10 @@ pub fn decode(reader: Box<dyn io::Read+Send+Unpin>) -> DecodeStream {
     }
   }))
 }
+
+/// Transform the given tokio binary stream `reader` into an stream of fallible `Dataset` items.
+#[cfg(feature="tokio")]
+pub fn decode_tokio(reader: Box<dyn tokio::io::AsyncRead+Send+Unpin>) -> DecodeStream {
+  use tokio_util::compat::TokioAsyncReadCompatExt;
+  decode(Box::new(reader.compat()))
+}
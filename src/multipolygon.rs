@@ -0,0 +1,200 @@
+use futures::prelude::*;
+use std::collections::{HashMap,VecDeque};
+use crate::filter::ring_contains;
+use crate::{Assembled,AssembleStream,Dataset,ElementType,NodeData,Relation,WayGeometry};
+
+/// Closed ring built from one or more ways. The first point is repeated at the end.
+#[derive(Clone,PartialEq,Debug)]
+pub struct Ring {
+  /// Ids of the ways that make up the ring, in the order they were joined.
+  pub ways: Vec<u64>,
+  pub points: Vec<NodeData>,
+}
+
+impl Ring {
+  fn area2(&self) -> i128 {
+    area2(&self.points)
+  }
+  fn orient(&mut self, counter_clockwise: bool) {
+    if (self.area2() > 0) != counter_clockwise {
+      self.points.reverse();
+    }
+  }
+}
+
+/// Outer ring, counter-clockwise, with the inner rings inside it, clockwise.
+#[derive(Clone,PartialEq,Debug)]
+pub struct Polygon {
+  pub outer: Ring,
+  pub inners: Vec<Ring>,
+}
+
+/// Member ways that could not be made part of a polygon.
+#[derive(Clone,PartialEq,Debug)]
+pub enum InvalidRing {
+  /// Member way that was not in the input or has nodes without a location.
+  MissingWay(u64),
+  /// Ways that do not join up into a closed ring, with the points of the open chain.
+  Unclosed(Ring),
+  /// Closed ring with fewer than 3 distinct points.
+  Degenerate(Ring),
+  /// Inner ring that is not inside any of the outer rings.
+  UnmatchedInner(Ring),
+}
+
+/// Area built from a `type=multipolygon` or `type=boundary` relation.
+#[derive(Clone,PartialEq,Debug)]
+pub struct MultipolygonGeometry {
+  pub relation: Relation,
+  pub polygons: Vec<Polygon>,
+  pub invalid: Vec<InvalidRing>,
+}
+
+// twice the signed area of a closed ring, positive for counter-clockwise rings
+pub(crate) fn area2(points: &[NodeData]) -> i128 {
+  points.windows(2).map(|w| {
+    let (ax,ay) = (w[0].longitude.0 as i128, w[0].latitude.0 as i128);
+    let (bx,by) = (w[1].longitude.0 as i128, w[1].latitude.0 as i128);
+    ax*by - bx*ay
+  }).sum()
+}
+
+// way or chain of ways joined end to end
+struct Chain {
+  ways: Vec<u64>,
+  refs: Vec<u64>,
+  points: Vec<NodeData>,
+}
+
+impl Chain {
+  fn is_closed(&self) -> bool {
+    self.refs.len() > 1 && self.refs.first() == self.refs.last()
+  }
+  fn into_ring(self) -> Ring {
+    Ring { ways: self.ways, points: self.points }
+  }
+}
+
+// join chains end to end into closed rings. returns the rings and the chains left open.
+fn join(chains: Vec<Chain>) -> (Vec<Ring>,Vec<InvalidRing>) {
+  let mut chains: VecDeque<Chain> = chains.into();
+  let mut rings = vec![];
+  let mut invalid = vec![];
+  while let Some(mut chain) = chains.pop_front() {
+    while !chain.is_closed() {
+      let end = chain.refs.last().copied();
+      let next = chains.iter()
+        .position(|c| c.refs.first().copied() == end || c.refs.last().copied() == end);
+      let mut next = match next.and_then(|i| chains.remove(i)) {
+        Some(next) => next,
+        None => break,
+      };
+      if next.refs.first().copied() != end {
+        next.refs.reverse();
+        next.points.reverse();
+      }
+      chain.ways.extend(next.ways);
+      chain.refs.extend_from_slice(&next.refs[1..]);
+      chain.points.extend_from_slice(&next.points[1..]);
+    }
+    if !chain.is_closed() {
+      invalid.push(InvalidRing::Unclosed(chain.into_ring()));
+    } else if chain.refs.len() < 4 {
+      invalid.push(InvalidRing::Degenerate(chain.into_ring()));
+    } else {
+      rings.push(chain.into_ring());
+    }
+  }
+  (rings,invalid)
+}
+
+/// Builds areas from multipolygon and boundary relations out of the ways that came before them.
+/// Every way with a complete geometry is kept in memory until the end of the input.
+#[derive(Default)]
+pub struct MultipolygonAssembler {
+  ways: HashMap<u64,(Vec<u64>,Vec<NodeData>)>,
+}
+
+impl MultipolygonAssembler {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Remember way geometry and replace multipolygon and boundary relations with their areas.
+  /// Everything else is returned unchanged.
+  pub fn assemble(&mut self, item: Assembled) -> Assembled {
+    match item {
+      Assembled::Way(geometry) => {
+        self.store(&geometry);
+        Assembled::Way(geometry)
+      },
+      Assembled::Dataset(Dataset::Relation(relation)) if is_area(&relation) => {
+        Assembled::Multipolygon(self.build(relation))
+      },
+      item => item,
+    }
+  }
+  fn store(&mut self, geometry: &WayGeometry) {
+    let refs = match &geometry.way.data {
+      Some(data) if geometry.is_complete() && data.refs.len() > 1 => data.refs.clone(),
+      _ => return,
+    };
+    let points = geometry.points.iter().flatten().cloned().collect();
+    self.ways.insert(geometry.way.id, (refs,points));
+  }
+  fn build(&self, relation: Relation) -> MultipolygonGeometry {
+    let mut invalid = vec![];
+    let mut outers = vec![];
+    let mut inners = vec![];
+    for m in relation.data.iter().flat_map(|d| d.members.iter()) {
+      if m.element_type != ElementType::Way() { continue }
+      let list = match m.role.as_str() {
+        "outer" | "" => &mut outers,
+        "inner" => &mut inners,
+        _ => continue,
+      };
+      match self.ways.get(&m.id) {
+        Some((refs,points)) => list.push(Chain {
+          ways: vec![m.id],
+          refs: refs.clone(),
+          points: points.clone(),
+        }),
+        None => invalid.push(InvalidRing::MissingWay(m.id)),
+      }
+    }
+    let (outers,mut e) = join(outers);
+    invalid.append(&mut e);
+    let (inners,mut e) = join(inners);
+    invalid.append(&mut e);
+    let mut polygons: Vec<Polygon> = outers.into_iter().map(|mut outer| {
+      outer.orient(true);
+      Polygon { outer, inners: vec![] }
+    }).collect();
+    for mut inner in inners {
+      // the smallest outer ring that holds part of the inner ring
+      let outer = polygons.iter().enumerate()
+        .filter(|(_,p)| inner.points.iter().any(|q| ring_contains(&p.outer.points, q)))
+        .min_by_key(|(_,p)| p.outer.area2().abs())
+        .map(|(i,_)| i);
+      inner.orient(false);
+      match outer {
+        Some(i) => polygons[i].inners.push(inner),
+        None => invalid.push(InvalidRing::UnmatchedInner(inner)),
+      }
+    }
+    MultipolygonGeometry { relation, polygons, invalid }
+  }
+}
+
+fn is_area(relation: &Relation) -> bool {
+  relation.data.is_some() && matches!(
+    relation.tags.get("type").map(|t| t.as_str()),
+    Some("multipolygon") | Some("boundary")
+  )
+}
+
+/// Replace the multipolygon and boundary relations in `stream`, the output of `assemble_ways`,
+/// with `Assembled::Multipolygon` areas joined from their member ways.
+pub fn assemble_multipolygons(stream: AssembleStream) -> AssembleStream {
+  let mut assembler = MultipolygonAssembler::new();
+  Box::new(stream.map(move |item| item.map(|item| assembler.assemble(item))))
+}
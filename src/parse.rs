@@ -1,159 +1,336 @@
-use crate::{DecodeError,Info};
-type Strings = std::collections::VecDeque<(Vec<u8>,Vec<u8>)>;
-use std::backtrace::Backtrace;
+use crate::{DecodeError,ErrorContext,ErrorTrace,ElementType,TagFilter};
+use std::collections::VecDeque;
 
-pub fn info(buf: &[u8], prev_id: &Option<u64>, prev_info: &Option<Info>, strings: &mut Strings)
--> Result<(usize,(u64,Option<Info>)),DecodeError> {
-  let mut offset = 0;
-  let mut info = Info::new();
+/// Location of a string decoded from a dataset: a byte range of the dataset itself,
+/// or a byte range of an entry in the string table.
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub(crate) enum Span {
+  Chunk(usize,usize),
+  Table(u64,usize,usize),
+}
+
+/// Table of recently seen strings that datasets refer back to by index.
+/// Each entry is the bytes of a string pair and the position where the second string begins.
+#[derive(Clone)]
+pub(crate) struct StringTable {
+  entries: VecDeque<(Vec<u8>,usize)>,
+  // entries pushed out of the table while parsing the current dataset, most recent last.
+  // the dataset may still refer to them from before they went
+  evicted: Vec<(Vec<u8>,usize)>,
+  // number of entries ever pushed, so that spans stay valid while the table shifts
+  seq: u64,
+}
+
+impl StringTable {
+  pub fn new() -> Self {
+    Self { entries: VecDeque::new(), evicted: vec![], seq: 0 }
+  }
+  pub fn clear(&mut self) {
+    self.entries.clear();
+    self.evicted.clear();
+  }
+  // forget the entries pushed out by the previous dataset
+  pub fn start(&mut self) {
+    self.evicted.clear();
+  }
+  pub fn push(&mut self, a: &[u8], b: &[u8]) {
+    if a.len() + b.len() > 250 { return }
+    let mut bytes = Vec::with_capacity(a.len() + b.len());
+    bytes.extend_from_slice(a);
+    bytes.extend_from_slice(b);
+    self.entries.push_front((bytes,a.len()));
+    self.seq += 1;
+    if self.entries.len() > 15_000 {
+      if let Some(entry) = self.entries.pop_back() { self.evicted.push(entry) }
+    }
+  }
+  // look up the 1-based back-reference `x` and return spans for both strings of the pair
+  pub fn pair(&self, x: u64) -> Result<(Span,Span),DecodeError> {
+    let i = (x as usize).wrapping_sub(1);
+    match self.entries.get(i) {
+      Some((bytes,split)) => {
+        let seq = self.seq - (i as u64);
+        Ok((Span::Table(seq,0,*split),Span::Table(seq,*split,bytes.len())))
+      },
+      None => Err(DecodeError::StringUnavailable {
+        index: x as usize,
+        context: ErrorContext::default(),
+        backtrace: ErrorTrace::capture(),
+      }),
+    }
+  }
+  // bytes at `span`, including entries the current dataset pushed out after referring to them.
+  // entries pushed out before it are unavailable
+  pub fn get<'a>(&'a self, chunk: &'a [u8], span: &Span) -> Result<&'a [u8],DecodeError> {
+    match span {
+      Span::Chunk(start,end) => Ok(&chunk[*start..*end]),
+      Span::Table(seq,start,end) => {
+        let i = self.seq.wrapping_sub(*seq) as usize;
+        let entry = match i.checked_sub(self.entries.len()) {
+          _ if *seq > self.seq => None,
+          None => self.entries.get(i),
+          Some(j) => self.evicted.len().checked_sub(j+1).and_then(|j| self.evicted.get(j)),
+        };
+        match entry {
+          Some((bytes,_)) => Ok(&bytes[*start..*end]),
+          _ => Err(DecodeError::StringUnavailable {
+            index: i.saturating_add(1),
+            context: ErrorContext::default(),
+            backtrace: ErrorTrace::capture(),
+          }),
+        }
+      },
+    }
+  }
+  // string at `span`, which was checked to be valid utf-8 and still in the table when the
+  // dataset was parsed
+  pub fn get_str<'a>(&'a self, chunk: &'a [u8], span: &Span) -> &'a str {
+    self.get(chunk, span).ok().and_then(|bytes| std::str::from_utf8(bytes).ok()).unwrap_or_default()
+  }
+}
+
+/// Author information of an element with its user name left in place.
+#[derive(Clone,PartialEq,Debug)]
+pub(crate) struct InfoSpan {
+  pub version: u64,
+  pub timestamp: Option<i64>,
+  pub changeset: Option<u64>,
+  pub uid: Option<u64>,
+  pub user: Option<Span>,
+}
+
+fn check_str(bytes: &[u8]) -> Result<(),DecodeError> {
+  std::str::from_utf8(bytes)
+    .map_err(|e| DecodeError::StringEncodingError {
+      source: Box::new(e.into()),
+      context: ErrorContext::default(),
+    })?;
+  Ok(())
+}
+
+// find the 0x00 terminator of the string starting at `offset`.
+// returns the end of the string and the offset after the terminator.
+fn terminated(buf: &[u8], offset: usize) -> (usize,usize) {
+  let i = offset + buf[offset..].iter()
+    .position(|p| *p == 0x00).unwrap_or(buf.len()-offset);
+  (i,(i+1).min(buf.len()))
+}
+
+/// Parse the id and author information of an element starting at `offset`.
+/// Returns the offset after the information, the id and the info if the element has any.
+pub(crate) fn info(buf: &[u8], offset: usize, prev_id: Option<u64>, prev_timestamp: i64,
+prev_changeset: u64, strings: &mut StringTable)
+-> Result<(usize,u64,Option<InfoSpan>),DecodeError> {
+  let mut offset = offset;
   let id = {
     let (s,x) = signed(&buf[offset..])?;
     offset += s;
-    (x + prev_id.unwrap_or(0) as i64) as u64
+    x.wrapping_add(prev_id.unwrap_or(0) as i64) as u64
   };
-  info.version = {
-    let (s,x) = unsigned(&buf[offset..])?;
-    offset += s;
-    if x == 0 { return Ok((offset, (id, None))) }
-    Some(x)
+  let mut info = InfoSpan {
+    version: {
+      let (s,x) = unsigned(&buf[offset..])?;
+      offset += s;
+      if x == 0 { return Ok((offset, id, None)) }
+      x
+    },
+    timestamp: None,
+    changeset: None,
+    uid: None,
+    user: None,
   };
   info.timestamp = {
     let (s,x) = signed(&buf[offset..])?;
     offset += s;
-    let p = prev_info.as_ref().map(|info| {
-      info.timestamp.unwrap_or(0)
-    }).unwrap_or(0);
-    if x + p == 0 { return Ok((offset, (id, Some(info)))) }
-    Some(x + p)
+    if x.wrapping_add(prev_timestamp) == 0 { return Ok((offset, id, Some(info))) }
+    Some(x.wrapping_add(prev_timestamp))
   };
   info.changeset = {
     let (s,x) = signed(&buf[offset..])?;
     offset += s;
-    let p = prev_info.as_ref().map(|info| {
-      info.changeset.unwrap_or(0)
-    }).unwrap_or(0) as i64;
-    Some((x + p) as u64)
+    Some(x.wrapping_add(prev_changeset as i64) as u64)
   };
   {
     let (s,x) = unsigned(&buf[offset..])?;
     offset += s;
     if x == 0 {
       let (s,x) = unsigned(&buf[offset..])?;
-      let uid_bytes = &buf[offset..offset+s];
+      let uid_start = offset;
       offset += s;
       info.uid = Some(x);
-      if buf[offset] != 0 {
+      if buf.get(offset) != Some(&0) {
         return Err(DecodeError::UnexpectedByte {
           info: "decoding uid".to_string(),
           expected: 0,
-          received: buf[offset],
-          backtrace: Backtrace::capture(),
+          received: buf.get(offset).copied().unwrap_or(0),
+          context: ErrorContext::default(),
+          backtrace: ErrorTrace::capture(),
         });
       }
       offset += 1;
-      let i = offset + buf[offset..].iter()
-        .position(|p| *p == 0x00).unwrap_or(buf.len()-offset);
-      info.user = Some(std::str::from_utf8(&buf[offset..i])
-        .map_err(|e| DecodeError::StringEncodingError {
-          source: Box::new(e.into())
-        })?
-        .to_string()
-      );
-      if uid_bytes.len() + (i-offset) <= 250 {
-        strings.push_front((uid_bytes.to_vec(),buf[offset..i].to_vec()));
-        if strings.len() > 15_000 { strings.pop_back(); }
-      }
-      offset = i+1;
+      let (i,next) = terminated(buf, offset);
+      check_str(&buf[offset..i])?;
+      info.user = Some(Span::Chunk(offset,i));
+      strings.push(&buf[uid_start..offset-1], &buf[offset..i]);
+      offset = next;
     } else {
-      let pair = strings.get((x as usize)-1);
-      if pair.is_none() {
-        return Err(DecodeError::StringUnavailable {
-          index: x as usize,
-          backtrace: Backtrace::capture(),
-        });
-      }
-      let (uid_bytes,user_bytes) = pair.unwrap();
-      info.uid = Some(unsigned(uid_bytes)?.1);
-      info.user = Some(String::from_utf8(user_bytes.to_vec())
-        .map_err(|e| DecodeError::StringEncodingError { source: Box::new(e.into()) })?
-      );
+      let (uid_span,user_span) = strings.pair(x)?;
+      info.uid = Some(unsigned(strings.get(buf, &uid_span)?)?.1);
+      check_str(strings.get(buf, &user_span)?)?;
+      info.user = Some(user_span);
     }
   }
-  Ok((offset, (id, Some(info))))
+  Ok((offset, id, Some(info)))
 }
 
-type Tags = std::collections::HashMap<String,String>;
-
-pub fn tags(buf: &[u8], strings: &mut Strings) -> Result<(usize,Tags),DecodeError> {
-  let mut tags = std::collections::HashMap::new();
-  let mut offset = 0;
+/// Parse the tags from `offset` to the end of `buf` into key and value spans.
+/// With `out` set to `None`, tags are only read to keep the string table up to date.
+/// Tags with keys that `filter` drops are read the same way but left out of `out`.
+pub(crate) fn tags(buf: &[u8], offset: usize, strings: &mut StringTable,
+out: Option<&mut Vec<(Span,Span)>>, filter: Option<&TagFilter>) -> Result<usize,DecodeError> {
+  let mut offset = offset;
+  let mut out = out;
   while offset < buf.len() {
     let (s,x) = unsigned(&buf[offset..])?;
     offset += s;
-    if x == 0 {
-      let i = offset + buf[offset..].iter()
-        .position(|p| *p == 0x00).unwrap_or(buf.len()-offset);
-      let key_bytes = &buf[offset..i];
-      let key = std::str::from_utf8(key_bytes)
-        .map_err(|e| DecodeError::StringEncodingError { source: Box::new(e.into()) })?
-        .to_string();
-      offset = i+1;
-      let j = offset + buf[offset..].iter()
-        .position(|p| *p == 0x00).unwrap_or(buf.len()-offset);
-      let value_bytes = &buf[offset..j];
-      let value = std::str::from_utf8(value_bytes)
-        .map_err(|e| DecodeError::StringEncodingError { source: Box::new(e.into()) })?
-        .to_string();
-      offset = j+1;
-      tags.insert(key, value);
-      if key_bytes.len() + value_bytes.len() <= 250 {
-        strings.push_front((key_bytes.to_vec(),value_bytes.to_vec()));
-        if strings.len() > 15_000 { strings.pop_back(); }
-      }
+    let (key,value) = if x == 0 {
+      let (i,value_start) = terminated(buf, offset);
+      let (j,next) = terminated(buf, value_start);
+      strings.push(&buf[offset..i], &buf[value_start..j]);
+      let pair = (Span::Chunk(offset,i),Span::Chunk(value_start,j));
+      offset = next;
+      pair
     } else {
-      let pair = strings.get((x as usize)-1);
-      if pair.is_none() {
-        return Err(DecodeError::StringUnavailable {
-          index: x as usize,
-          backtrace: Backtrace::capture(),
-        });
+      strings.pair(x)?
+    };
+    if let Some(out) = out.as_mut() {
+      if let Some(filter) = filter {
+        if !filter.keeps(strings.get(buf, &key)?) { continue }
       }
-      let (key_bytes,value_bytes) = pair.unwrap();
-      let key = String::from_utf8(key_bytes.to_vec())
-        .map_err(|e| DecodeError::StringEncodingError { source: Box::new(e.into()) })?;
-      let value = String::from_utf8(value_bytes.to_vec())
-        .map_err(|e| DecodeError::StringEncodingError { source: Box::new(e.into()) })?;
-      tags.insert(key, value);
+      check_str(strings.get(buf, &key)?)?;
+      check_str(strings.get(buf, &value)?)?;
+      out.push((key,value));
     }
   }
-  Ok((offset,tags))
+  Ok(offset)
+}
+
+/// Parse `len` bytes of delta coded way refs starting at `offset`.
+pub(crate) fn refs(buf: &[u8], offset: usize, len: usize, prev_ref: u64, out: &mut Vec<u64>)
+-> Result<usize,DecodeError> {
+  let mut offset = offset;
+  let mut prev_ref = prev_ref;
+  let ref_end = offset.saturating_add(len);
+  while offset < ref_end {
+    let (s,x) = signed(&buf[offset..])?;
+    offset += s;
+    let r = x.wrapping_add(prev_ref as i64) as u64;
+    out.push(r);
+    prev_ref = r;
+  }
+  Ok(offset)
+}
+
+/// Parse `len` bytes of relation members starting at `offset`.
+/// Each member is a delta coded id followed by a string of the member type and role.
+/// With `out` set to `None`, members are only read to keep the string table up to date.
+pub(crate) fn members(buf: &[u8], offset: usize, len: usize, prev_id: u64,
+strings: &mut StringTable, out: Option<&mut Vec<(u64,ElementType,Span)>>)
+-> Result<usize,DecodeError> {
+  let mut offset = offset;
+  let mut prev_id = prev_id;
+  let out = match out {
+    Some(out) => out,
+    None => {
+      let ref_end = offset.saturating_add(len);
+      while offset < ref_end {
+        let (s,_) = signed(&buf[offset..])?;
+        offset += s;
+        let (s,x) = unsigned(&buf[offset..])?;
+        offset += s;
+        if x == 0 {
+          let (i,next) = terminated(buf, offset);
+          strings.push(&buf[offset..i], &[]);
+          offset = next;
+        }
+      }
+      return Ok(offset);
+    },
+  };
+  let ref_end = offset.saturating_add(len);
+  while offset < ref_end {
+    let m_id = {
+      let (s,x) = signed(&buf[offset..])?;
+      offset += s;
+      x.wrapping_add(prev_id as i64) as u64
+    };
+    prev_id = m_id;
+    let mstring = {
+      let (s,x) = unsigned(&buf[offset..])?;
+      offset += s;
+      if x == 0 {
+        let (i,next) = terminated(buf, offset);
+        let span = Span::Chunk(offset,i);
+        strings.push(&buf[offset..i], &[]);
+        offset = next;
+        span
+      } else {
+        strings.pair(x)?.0
+      }
+    };
+    let (element_type,role) = {
+      let bytes = strings.get(buf, &mstring)?;
+      let element_type = match bytes.first() {
+        Some(0x30) => ElementType::Node(),
+        Some(0x31) => ElementType::Way(),
+        Some(0x32) => ElementType::Relation(),
+        x => return Err(DecodeError::UnexpectedElementType {
+          received: x.copied().unwrap_or(0),
+          context: ErrorContext::default(),
+          backtrace: ErrorTrace::capture(),
+        }),
+      };
+      check_str(&bytes[1..])?;
+      (element_type, match mstring {
+        Span::Chunk(start,end) => Span::Chunk(start+1,end),
+        Span::Table(seq,start,end) => Span::Table(seq,start+1,end),
+      })
+    };
+    out.push((m_id,element_type,role));
+  }
+  Ok(offset)
 }
 
 pub fn signed(buf: &[u8]) -> Result<(usize,i64),DecodeError> {
-  let mut value = 0;
+  let mut value = 0u64;
   let mut lshift = 0;
   for (i,b) in buf.iter().enumerate() {
-    value += ((*b as i64) & 0x7f) << lshift;
+    value |= ((*b as u64) & 0x7f).checked_shl(lshift).unwrap_or(0);
     lshift += 7;
     if *b < 0x80 {
-      let sign = match value % 2 { 0 => 1, _ => -1 };
-      value = value * sign / 2;
-      if sign < 0 { value -= 1 }
-      return Ok((i+1,value));
+      // the lowest bit is the sign, so that the full range of deltas fits in 64 bits
+      let x = (value >> 1) as i64;
+      return Ok((i+1,if value & 1 == 1 { !x } else { x }));
     }
   }
-  Err(DecodeError::UnterminatedSignedInteger { backtrace: Backtrace::capture() })
+  Err(DecodeError::UnterminatedSignedInteger {
+    context: ErrorContext::default(),
+    backtrace: ErrorTrace::capture(),
+  })
 }
 
 pub fn unsigned(buf: &[u8]) -> Result<(usize,u64),DecodeError> {
   let mut value = 0;
   let mut lshift = 0;
   for (i,b) in buf.iter().enumerate() {
-    value += ((*b as u64) & 0x7f) << lshift;
+    value += ((*b as u64) & 0x7f).checked_shl(lshift).unwrap_or(0);
     lshift += 7;
     if *b < 0x80 {
       return Ok((i+1,value));
     }
   }
-  Err(DecodeError::UnterminatedUnsignedInteger { backtrace: Backtrace::capture() })
+  Err(DecodeError::UnterminatedUnsignedInteger {
+    context: ErrorContext::default(),
+    backtrace: ErrorTrace::capture(),
+  })
 }
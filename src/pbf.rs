@@ -0,0 +1,1026 @@
+use futures::{prelude::*,stream::Stream,io};
+use std::collections::{HashMap,VecDeque};
+use std::convert::TryFrom;
+use std::io::{Read,Write};
+use flate2::{Compression,read::ZlibDecoder,write::ZlibEncoder};
+use crate::protobuf::{Fields,Packed,Value,unzigzag,zigzag,write_bytes,write_int,write_packed,
+  write_sint};
+use crate::{
+  BBox,Coord,Dataset,DatasetType,DecodeError,DecodeItem,DecodeStream,DecoderOptions,Delete,
+  ElementType,EncodeError,ErrorContext,ErrorTrace,Header,Info,Node,NodeData,Relation,
+  RelationData,RelationMember,Tags,Timestamp,Way,WayData,
+};
+
+// limits from the pbf specification
+const MAX_HEADER_SIZE: usize = 64*1024;
+const MAX_BLOB_SIZE: usize = 32*1024*1024;
+// elements per block written by the encoder, as osmium and osmosis do
+const BLOCK_SIZE: usize = 8000;
+// optional feature that marks a history file written from an o5c change stream
+const CHANGE_FEATURE: &[u8] = b"OsmChange";
+
+// which part of the next frame the decoder is waiting for
+enum Stage {
+  Len(),
+  Header(usize),
+  Blob(Vec<u8>,usize),
+  Done(),
+}
+
+// per-block values that elements are decoded against
+struct Block<'a> {
+  strings: Vec<&'a [u8]>,
+  granularity: i64,
+  date_granularity: i64,
+  lat_offset: i64,
+  lon_offset: i64,
+}
+
+impl<'a> Block<'a> {
+  // nanodegrees to the 1e-7 degree units of o5m, rounded to the nearest unit, or `None` when the
+  // result does not fit
+  fn coord(&self, offset: i64, x: i64) -> Option<Coord> {
+    let n = self.granularity.checked_mul(x)?.checked_add(offset)?.checked_add(50)?;
+    i32::try_from(n.div_euclid(100)).ok().map(Coord)
+  }
+  fn timestamp(&self, x: i64) -> Option<i64> {
+    Some(x.checked_mul(self.date_granularity)? / 1000)
+  }
+}
+
+// info fields of one element before they are turned into an `Info`
+struct RawInfo {
+  version: i64,
+  timestamp: i64,
+  changeset: i64,
+  uid: i64,
+  user_sid: u64,
+  visible: bool,
+}
+
+impl RawInfo {
+  fn new() -> Self {
+    Self { version: -1, timestamp: 0, changeset: 0, uid: 0, user_sid: 0, visible: true }
+  }
+}
+
+// frame reader and block parser shared by the sync and async pbf decoders
+struct PbfState {
+  options: DecoderOptions,
+  stage: Stage,
+  // absolute offset of the frame being read
+  frame_offset: u64,
+  offset: u64,
+  started: bool,
+  data_type: Option<DatasetType>,
+  last_id: Option<u64>,
+  output: VecDeque<DecodeItem>,
+}
+
+impl PbfState {
+  fn new(options: DecoderOptions) -> Self {
+    Self {
+      options,
+      stage: Stage::Len(),
+      frame_offset: 0,
+      offset: 0,
+      started: false,
+      data_type: None,
+      last_id: None,
+      output: VecDeque::new(),
+    }
+  }
+  fn context(&self) -> ErrorContext {
+    ErrorContext {
+      offset: Some(self.frame_offset),
+      data_type: self.data_type.clone(),
+      last_id: self.last_id,
+    }
+  }
+  fn error(&self, info: &str) -> DecodeError {
+    DecodeError::InvalidPbf {
+      info: info.to_string(),
+      context: self.context(),
+      backtrace: ErrorTrace::capture(),
+    }
+  }
+  // error that leaves the decoder unable to find the next frame
+  fn fatal(&mut self, info: &str) -> DecodeError {
+    let e = self.error(info);
+    self.stage = Stage::Done();
+    e
+  }
+  // number of bytes to read for the next part of the frame, or 0 when decoding has stopped
+  fn wanted(&self) -> usize {
+    match &self.stage {
+      Stage::Len() => 4,
+      Stage::Header(n) => *n,
+      Stage::Blob(_,n) => *n,
+      Stage::Done() => 0,
+    }
+  }
+  // handle the bytes read for a part of the frame, queueing any error after the datasets decoded
+  // so far. `n` is less than `wanted` at the end of input. Unless the decoder is lenient, the
+  // first error ends decoding.
+  fn filled(&mut self, buf: &[u8]) {
+    if let Err(e) = self.read_part(buf) {
+      self.output.push_back(Err(e));
+      if !self.options.lenient {
+        self.stage = Stage::Done();
+      }
+    }
+  }
+  fn read_part(&mut self, buf: &[u8]) -> Result<(),DecodeError> {
+    if buf.len() < self.wanted() {
+      if buf.is_empty() && matches!(self.stage, Stage::Len()) {
+        self.stage = Stage::Done();
+        return Ok(());
+      }
+      return Err(self.fatal("unexpected end of input"));
+    }
+    if let Stage::Len() = self.stage {
+      self.frame_offset = self.offset;
+    }
+    self.offset += buf.len() as u64;
+    match std::mem::replace(&mut self.stage, Stage::Len()) {
+      Stage::Len() => {
+        let n = u32::from_be_bytes([buf[0],buf[1],buf[2],buf[3]]) as usize;
+        if n > MAX_HEADER_SIZE {
+          return Err(self.fatal("blob header larger than 64 KiB"));
+        }
+        self.stage = Stage::Header(n);
+      },
+      Stage::Header(_) => {
+        let mut kind = vec![];
+        let mut size = 0;
+        for field in Fields::new(buf) {
+          match field.map_err(|_| self.fatal("malformed blob header"))? {
+            (1,v) => kind = v.bytes().to_vec(),
+            (3,v) => size = v.int(),
+            _ => {},
+          }
+        }
+        if size < 0 || size as usize > MAX_BLOB_SIZE {
+          return Err(self.fatal("blob size out of range"));
+        }
+        self.stage = Stage::Blob(kind, size as usize);
+      },
+      Stage::Blob(kind,_) => {
+        if kind == b"OSMData" {
+          self.start("o5m2", None, None);
+        }
+        let start = self.output.len();
+        let result = self.blob(buf).and_then(|data| match kind.as_slice() {
+          b"OSMHeader" => self.header_block(&data),
+          b"OSMData" => self.primitive_block(&data),
+          _ => Ok(()),
+        });
+        self.data_type = None;
+        if let Err(e) = result {
+          // a block is emitted whole or not at all, and never ahead of the header
+          self.output.truncate(start);
+          self.start("o5m2", None, None);
+          return Err(e);
+        }
+      },
+      Stage::Done() => {},
+    }
+    Ok(())
+  }
+  // uncompressed contents of a blob
+  fn blob(&self, buf: &[u8]) -> Result<Vec<u8>,DecodeError> {
+    let mut raw_size = None;
+    for field in Fields::new(buf) {
+      match field.map_err(|_| self.error("malformed blob"))? {
+        (1,v) => return Ok(v.bytes().to_vec()),
+        (2,v) => raw_size = Some(v.int() as usize),
+        (3,v) => {
+          let mut data = Vec::with_capacity(raw_size.unwrap_or(0).min(MAX_BLOB_SIZE));
+          ZlibDecoder::new(v.bytes()).take(MAX_BLOB_SIZE as u64 + 1).read_to_end(&mut data)
+            .map_err(|e| self.error(&format!("zlib data: {}", e)))?;
+          if data.len() > MAX_BLOB_SIZE {
+            return Err(self.error("uncompressed blob larger than 32 MiB"));
+          }
+          return Ok(data);
+        },
+        (4,_) => return Err(self.error("lzma compressed blobs are not supported")),
+        (6,_) => return Err(self.error("lz4 compressed blobs are not supported")),
+        (7,_) => return Err(self.error("zstd compressed blobs are not supported")),
+        _ => {},
+      }
+    }
+    Err(self.error("blob without data"))
+  }
+  fn header_block(&mut self, buf: &[u8]) -> Result<(),DecodeError> {
+    let mut bbox = None;
+    let mut timestamp = None;
+    let mut change = false;
+    for field in Fields::new(buf) {
+      match field.map_err(|_| self.error("malformed header block"))? {
+        (1,v) => {
+          let mut b = BBox { x1: Coord(0), y1: Coord(0), x2: Coord(0), y2: Coord(0) };
+          for field in Fields::new(v.bytes()) {
+            let (n,v) = field.map_err(|_| self.error("malformed header bbox"))?;
+            // nanodegrees, rounded to the nearest unit like node coordinates
+            let x = Coord(v.sint().saturating_add(50).div_euclid(100) as i32);
+            match n {
+              1 => b.x1 = x,
+              2 => b.x2 = x,
+              3 => b.y2 = x,
+              4 => b.y1 = x,
+              _ => {},
+            }
+          }
+          bbox = Some(b);
+        },
+        (4,v) => match v.bytes() {
+          // history files are still snapshots, with old versions as invisible elements
+          b"OsmSchema-V0.6" | b"DenseNodes" | b"HistoricalInformation" => {},
+          feature => return Err(self.error(&format!(
+            "unsupported required feature {:?}", String::from_utf8_lossy(feature)
+          ))),
+        },
+        (5,v) => change = change || v.bytes() == CHANGE_FEATURE,
+        (32,v) => timestamp = Some(v.int()),
+        _ => {},
+      }
+    }
+    self.start(if change { "o5c2" } else { "o5m2" }, timestamp, bbox);
+    Ok(())
+  }
+  // queue the header, with the timestamp and bbox of the header block, if it is not queued yet
+  fn start(&mut self, format: &str, timestamp: Option<i64>, bbox: Option<BBox>) {
+    if self.started { return }
+    self.started = true;
+    self.output.push_back(Ok(Dataset::Header(Header { format: format.to_string() })));
+    if let Some(time) = timestamp {
+      self.output.push_back(Ok(Dataset::Timestamp(Timestamp { time })));
+    }
+    if let Some(bbox) = bbox {
+      self.output.push_back(Ok(Dataset::BBox(bbox)));
+    }
+  }
+  fn primitive_block(&mut self, buf: &[u8]) -> Result<(),DecodeError> {
+    let mut block = Block {
+      strings: vec![],
+      granularity: 100,
+      date_granularity: 1000,
+      lat_offset: 0,
+      lon_offset: 0,
+    };
+    let mut groups = vec![];
+    for field in Fields::new(buf) {
+      match field.map_err(|_| self.error("malformed primitive block"))? {
+        (1,v) => for field in Fields::new(v.bytes()) {
+          if let (1,v) = field.map_err(|_| self.error("malformed string table"))? {
+            block.strings.push(v.bytes());
+          }
+        },
+        (2,v) => groups.push(v.bytes()),
+        (17,v) => block.granularity = v.int(),
+        (18,v) => block.date_granularity = v.int(),
+        (19,v) => block.lat_offset = v.int(),
+        (20,v) => block.lon_offset = v.int(),
+        _ => {},
+      }
+    }
+    for group in groups {
+      for field in Fields::new(group) {
+        match field.map_err(|_| self.error("malformed primitive group"))? {
+          (1,v) => self.node(&block, v.bytes())?,
+          (2,v) => self.dense(&block, v.bytes())?,
+          (3,v) => self.way(&block, v.bytes())?,
+          (4,v) => self.relation(&block, v.bytes())?,
+          _ => {},
+        }
+      }
+    }
+    Ok(())
+  }
+  fn string(&self, block: &Block, index: u64) -> Result<String,DecodeError> {
+    let s = block.strings.get(index as usize).ok_or_else(|| DecodeError::StringUnavailable {
+      index: index as usize,
+      context: self.context(),
+      backtrace: ErrorTrace::capture(),
+    })?;
+    String::from_utf8(s.to_vec()).map_err(|e| DecodeError::StringEncodingError {
+      source: Box::new(e.into()),
+      context: self.context(),
+    })
+  }
+  fn tags(&self, block: &Block, keys: &[u64], vals: &[u64]) -> Result<Tags,DecodeError> {
+    if keys.len() != vals.len() {
+      return Err(self.error("tag keys and values differ in length"));
+    }
+    let mut tags = Tags::new();
+    for (k,v) in keys.iter().zip(vals) {
+      tags.insert(self.string(block, *k)?, self.string(block, *v)?);
+    }
+    Ok(tags)
+  }
+  fn info(&self, block: &Block, raw: &RawInfo) -> Result<Option<Info>,DecodeError> {
+    if raw.version <= 0 && raw.timestamp == 0 { return Ok(None) }
+    let mut info = Info::new();
+    if raw.version > 0 {
+      info.version = Some(raw.version as u64);
+    }
+    // like o5m, the author fields are only present along with a timestamp
+    if raw.timestamp != 0 {
+      let timestamp = block.timestamp(raw.timestamp)
+        .ok_or_else(|| self.error("timestamp out of range"))?;
+      info.timestamp = Some(timestamp);
+      info.changeset = Some(raw.changeset as u64);
+      info.uid = Some(raw.uid as u64);
+      info.user = Some(self.string(block, raw.user_sid)?);
+    }
+    Ok(Some(info))
+  }
+  fn raw_info(&self, buf: &[u8]) -> Result<RawInfo,DecodeError> {
+    let mut raw = RawInfo::new();
+    for field in Fields::new(buf) {
+      match field.map_err(|_| self.error("malformed info"))? {
+        (1,v) => raw.version = v.int(),
+        (2,v) => raw.timestamp = v.int(),
+        (3,v) => raw.changeset = v.int(),
+        (4,v) => raw.uid = v.int(),
+        (5,v) => raw.user_sid = v.int() as u64,
+        (6,v) => raw.visible = v.int() != 0,
+        _ => {},
+      }
+    }
+    Ok(raw)
+  }
+  fn location(&self, block: &Block, lon: i64, lat: i64) -> Result<NodeData,DecodeError> {
+    match (block.coord(block.lon_offset, lon),block.coord(block.lat_offset, lat)) {
+      (Some(longitude),Some(latitude)) => Ok(NodeData { longitude, latitude }),
+      _ => Err(self.error("coordinate out of range")),
+    }
+  }
+  // push an element, or a delete when the element is not visible
+  fn push(&mut self, visible: bool, element_type: ElementType, id: u64, info: Option<Info>,
+  data: Dataset) {
+    self.last_id = Some(id);
+    self.output.push_back(Ok(match visible {
+      true => data,
+      false => Dataset::Delete(Delete { id, element_type, info }),
+    }));
+  }
+  fn node(&mut self, block: &Block, buf: &[u8]) -> Result<(),DecodeError> {
+    self.data_type = Some(DatasetType::Node());
+    let (mut id, mut lat, mut lon) = (0,0,0);
+    let (mut keys, mut vals) = (vec![],vec![]);
+    let mut raw = RawInfo::new();
+    for field in Fields::new(buf) {
+      match field.map_err(|_| self.error("malformed node"))? {
+        (1,v) => id = v.sint(),
+        (2,v) => repeated(&v, &mut keys),
+        (3,v) => repeated(&v, &mut vals),
+        (4,v) => raw = self.raw_info(v.bytes())?,
+        (8,v) => lat = v.sint(),
+        (9,v) => lon = v.sint(),
+        _ => {},
+      }
+    }
+    let info = self.info(block, &raw)?;
+    let node = Node {
+      id: id as u64,
+      info: info.clone(),
+      data: Some(self.location(block, lon, lat)?),
+      tags: self.tags(block, &keys, &vals)?,
+    };
+    self.push(raw.visible, ElementType::Node(), id as u64, info, Dataset::Node(node));
+    Ok(())
+  }
+  fn dense(&mut self, block: &Block, buf: &[u8]) -> Result<(),DecodeError> {
+    self.data_type = Some(DatasetType::Node());
+    let (mut ids, mut lats, mut lons, mut keys_vals) = (vec![],vec![],vec![],vec![]);
+    let (mut versions, mut timestamps, mut changesets) = (vec![],vec![],vec![]);
+    let (mut uids, mut user_sids, mut visible) = (vec![],vec![],vec![]);
+    for field in Fields::new(buf) {
+      match field.map_err(|_| self.error("malformed dense nodes"))? {
+        (1,v) => repeated(&v, &mut ids),
+        (5,v) => for field in Fields::new(v.bytes()) {
+          match field.map_err(|_| self.error("malformed dense info"))? {
+            (1,v) => repeated(&v, &mut versions),
+            (2,v) => repeated(&v, &mut timestamps),
+            (3,v) => repeated(&v, &mut changesets),
+            (4,v) => repeated(&v, &mut uids),
+            (5,v) => repeated(&v, &mut user_sids),
+            (6,v) => repeated(&v, &mut visible),
+            _ => {},
+          }
+        },
+        (8,v) => repeated(&v, &mut lats),
+        (9,v) => repeated(&v, &mut lons),
+        (10,v) => repeated(&v, &mut keys_vals),
+        _ => {},
+      }
+    }
+    if lats.len() != ids.len() || lons.len() != ids.len() {
+      return Err(self.error("dense node ids and coordinates differ in length"));
+    }
+    let has_info = !versions.is_empty();
+    if has_info && [&versions,&timestamps,&changesets,&uids,&user_sids].iter()
+    .any(|column| column.len() != ids.len()) {
+      return Err(self.error("dense info and node ids differ in length"));
+    }
+    let (mut id, mut lat, mut lon) = (0i64,0i64,0i64);
+    let mut raw = RawInfo::new();
+    let mut kv = keys_vals.iter();
+    for i in 0..ids.len() {
+      id = id.wrapping_add(unzigzag(ids[i]));
+      lat = lat.wrapping_add(unzigzag(lats[i]));
+      lon = lon.wrapping_add(unzigzag(lons[i]));
+      let info = match has_info {
+        true => {
+          raw.version = versions[i] as i64;
+          raw.timestamp = raw.timestamp.wrapping_add(unzigzag(timestamps[i]));
+          raw.changeset = raw.changeset.wrapping_add(unzigzag(changesets[i]));
+          raw.uid = raw.uid.wrapping_add(unzigzag(uids[i]));
+          raw.user_sid = (raw.user_sid as i64).wrapping_add(unzigzag(user_sids[i])) as u64;
+          self.info(block, &raw)?
+        },
+        false => None,
+      };
+      // keys and values alternate, with a 0 after the tags of each node
+      let mut tags = Tags::new();
+      while let Some(k) = kv.next() {
+        if *k == 0 { break }
+        let v = kv.next().ok_or_else(|| self.error("dense node key without a value"))?;
+        tags.insert(self.string(block, *k)?, self.string(block, *v)?);
+      }
+      let node = Node {
+        id: id as u64,
+        info: info.clone(),
+        data: Some(self.location(block, lon, lat)?),
+        tags,
+      };
+      let visible = visible.get(i).map(|v| *v != 0).unwrap_or(true);
+      self.push(visible, ElementType::Node(), id as u64, info, Dataset::Node(node));
+    }
+    Ok(())
+  }
+  fn way(&mut self, block: &Block, buf: &[u8]) -> Result<(),DecodeError> {
+    self.data_type = Some(DatasetType::Way());
+    let mut id = 0;
+    let (mut keys, mut vals, mut refs) = (vec![],vec![],vec![]);
+    let mut raw = RawInfo::new();
+    for field in Fields::new(buf) {
+      match field.map_err(|_| self.error("malformed way"))? {
+        (1,v) => id = v.int() as u64,
+        (2,v) => repeated(&v, &mut keys),
+        (3,v) => repeated(&v, &mut vals),
+        (4,v) => raw = self.raw_info(v.bytes())?,
+        (8,v) => repeated(&v, &mut refs),
+        _ => {},
+      }
+    }
+    let info = self.info(block, &raw)?;
+    let way = Way {
+      id,
+      info: info.clone(),
+      data: Some(WayData { refs: undelta(&refs) }),
+      tags: self.tags(block, &keys, &vals)?,
+    };
+    self.push(raw.visible, ElementType::Way(), id, info, Dataset::Way(way));
+    Ok(())
+  }
+  fn relation(&mut self, block: &Block, buf: &[u8]) -> Result<(),DecodeError> {
+    self.data_type = Some(DatasetType::Relation());
+    let mut id = 0;
+    let (mut keys, mut vals) = (vec![],vec![]);
+    let (mut roles, mut memids, mut types) = (vec![],vec![],vec![]);
+    let mut raw = RawInfo::new();
+    for field in Fields::new(buf) {
+      match field.map_err(|_| self.error("malformed relation"))? {
+        (1,v) => id = v.int() as u64,
+        (2,v) => repeated(&v, &mut keys),
+        (3,v) => repeated(&v, &mut vals),
+        (4,v) => raw = self.raw_info(v.bytes())?,
+        (8,v) => repeated(&v, &mut roles),
+        (9,v) => repeated(&v, &mut memids),
+        (10,v) => repeated(&v, &mut types),
+        _ => {},
+      }
+    }
+    if roles.len() != memids.len() || types.len() != memids.len() {
+      return Err(self.error("relation member fields differ in length"));
+    }
+    let mut members = Vec::with_capacity(memids.len());
+    for ((id,role),t) in undelta(&memids).into_iter().zip(roles).zip(types) {
+      members.push(RelationMember {
+        id,
+        element_type: match t {
+          0 => ElementType::Node(),
+          1 => ElementType::Way(),
+          2 => ElementType::Relation(),
+          _ => return Err(self.error("unknown relation member type")),
+        },
+        role: self.string(block, role)?,
+      });
+    }
+    let info = self.info(block, &raw)?;
+    let relation = Relation {
+      id,
+      info: info.clone(),
+      data: Some(RelationData { members }),
+      tags: self.tags(block, &keys, &vals)?,
+    };
+    self.push(raw.visible, ElementType::Relation(), id, info, Dataset::Relation(relation));
+    Ok(())
+  }
+}
+
+// values of a repeated integer field, packed or not
+fn repeated(value: &Value, out: &mut Vec<u64>) {
+  match value {
+    Value::Bytes(buf) => out.extend(Packed::new(buf)),
+    Value::Varint(x) => out.push(*x),
+    Value::Fixed() => {},
+  }
+}
+
+// running sum of zigzag encoded deltas
+fn undelta(deltas: &[u64]) -> Vec<u64> {
+  let mut prev = 0i64;
+  deltas.iter().map(|x| {
+    prev = prev.wrapping_add(unzigzag(*x));
+    prev as u64
+  }).collect()
+}
+
+// zigzag encoded differences between consecutive values
+fn delta(values: &[i64]) -> impl Iterator<Item=u64>+'_ {
+  let mut prev = 0i64;
+  values.iter().map(move |x| {
+    let d = x.wrapping_sub(prev);
+    prev = *x;
+    zigzag(d)
+  })
+}
+
+// read until `buf` is full or the input ends
+fn fill<R: std::io::Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+  let mut n = 0;
+  while n < buf.len() {
+    match reader.read(&mut buf[n..]) {
+      Ok(0) => break,
+      Ok(m) => n += m,
+      Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(n)
+}
+
+/// Synchronous OSM PBF decoder that reads from any `std::io::Read` and iterates over the same
+/// fallible `Dataset` items as `Decoder`.
+pub struct PbfDecoder<R: std::io::Read> {
+  reader: R,
+  state: PbfState,
+  chunk: Vec<u8>,
+}
+
+impl<R: std::io::Read> PbfDecoder<R> {
+  pub fn new(reader: R) -> Self {
+    Self::with_options(reader, DecoderOptions::default())
+  }
+  /// Only `lenient` applies here: a malformed block is reported as an error in place of its
+  /// elements and decoding continues with the next block, where otherwise the first error ends
+  /// decoding.
+  pub fn with_options(reader: R, options: DecoderOptions) -> Self {
+    Self { reader, state: PbfState::new(options), chunk: vec![] }
+  }
+  pub fn next_item(&mut self) -> Result<Option<Dataset>,DecodeError> {
+    loop {
+      if let Some(item) = self.state.output.pop_front() {
+        return item.map(Some);
+      }
+      let wanted = self.state.wanted();
+      if wanted == 0 && matches!(self.state.stage, Stage::Done()) { return Ok(None) }
+      self.chunk.resize(wanted, 0);
+      let n = fill(&mut self.reader, &mut self.chunk).map_err(|e| {
+        self.state.stage = Stage::Done();
+        DecodeError::StreamReadError { source: Box::new(e.into()), context: self.state.context() }
+      })?;
+      self.state.filled(&self.chunk[..n]);
+    }
+  }
+}
+
+impl<R: std::io::Read> Iterator for PbfDecoder<R> {
+  type Item = DecodeItem;
+  fn next(&mut self) -> Option<Self::Item> {
+    match self.next_item() {
+      Ok(None) => None,
+      Ok(Some(x)) => Some(Ok(x)),
+      Err(e) => Some(Err(e)),
+    }
+  }
+}
+
+struct AsyncPbfDecoder {
+  reader: Box<dyn io::AsyncRead+Send+Unpin>,
+  state: PbfState,
+  chunk: Vec<u8>,
+}
+
+impl AsyncPbfDecoder {
+  async fn next_item(&mut self) -> Result<Option<Dataset>,DecodeError> {
+    loop {
+      if let Some(item) = self.state.output.pop_front() {
+        return item.map(Some);
+      }
+      let wanted = self.state.wanted();
+      if wanted == 0 && matches!(self.state.stage, Stage::Done()) { return Ok(None) }
+      self.chunk.resize(wanted, 0);
+      let mut n = 0;
+      while n < wanted {
+        match self.reader.read(&mut self.chunk[n..]).await {
+          Ok(0) => break,
+          Ok(m) => n += m,
+          Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+          Err(e) => {
+            self.state.stage = Stage::Done();
+            return Err(DecodeError::StreamReadError {
+              source: Box::new(e.into()),
+              context: self.state.context(),
+            });
+          },
+        }
+      }
+      self.state.filled(&self.chunk[..n]);
+    }
+  }
+}
+
+/// Transform OSM PBF from `reader` into a stream of fallible `Dataset` items, like `decode`
+/// does for o5m. The stream starts with an o5m2 header, or an o5c2 header for files that
+/// `PbfEncoder` wrote from o5c. Elements marked invisible, as in files that require
+/// `HistoricalInformation`, become `Dataset::Delete` items.
+/// As with `decode`, the first error ends the stream.
+pub fn decode_pbf(reader: Box<dyn io::AsyncRead+Send+Unpin>) -> DecodeStream {
+  decode_pbf_with_options(reader, DecoderOptions::default())
+}
+
+/// Like `decode_pbf`, with `options` to control how the input is decoded. Only `lenient`
+/// applies: a malformed block is reported as an error in place of its elements, and decoding
+/// continues with the next block.
+pub fn decode_pbf_with_options(
+  reader: Box<dyn io::AsyncRead+Send+Unpin>,
+  options: DecoderOptions,
+) -> DecodeStream {
+  let state = AsyncPbfDecoder { reader, state: PbfState::new(options), chunk: vec![] };
+  Box::new(crate::unfold::unfold(state, |mut qs| async move {
+    match qs.next_item().await {
+      Ok(None) => None,
+      Ok(Some(x)) => Some((Ok(x),qs)),
+      Err(e) => Some((Err(e),qs)),
+    }
+  }))
+}
+
+// columns of a dense node group
+#[derive(Default)]
+struct DenseColumns {
+  ids: Vec<i64>,
+  lats: Vec<i64>,
+  lons: Vec<i64>,
+  keys_vals: Vec<u64>,
+  versions: Vec<u64>,
+  timestamps: Vec<i64>,
+  changesets: Vec<i64>,
+  uids: Vec<i64>,
+  user_sids: Vec<i64>,
+  visible: Vec<bool>,
+  has_info: bool,
+  has_tags: bool,
+}
+
+/// Incremental OSM PBF encoder. Elements are collected into blocks of up to 8000 elements of
+/// one type, which are written as zlib compressed blobs; nodes are written as dense nodes.
+///
+/// The header block is written before the first element, with the bbox and timestamp seen until
+/// then. When the first dataset is an o5c header, the file requires `HistoricalInformation`,
+/// lists the optional feature `OsmChange` so that it reads back as o5c, and deletes are written
+/// as invisible elements. Deletes in any other stream are an `EncodeError::UnsupportedDataset`,
+/// and so are nodes, ways and relations without data, which would read back with made up
+/// coordinates or empty data.
+pub struct PbfEncoder {
+  begun: bool,
+  ended: bool,
+  change: bool,
+  bbox: Option<BBox>,
+  timestamp: Option<i64>,
+  element_type: Option<ElementType>,
+  count: usize,
+  strings: HashMap<String,u64>,
+  table: Vec<String>,
+  dense: DenseColumns,
+  group: Vec<u8>,
+}
+
+impl PbfEncoder {
+  pub fn new() -> Self {
+    Self {
+      begun: false,
+      ended: false,
+      change: false,
+      bbox: None,
+      timestamp: None,
+      element_type: None,
+      count: 0,
+      strings: HashMap::new(),
+      table: vec![String::new()],
+      dense: DenseColumns::default(),
+      group: vec![],
+    }
+  }
+  /// Write the header block if it has not been written yet.
+  pub fn begin(&mut self, buf: &mut Vec<u8>) {
+    if self.begun { return }
+    self.begun = true;
+    let mut header = vec![];
+    if let Some(bbox) = &self.bbox {
+      let mut b = vec![];
+      write_sint(&mut b, 1, bbox.x1.0 as i64 * 100);
+      write_sint(&mut b, 2, bbox.x2.0 as i64 * 100);
+      write_sint(&mut b, 3, bbox.y2.0 as i64 * 100);
+      write_sint(&mut b, 4, bbox.y1.0 as i64 * 100);
+      write_bytes(&mut header, 1, &b);
+    }
+    write_bytes(&mut header, 4, b"OsmSchema-V0.6");
+    write_bytes(&mut header, 4, b"DenseNodes");
+    if self.change {
+      write_bytes(&mut header, 4, b"HistoricalInformation");
+      write_bytes(&mut header, 5, CHANGE_FEATURE);
+    }
+    write_bytes(&mut header, 16, b"o5m-stream");
+    if let Some(time) = self.timestamp {
+      write_int(&mut header, 32, time);
+    }
+    blob(buf, "OSMHeader", &header);
+  }
+  /// Write the pending block. Nothing can be written afterwards.
+  pub fn end(&mut self, buf: &mut Vec<u8>) {
+    self.begin(buf);
+    if self.ended { return }
+    self.ended = true;
+    self.flush(buf);
+  }
+  pub fn write_dataset(&mut self, dataset: &Dataset, buf: &mut Vec<u8>) -> Result<(),EncodeError> {
+    if self.ended { return Ok(()) }
+    let element_type = match dataset {
+      Dataset::Header(header) => {
+        if !self.begun {
+          self.change = header.is_change();
+        }
+        return Ok(());
+      },
+      Dataset::Timestamp(t) => {
+        if !self.begun {
+          self.timestamp = Some(t.time);
+        }
+        return Ok(());
+      },
+      Dataset::BBox(bbox) => {
+        if !self.begun {
+          self.bbox = Some(bbox.clone());
+        }
+        return Ok(());
+      },
+      Dataset::Node(node) if node.data.is_none() => {
+        return Err(unsupported(format!("node {} without data", node.id)));
+      },
+      Dataset::Way(way) if way.data.is_none() => {
+        return Err(unsupported(format!("way {} without data", way.id)));
+      },
+      Dataset::Relation(relation) if relation.data.is_none() => {
+        return Err(unsupported(format!("relation {} without data", relation.id)));
+      },
+      Dataset::Node(_) => ElementType::Node(),
+      Dataset::Way(_) => ElementType::Way(),
+      Dataset::Relation(_) => ElementType::Relation(),
+      Dataset::Delete(delete) if self.change => delete.element_type.clone(),
+      Dataset::Delete(delete) => return Err(unsupported(format!(
+        "delete of {:?} {} in a pbf stream without an o5c header", delete.element_type, delete.id
+      ))),
+    };
+    self.begin(buf);
+    if self.element_type.as_ref() != Some(&element_type) || self.count >= BLOCK_SIZE
+    || self.group.len() >= MAX_BLOB_SIZE / 4 {
+      self.flush(buf);
+      self.element_type = Some(element_type);
+    }
+    self.count += 1;
+    match dataset {
+      Dataset::Node(node) => {
+        let data = node.data.as_ref();
+        self.dense_node(node.id, data, &node.info, &node.tags, true);
+      },
+      Dataset::Way(way) => {
+        let refs = way.data.as_ref().map(|d| d.refs.as_slice()).unwrap_or(&[]);
+        self.way(way.id, refs, &way.info, &way.tags, true);
+      },
+      Dataset::Relation(relation) => {
+        let members = relation.data.as_ref().map(|d| d.members.as_slice()).unwrap_or(&[]);
+        self.relation(relation.id, members, &relation.info, &relation.tags, true);
+      },
+      Dataset::Delete(delete) => match delete.element_type {
+        ElementType::Node() => self.dense_node(delete.id, None, &delete.info, &Tags::new(), false),
+        ElementType::Way() => self.way(delete.id, &[], &delete.info, &Tags::new(), false),
+        ElementType::Relation() => {
+          self.relation(delete.id, &[], &delete.info, &Tags::new(), false)
+        },
+      },
+      _ => {},
+    }
+    Ok(())
+  }
+  // index of `s` in the string table of the pending block. index 0 is reserved as the separator
+  // of dense node tags, so empty strings get an index of their own like any other string
+  fn sid(&mut self, s: &str) -> u64 {
+    if let Some(i) = self.strings.get(s) { return *i }
+    let i = self.table.len() as u64;
+    self.table.push(s.to_string());
+    self.strings.insert(s.to_string(), i);
+    i
+  }
+  // string ids of the tags sorted by key, so that the output does not depend on hash map order
+  fn tags(&mut self, tags: &Tags) -> Vec<(u64,u64)> {
+    let mut sorted = tags.iter().collect::<Vec<_>>();
+    sorted.sort();
+    sorted.into_iter().map(|(k,v)| (self.sid(k),self.sid(v))).collect()
+  }
+  fn dense_node(&mut self, id: u64, data: Option<&NodeData>, info: &Option<Info>, tags: &Tags,
+  visible: bool) {
+    let tags = self.tags(tags);
+    let user = info.as_ref().and_then(|info| info.user.as_deref());
+    let user_sid = user.map(|user| self.sid(user) as i64).unwrap_or(0);
+    let d = &mut self.dense;
+    d.ids.push(id as i64);
+    d.lats.push(data.map(|data| data.latitude.0 as i64).unwrap_or(0));
+    d.lons.push(data.map(|data| data.longitude.0 as i64).unwrap_or(0));
+    for (k,v) in &tags {
+      d.keys_vals.push(*k);
+      d.keys_vals.push(*v);
+    }
+    d.keys_vals.push(0);
+    d.has_tags = d.has_tags || !tags.is_empty();
+    d.has_info = d.has_info || info.is_some();
+    let info = info.clone().unwrap_or_default();
+    d.versions.push(info.version.unwrap_or(0));
+    d.timestamps.push(info.timestamp.unwrap_or(0));
+    d.changesets.push(info.changeset.unwrap_or(0) as i64);
+    d.uids.push(info.uid.unwrap_or(0) as i64);
+    d.user_sids.push(user_sid);
+    d.visible.push(visible);
+  }
+  fn info(&mut self, info: &Option<Info>, visible: bool) -> Option<Vec<u8>> {
+    if info.is_none() && visible { return None }
+    let mut buf = vec![];
+    if let Some(info) = info {
+      if let Some(version) = info.version {
+        write_int(&mut buf, 1, version as i64);
+      }
+      if let Some(timestamp) = info.timestamp {
+        write_int(&mut buf, 2, timestamp);
+      }
+      if let Some(changeset) = info.changeset {
+        write_int(&mut buf, 3, changeset as i64);
+      }
+      if let Some(uid) = info.uid {
+        write_int(&mut buf, 4, uid as i64);
+      }
+      if let Some(user) = &info.user {
+        let sid = self.sid(user);
+        write_int(&mut buf, 5, sid as i64);
+      }
+    }
+    if !visible {
+      write_int(&mut buf, 6, 0);
+    }
+    Some(buf)
+  }
+  // keys, values and info fields shared by ways and relations
+  fn element(&mut self, message: &mut Vec<u8>, id: u64, info: &Option<Info>, tags: &Tags,
+  visible: bool) {
+    let tags = self.tags(tags);
+    write_int(message, 1, id as i64);
+    write_packed(message, 2, tags.iter().map(|(k,_)| *k));
+    write_packed(message, 3, tags.iter().map(|(_,v)| *v));
+    if let Some(info) = self.info(info, visible) {
+      write_bytes(message, 4, &info);
+    }
+  }
+  fn way(&mut self, id: u64, refs: &[u64], info: &Option<Info>, tags: &Tags, visible: bool) {
+    let mut message = vec![];
+    self.element(&mut message, id, info, tags, visible);
+    let refs = refs.iter().map(|r| *r as i64).collect::<Vec<_>>();
+    write_packed(&mut message, 8, delta(&refs));
+    write_bytes(&mut self.group, 3, &message);
+  }
+  fn relation(&mut self, id: u64, members: &[RelationMember], info: &Option<Info>, tags: &Tags,
+  visible: bool) {
+    let mut message = vec![];
+    self.element(&mut message, id, info, tags, visible);
+    let roles = members.iter().map(|m| self.sid(&m.role)).collect::<Vec<_>>();
+    write_packed(&mut message, 8, roles.into_iter());
+    let memids = members.iter().map(|m| m.id as i64).collect::<Vec<_>>();
+    write_packed(&mut message, 9, delta(&memids));
+    write_packed(&mut message, 10, members.iter().map(|m| match m.element_type {
+      ElementType::Node() => 0,
+      ElementType::Way() => 1,
+      ElementType::Relation() => 2,
+    }));
+    write_bytes(&mut self.group, 4, &message);
+  }
+  // write the pending elements as one block
+  fn flush(&mut self, buf: &mut Vec<u8>) {
+    if self.count == 0 { return }
+    let mut group = std::mem::take(&mut self.group);
+    if self.element_type == Some(ElementType::Node()) {
+      let d = std::mem::take(&mut self.dense);
+      let mut dense = vec![];
+      write_packed(&mut dense, 1, delta(&d.ids));
+      if d.has_info || d.visible.iter().any(|v| !v) {
+        let mut info = vec![];
+        write_packed(&mut info, 1, d.versions.into_iter());
+        write_packed(&mut info, 2, delta(&d.timestamps));
+        write_packed(&mut info, 3, delta(&d.changesets));
+        write_packed(&mut info, 4, delta(&d.uids));
+        write_packed(&mut info, 5, delta(&d.user_sids));
+        if d.visible.iter().any(|v| !v) {
+          write_packed(&mut info, 6, d.visible.iter().map(|v| *v as u64));
+        }
+        write_bytes(&mut dense, 5, &info);
+      }
+      write_packed(&mut dense, 8, delta(&d.lats));
+      write_packed(&mut dense, 9, delta(&d.lons));
+      if d.has_tags {
+        write_packed(&mut dense, 10, d.keys_vals.into_iter());
+      }
+      write_bytes(&mut group, 2, &dense);
+    }
+    // the string at index 0 is empty and only used to separate the tags of dense nodes
+    let mut strings = vec![];
+    for s in &self.table {
+      write_bytes(&mut strings, 1, s.as_bytes());
+    }
+    self.table.truncate(1);
+    let mut block = vec![];
+    write_bytes(&mut block, 1, &strings);
+    write_bytes(&mut block, 2, &group);
+    blob(buf, "OSMData", &block);
+    self.strings.clear();
+    self.count = 0;
+  }
+}
+
+impl Default for PbfEncoder {
+  fn default() -> Self { Self::new() }
+}
+
+// append a frame with a zlib compressed blob of `data`
+fn unsupported(info: String) -> EncodeError {
+  EncodeError::UnsupportedDataset { info, backtrace: ErrorTrace::capture() }
+}
+
+fn blob(buf: &mut Vec<u8>, kind: &str, data: &[u8]) {
+  let mut z = ZlibEncoder::new(vec![], Compression::default());
+  // writing into a vec cannot fail
+  z.write_all(data).unwrap();
+  let compressed = z.finish().unwrap();
+  let mut blob = vec![];
+  write_int(&mut blob, 2, data.len() as i64);
+  write_bytes(&mut blob, 3, &compressed);
+  let mut header = vec![];
+  write_bytes(&mut header, 1, kind.as_bytes());
+  write_int(&mut header, 3, blob.len() as i64);
+  buf.extend_from_slice(&(header.len() as u32).to_be_bytes());
+  buf.extend_from_slice(&header);
+  buf.extend_from_slice(&blob);
+}
+
+/// Write the `Dataset` items from `stream` to `writer` as OSM PBF.
+pub async fn encode_pbf(
+  mut stream: Box<dyn Stream<Item=Dataset>+Send+Unpin>,
+  mut writer: Box<dyn io::AsyncWrite+Send+Unpin>,
+) -> Result<(),EncodeError> {
+  let mut encoder = PbfEncoder::new();
+  let mut buf = vec![];
+  while let Some(dataset) = stream.next().await {
+    encoder.write_dataset(&dataset, &mut buf)?;
+    if !buf.is_empty() {
+      writer.write_all(&buf).await
+        .map_err(|e| EncodeError::StreamWriteError { source: Box::new(e.into()) })?;
+      buf.clear();
+    }
+  }
+  encoder.end(&mut buf);
+  writer.write_all(&buf).await
+    .map_err(|e| EncodeError::StreamWriteError { source: Box::new(e.into()) })?;
+  writer.flush().await
+    .map_err(|e| EncodeError::StreamWriteError { source: Box::new(e.into()) })?;
+  Ok(())
+}
@@ -0,0 +1,146 @@
+// minimal protocol buffers wire format reader and writer for the pbf messages
+
+pub enum Value<'a> {
+  Varint(u64),
+  Bytes(&'a [u8]),
+  Fixed(),
+}
+
+impl<'a> Value<'a> {
+  pub fn int(&self) -> i64 {
+    match self {
+      Self::Varint(x) => *x as i64,
+      _ => 0,
+    }
+  }
+  pub fn sint(&self) -> i64 {
+    match self {
+      Self::Varint(x) => unzigzag(*x),
+      _ => 0,
+    }
+  }
+  pub fn bytes(&self) -> &'a [u8] {
+    match self {
+      Self::Bytes(b) => b,
+      _ => &[],
+    }
+  }
+}
+
+pub fn unzigzag(x: u64) -> i64 {
+  ((x >> 1) as i64) ^ -((x & 1) as i64)
+}
+
+pub fn zigzag(x: i64) -> u64 {
+  ((x << 1) ^ (x >> 63)) as u64
+}
+
+fn varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+  let mut value = 0u64;
+  for shift in (0..64).step_by(7) {
+    let b = *buf.get(*pos)?;
+    *pos += 1;
+    value |= ((b & 0x7f) as u64) << shift;
+    if b < 0x80 { return Some(value) }
+  }
+  None
+}
+
+/// Fields of an encoded message in order. Yields `Err(())` for malformed input.
+pub struct Fields<'a> {
+  buf: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Fields<'a> {
+  pub fn new(buf: &'a [u8]) -> Self {
+    Self { buf, pos: 0 }
+  }
+}
+
+impl<'a> Iterator for Fields<'a> {
+  type Item = Result<(u64,Value<'a>),()>;
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.pos >= self.buf.len() { return None }
+    let key = match varint(self.buf, &mut self.pos) {
+      Some(key) => key,
+      None => return Some(Err(())),
+    };
+    let value = match key & 7 {
+      0 => varint(self.buf, &mut self.pos).map(Value::Varint),
+      1 | 5 => {
+        self.pos += if key & 7 == 1 { 8 } else { 4 };
+        if self.pos <= self.buf.len() { Some(Value::Fixed()) } else { None }
+      },
+      2 => varint(self.buf, &mut self.pos).and_then(|len| {
+        let end = self.pos.checked_add(len as usize).filter(|end| *end <= self.buf.len())?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Some(Value::Bytes(bytes))
+      }),
+      _ => None,
+    };
+    match value {
+      Some(value) => Some(Ok((key >> 3, value))),
+      None => {
+        self.pos = self.buf.len();
+        Some(Err(()))
+      },
+    }
+  }
+}
+
+/// Varints of a packed repeated field.
+pub struct Packed<'a> {
+  buf: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Packed<'a> {
+  pub fn new(buf: &'a [u8]) -> Self {
+    Self { buf, pos: 0 }
+  }
+}
+
+impl<'a> Iterator for Packed<'a> {
+  type Item = u64;
+  fn next(&mut self) -> Option<u64> {
+    let x = varint(self.buf, &mut self.pos);
+    if x.is_none() { self.pos = self.buf.len() }
+    x
+  }
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, mut x: u64) {
+  while x >= 0x80 {
+    buf.push((x as u8) | 0x80);
+    x >>= 7;
+  }
+  buf.push(x as u8);
+}
+
+pub fn write_int(buf: &mut Vec<u8>, field: u64, x: i64) {
+  write_varint(buf, field << 3);
+  write_varint(buf, x as u64);
+}
+
+pub fn write_sint(buf: &mut Vec<u8>, field: u64, x: i64) {
+  write_varint(buf, field << 3);
+  write_varint(buf, zigzag(x));
+}
+
+pub fn write_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+  write_varint(buf, (field << 3) | 2);
+  write_varint(buf, bytes.len() as u64);
+  buf.extend_from_slice(bytes);
+}
+
+pub fn write_packed(buf: &mut Vec<u8>, field: u64, values: impl Iterator<Item=u64>) {
+  let mut packed = vec![];
+  for x in values {
+    write_varint(&mut packed, x);
+  }
+  if !packed.is_empty() {
+    write_bytes(buf, field, &packed);
+  }
+}
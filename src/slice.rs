@@ -0,0 +1,121 @@
+use crate::{
+  Dataset,DatasetRef,DecodeError,DecodeItem,DecoderOptions,DecoderState,Frame,Header,
+};
+
+/// Synchronous decoder for o5m that is already in memory, such as a `&[u8]` from a
+/// memory-mapped file.
+///
+/// Frames are parsed in place: nothing is copied out of `data` and there is no read buffer.
+/// Each frame that lies whole in `data` is split off in one step and handed to the dataset
+/// parser, so the work per element on top of parsing it is a type byte and a length. It takes
+/// the same options and returns the same items and errors as `Decoder`.
+/// `cargo run --release --example slice` compares the two.
+pub struct SliceDecoder<'a> {
+  data: &'a [u8],
+  state: DecoderState,
+}
+
+impl<'a> SliceDecoder<'a> {
+  pub fn new(data: &'a [u8]) -> Self {
+    Self::with_options(data, DecoderOptions::default())
+  }
+  pub fn with_options(data: &'a [u8], options: DecoderOptions) -> Self {
+    Self { data, state: slice_state(data, options) }
+  }
+  /// Header dataset at the start of the input, or `None` if the input does not begin with one.
+  pub fn header(&self) -> Result<Option<Header>,DecodeError> {
+    let options = self.state.options.clone();
+    Ok(match SliceDecoder::with_options(self.data, options).next_ref()? {
+      Some(DatasetRef::Header(header)) => Some(Header { format: header.format.to_string() }),
+      _ => None,
+    })
+  }
+  pub fn next_item(&mut self) -> Result<Option<Dataset>,DecodeError> {
+    Ok(self.next_ref()?.map(|data| data.to_dataset()))
+  }
+  /// Decode the next dataset without allocating. The returned view borrows from the decoder
+  /// and the input and is valid until the next call.
+  pub fn next_ref(&mut self) -> Result<Option<DatasetRef<'_>>,DecodeError> {
+    if !next_in(&mut self.state, self.data)? {
+      return Ok(None);
+    }
+    Ok(self.state.view_in(self.data))
+  }
+  /// Byte offset in the input of the most recently started dataset.
+  pub fn offset(&self) -> u64 {
+    self.state.frame_offset
+  }
+}
+
+impl<'a> Iterator for SliceDecoder<'a> {
+  type Item = DecodeItem;
+  fn next(&mut self) -> Option<Self::Item> {
+    match self.next_item() {
+      Ok(None) => None,
+      Ok(Some(x)) => Some(Ok(x)),
+      Err(e) => Some(Err(e)),
+    }
+  }
+}
+
+/// Frame reader like `FrameDecoder` for o5m that is already in memory. Payloads borrow from
+/// `data` itself, so frames stay valid after the next call.
+pub struct SliceFrameDecoder<'a> {
+  data: &'a [u8],
+  state: DecoderState,
+}
+
+impl<'a> SliceFrameDecoder<'a> {
+  pub fn new(data: &'a [u8]) -> Self {
+    Self::with_options(data, DecoderOptions::default())
+  }
+  /// Only `lenient` applies here, as for `FrameDecoder::with_options`.
+  pub fn with_options(data: &'a [u8], options: DecoderOptions) -> Self {
+    let mut state = slice_state(data, options);
+    state.frames = true;
+    Self { data, state }
+  }
+  #[inline]
+  pub fn next_frame(&mut self) -> Result<Option<Frame<'a>>,DecodeError> {
+    if !next_in(&mut self.state, self.data)? {
+      return Ok(None);
+    }
+    let data = match self.state.payload {
+      Some((start,end)) => &self.data[start..end],
+      None => &[],
+    };
+    Ok(Some(Frame {
+      data_type: self.state.data_type.clone(),
+      type_byte: self.state.type_byte,
+      offset: self.state.frame_offset,
+      end: self.state.buffer_offset + (self.state.index as u64),
+      data,
+    }))
+  }
+}
+
+// state that reads all of `data` as one buffer
+fn slice_state(data: &[u8], options: DecoderOptions) -> DecoderState {
+  let mut state = DecoderState::new(options);
+  state.buffer = vec![];
+  state.filled(data.len());
+  state
+}
+
+// next dataset, or next frame in `frames` mode, from `data`. false at the end of the input
+#[inline]
+fn next_in(state: &mut DecoderState, data: &[u8]) -> Result<bool,DecodeError> {
+  loop {
+    match state.next_whole(data) {
+      Some(Ok(true)) => return Ok(true),
+      Some(Ok(false)) => continue,
+      Some(Err(e)) => return Err(e),
+      None => break,
+    }
+  }
+  if state.next_in(data)? { return Ok(true) }
+  match state.finish() {
+    Some(e) => Err(e),
+    None => Ok(false),
+  }
+}
@@ -1,5 +1,4 @@
 // vendored version of futures::stream::unfold
-// modified to use async_std
 
 // The original source file from which this is derived is
 // Copyright (c) 2016 Alex Crichton
@@ -12,9 +11,9 @@
 use core::fmt;
 use core::pin::Pin;
 use futures::ready;
-use async_std::future::Future;
-use async_std::stream::{Stream};
-use async_std::task::{Context, Poll};
+use core::future::Future;
+use core::task::{Context, Poll};
+use futures::stream::Stream;
 
 pub fn unfold<T, F, Fut, It>(init: T, f: F) -> Unfold<T, F, Fut>
   where F: FnMut(T) -> Fut,
@@ -0,0 +1,490 @@
+use futures::{prelude::*,io};
+use std::collections::VecDeque;
+use crate::{
+  BBox,Dataset,DatasetType,DecodeError,DecodeItem,DecodeStream,Delete,ElementType,ErrorContext,
+  ErrorTrace,Header,Info,Node,NodeData,Relation,RelationData,RelationMember,Tags,Timestamp,Way,
+  WayData,
+};
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+enum TagKind { Start(), End(), Empty() }
+
+// one start, end or empty-element tag with its attributes unescaped
+struct Tag {
+  kind: TagKind,
+  name: String,
+  attrs: Vec<(String,String)>,
+}
+
+impl Tag {
+  fn get(&self, key: &str) -> Option<&str> {
+    self.attrs.iter().find(|(k,_)| k == key).map(|(_,v)| v.as_str())
+  }
+}
+
+// incremental xml scanner and osm element builder shared by the sync and async xml decoders
+struct XmlState {
+  buffer: Vec<u8>,
+  index: usize,
+  // absolute offset of buffer[0] in the input
+  buffer_offset: u64,
+  tag_offset: u64,
+  eof: bool,
+  deleting: bool,
+  element: Option<(Dataset,bool)>,
+  last_id: Option<u64>,
+  output: VecDeque<Dataset>,
+  // set by the first error, which ends the input
+  failed: bool,
+}
+
+impl XmlState {
+  fn new() -> Self {
+    Self {
+      buffer: vec![],
+      index: 0,
+      buffer_offset: 0,
+      tag_offset: 0,
+      eof: false,
+      deleting: false,
+      element: None,
+      last_id: None,
+      output: VecDeque::new(),
+      failed: false,
+    }
+  }
+  fn context(&self) -> ErrorContext {
+    ErrorContext {
+      offset: Some(self.tag_offset),
+      data_type: self.element.as_ref().map(|(data,_)| match data {
+        Dataset::Node(_) => DatasetType::Node(),
+        Dataset::Way(_) => DatasetType::Way(),
+        _ => DatasetType::Relation(),
+      }),
+      last_id: self.last_id,
+    }
+  }
+  fn error(&self, info: &str) -> DecodeError {
+    DecodeError::InvalidXml {
+      info: info.to_string(),
+      context: self.context(),
+      backtrace: ErrorTrace::capture(),
+    }
+  }
+  // error for input that ends in the middle of markup, which is dropped
+  fn truncated(&mut self, info: &str) -> DecodeError {
+    let e = self.error(info);
+    self.index = self.buffer.len();
+    e
+  }
+  // drop the consumed part of the buffer to make room for more input
+  fn compact(&mut self) {
+    self.buffer.drain(..self.index);
+    self.buffer_offset += self.index as u64;
+    self.index = 0;
+  }
+  fn filled(&mut self, chunk: &[u8]) {
+    if chunk.is_empty() {
+      self.eof = true;
+    }
+    self.buffer.extend_from_slice(chunk);
+  }
+  // next dataset from the buffered input, or Ok(None) when more input is needed
+  // or the input has ended
+  fn next_buffered(&mut self) -> Result<Option<Dataset>,DecodeError> {
+    loop {
+      if let Some(data) = self.output.pop_front() {
+        return Ok(Some(data));
+      }
+      let tag = match self.next_tag()? {
+        Some(tag) => tag,
+        None => {
+          if self.eof && self.element.is_some() {
+            let e = self.error("unexpected end of input inside an element");
+            self.element = None;
+            return Err(e);
+          }
+          return Ok(None);
+        },
+      };
+      self.handle(tag)?;
+    }
+  }
+  // scan ahead to the next tag, skipping text, comments, declarations and cdata
+  fn next_tag(&mut self) -> Result<Option<Tag>,DecodeError> {
+    loop {
+      let rest = &self.buffer[self.index..];
+      let start = match rest.iter().position(|b| *b == b'<') {
+        Some(i) => i,
+        None => {
+          self.index = self.buffer.len();
+          return Ok(None);
+        },
+      };
+      self.index += start;
+      self.tag_offset = self.buffer_offset + (self.index as u64);
+      let rest = &self.buffer[self.index..];
+      if rest.len() < 9 && !self.eof {
+        return Ok(None);
+      }
+      let end = if rest.starts_with(b"<!--") {
+        find(rest, b"-->").map(|i| i+3)
+      } else if rest.starts_with(b"<![CDATA[") {
+        find(rest, b"]]>").map(|i| i+3)
+      } else if rest.starts_with(b"<?") {
+        find(rest, b"?>").map(|i| i+2)
+      } else if rest.starts_with(b"<!") {
+        find(rest, b">").map(|i| i+1)
+      } else {
+        let end = match tag_end(rest) {
+          Some(end) => end,
+          None if self.eof => return Err(self.truncated("unterminated tag")),
+          None => return Ok(None),
+        };
+        let tag = parse_tag(&rest[..end]).map_err(|info| self.error(info))?;
+        self.index += end;
+        return Ok(Some(tag));
+      };
+      match end {
+        Some(end) => self.index += end,
+        None if self.eof => return Err(self.truncated("unterminated markup")),
+        None => return Ok(None),
+      }
+    }
+  }
+  fn handle(&mut self, tag: Tag) -> Result<(),DecodeError> {
+    match (tag.kind, tag.name.as_str()) {
+      (TagKind::End(), "node") | (TagKind::End(), "way") | (TagKind::End(), "relation") => {
+        self.finish_element();
+      },
+      (TagKind::End(), "delete") => self.deleting = false,
+      (TagKind::End(), _) => {},
+      (_, "osm") | (_, "osmChange") => {
+        let format = if tag.name == "osmChange" { "o5c2" } else { "o5m2" };
+        self.output.push_back(Dataset::Header(Header { format: format.to_string() }));
+        if let Some(t) = tag.get("timestamp") {
+          let time = parse_timestamp(t).ok_or_else(|| self.error("invalid timestamp"))?;
+          self.output.push_back(Dataset::Timestamp(Timestamp { time }));
+        }
+      },
+      (_, "delete") => self.deleting = tag.kind == TagKind::Start(),
+      (_, "bounds") => {
+        let get = |key| tag.get(key).and_then(|x| x.parse().ok())
+          .ok_or_else(|| self.error("invalid bounds"));
+        let bbox = BBox {
+          x1: get("minlon")?,
+          y1: get("minlat")?,
+          x2: get("maxlon")?,
+          y2: get("maxlat")?,
+        };
+        self.output.push_back(Dataset::BBox(bbox));
+      },
+      (_, "node") | (_, "way") | (_, "relation") => {
+        if self.element.is_some() {
+          return Err(self.error("nested element"));
+        }
+        let id = tag.get("id").and_then(|x| x.parse().ok())
+          .ok_or_else(|| self.error("invalid id"))?;
+        let info = self.info(&tag)?;
+        let tags = Tags::new();
+        let data = match tag.name.as_str() {
+          "node" => {
+            let data = match (tag.get("lon"),tag.get("lat")) {
+              (Some(lon),Some(lat)) => Some(NodeData {
+                longitude: lon.parse().map_err(|_| self.error("invalid lon"))?,
+                latitude: lat.parse().map_err(|_| self.error("invalid lat"))?,
+              }),
+              _ => None,
+            };
+            Dataset::Node(Node { id, info, data, tags })
+          },
+          "way" => Dataset::Way(Way { id, info, data: Some(WayData { refs: vec![] }), tags }),
+          _ => {
+            let data = Some(RelationData { members: vec![] });
+            Dataset::Relation(Relation { id, info, data, tags })
+          },
+        };
+        let deleted = self.deleting || tag.get("visible") == Some("false");
+        self.element = Some((data,deleted));
+        if tag.kind == TagKind::Empty() {
+          self.finish_element();
+        }
+      },
+      (_, "tag") => {
+        let (k,v) = match (tag.get("k"),tag.get("v")) {
+          (Some(k),Some(v)) => (k.to_string(),v.to_string()),
+          _ => return Err(self.error("tag without k or v")),
+        };
+        match &mut self.element {
+          Some((Dataset::Node(node),_)) => { node.tags.insert(k,v); },
+          Some((Dataset::Way(way),_)) => { way.tags.insert(k,v); },
+          Some((Dataset::Relation(relation),_)) => { relation.tags.insert(k,v); },
+          _ => {},
+        }
+      },
+      (_, "nd") => {
+        let r = tag.get("ref").and_then(|x| x.parse().ok())
+          .ok_or_else(|| self.error("invalid nd ref"))?;
+        if let Some((Dataset::Way(way),_)) = &mut self.element {
+          way.data.get_or_insert_with(|| WayData { refs: vec![] }).refs.push(r);
+        }
+      },
+      (_, "member") => {
+        let id = tag.get("ref").and_then(|x| x.parse().ok())
+          .ok_or_else(|| self.error("invalid member ref"))?;
+        let element_type = match tag.get("type") {
+          Some("node") => ElementType::Node(),
+          Some("way") => ElementType::Way(),
+          Some("relation") => ElementType::Relation(),
+          _ => return Err(self.error("invalid member type")),
+        };
+        let role = tag.get("role").unwrap_or("").to_string();
+        if let Some((Dataset::Relation(relation),_)) = &mut self.element {
+          relation.data.get_or_insert_with(|| RelationData { members: vec![] })
+            .members.push(RelationMember { id, element_type, role });
+        }
+      },
+      _ => {},
+    }
+    Ok(())
+  }
+  fn info(&self, tag: &Tag) -> Result<Option<Info>,DecodeError> {
+    let number = |key| match tag.get(key) {
+      Some(x) => x.parse().map(Some).map_err(|_| self.error(&format!("invalid {}", key))),
+      None => Ok(None),
+    };
+    let info = Info {
+      version: number("version")?,
+      timestamp: match tag.get("timestamp") {
+        Some(t) => Some(parse_timestamp(t).ok_or_else(|| self.error("invalid timestamp"))?),
+        None => None,
+      },
+      changeset: number("changeset")?,
+      uid: number("uid")?,
+      user: tag.get("user").map(|user| user.to_string()),
+    };
+    let empty = info.version.is_none() && info.timestamp.is_none() && info.changeset.is_none()
+      && info.uid.is_none() && info.user.is_none();
+    Ok(if empty { None } else { Some(info) })
+  }
+  fn finish_element(&mut self) {
+    let (data,deleted) = match self.element.take() {
+      Some(x) => x,
+      None => return,
+    };
+    self.last_id = data.get_id();
+    self.output.push_back(match deleted {
+      true => Dataset::Delete(Delete {
+        id: data.get_id().unwrap_or(0),
+        element_type: data.get_type().unwrap_or(ElementType::Node()),
+        info: data.get_info(),
+      }),
+      false => data,
+    });
+  }
+}
+
+fn find(buf: &[u8], pattern: &[u8]) -> Option<usize> {
+  buf.windows(pattern.len()).position(|w| w == pattern)
+}
+
+// length of the tag at the start of `buf` up to and including its closing '>',
+// skipping over quoted attribute values
+fn tag_end(buf: &[u8]) -> Option<usize> {
+  let mut quote = None;
+  for (i,b) in buf.iter().enumerate() {
+    match (quote,*b) {
+      (None,b'>') => return Some(i+1),
+      (None,b'"') | (None,b'\'') => quote = Some(*b),
+      (Some(q),b) if q == b => quote = None,
+      _ => {},
+    }
+  }
+  None
+}
+
+fn parse_tag(buf: &[u8]) -> Result<Tag,&'static str> {
+  let s = std::str::from_utf8(buf).map_err(|_| "invalid utf-8 in tag")?;
+  let (kind,body) = if let Some(body) = s.strip_prefix("</") {
+    (TagKind::End(), body.trim_end_matches('>'))
+  } else if let Some(body) = s.strip_suffix("/>") {
+    (TagKind::Empty(), &body[1..])
+  } else {
+    (TagKind::Start(), &s[1..s.len()-1])
+  };
+  let body = body.trim();
+  let name_end = body.find(|c: char| c.is_whitespace()).unwrap_or(body.len());
+  let name = body[..name_end].to_string();
+  if name.is_empty() { return Err("missing tag name") }
+  let mut attrs = vec![];
+  let mut rest = body[name_end..].trim_start();
+  while !rest.is_empty() {
+    let eq = rest.find('=').ok_or("attribute without value")?;
+    let key = rest[..eq].trim().to_string();
+    rest = rest[eq+1..].trim_start();
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')
+      .ok_or("unquoted attribute value")?;
+    let end = rest[1..].find(quote).ok_or("unterminated attribute value")? + 1;
+    attrs.push((key,unescape(&rest[1..end])?));
+    rest = rest[end+1..].trim_start();
+  }
+  Ok(Tag { kind, name, attrs })
+}
+
+fn unescape(s: &str) -> Result<String,&'static str> {
+  if !s.contains('&') { return Ok(s.to_string()) }
+  let mut out = String::with_capacity(s.len());
+  let mut rest = s;
+  while let Some(i) = rest.find('&') {
+    out.push_str(&rest[..i]);
+    let end = rest[i..].find(';').ok_or("unterminated entity")? + i;
+    let entity = &rest[i+1..end];
+    let c = match entity {
+      "amp" => '&',
+      "lt" => '<',
+      "gt" => '>',
+      "quot" => '"',
+      "apos" => '\'',
+      _ => {
+        let code = match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+          Some(hex) => u32::from_str_radix(hex, 16).ok(),
+          None => entity.strip_prefix('#').and_then(|d| d.parse().ok()),
+        };
+        code.and_then(std::char::from_u32).ok_or("unknown entity")?
+      },
+    };
+    out.push(c);
+    rest = &rest[end+1..];
+  }
+  out.push_str(rest);
+  Ok(out)
+}
+
+// ISO 8601 UTC timestamp such as 2020-09-13T12:26:40Z to seconds since the unix epoch
+fn parse_timestamp(s: &str) -> Option<i64> {
+  let b = s.as_bytes();
+  if b.len() != 20 || b[4] != b'-' || b[7] != b'-' || b[10] != b'T' || b[13] != b':'
+  || b[16] != b':' || b[19] != b'Z' {
+    return None;
+  }
+  let n = |i: usize, len: usize| -> Option<i64> {
+    let digits = &s[i..i+len];
+    if !digits.bytes().all(|b| b.is_ascii_digit()) { return None }
+    digits.parse().ok()
+  };
+  let (year,month,day) = (n(0,4)?,n(5,2)?,n(8,2)?);
+  let (hour,minute,second) = (n(11,2)?,n(14,2)?,n(17,2)?);
+  // civil date to days, from Howard Hinnant's date algorithms
+  let y = if month <= 2 { year - 1 } else { year };
+  let era = y.div_euclid(400);
+  let yoe = y.rem_euclid(400);
+  let mp = if month > 2 { month - 3 } else { month + 9 };
+  let doy = (153*mp + 2)/5 + day - 1;
+  let doe = yoe*365 + yoe/4 - yoe/100 + doy;
+  let days = era*146_097 + doe - 719_468;
+  Some(days*86400 + hour*3600 + minute*60 + second)
+}
+
+/// Synchronous OSM XML decoder that reads `.osm` or osmChange XML from any `std::io::Read`
+/// and iterates over the same fallible `Dataset` items as `Decoder`. The first error ends the
+/// iteration.
+pub struct XmlDecoder<R: std::io::Read> {
+  reader: R,
+  state: XmlState,
+  chunk: Vec<u8>,
+}
+
+impl<R: std::io::Read> XmlDecoder<R> {
+  pub fn new(reader: R) -> Self {
+    Self { reader, state: XmlState::new(), chunk: vec![0;65536] }
+  }
+  /// Read the next dataset. After the first error, this returns `Ok(None)`.
+  pub fn next_item(&mut self) -> Result<Option<Dataset>,DecodeError> {
+    if self.state.failed { return Ok(None) }
+    let result = self.read_item();
+    self.state.failed = result.is_err();
+    result
+  }
+  fn read_item(&mut self) -> Result<Option<Dataset>,DecodeError> {
+    loop {
+      if let Some(data) = self.state.next_buffered()? {
+        return Ok(Some(data));
+      }
+      if self.state.eof { return Ok(None) }
+      self.state.compact();
+      let n = loop {
+        match self.reader.read(&mut self.chunk) {
+          Ok(n) => break n,
+          Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+          Err(e) => return Err(DecodeError::StreamReadError {
+            source: Box::new(e.into()),
+            context: self.state.context(),
+          }),
+        }
+      };
+      self.state.filled(&self.chunk[..n]);
+    }
+  }
+}
+
+impl<R: std::io::Read> Iterator for XmlDecoder<R> {
+  type Item = DecodeItem;
+  fn next(&mut self) -> Option<Self::Item> {
+    match self.next_item() {
+      Ok(None) => None,
+      Ok(Some(x)) => Some(Ok(x)),
+      Err(e) => Some(Err(e)),
+    }
+  }
+}
+
+struct AsyncXmlDecoder {
+  reader: Box<dyn io::AsyncRead+Send+Unpin>,
+  state: XmlState,
+  chunk: Vec<u8>,
+}
+
+impl AsyncXmlDecoder {
+  async fn next_item(&mut self) -> Result<Option<Dataset>,DecodeError> {
+    if self.state.failed { return Ok(None) }
+    let result = self.read_item().await;
+    self.state.failed = result.is_err();
+    result
+  }
+  async fn read_item(&mut self) -> Result<Option<Dataset>,DecodeError> {
+    loop {
+      if let Some(data) = self.state.next_buffered()? {
+        return Ok(Some(data));
+      }
+      if self.state.eof { return Ok(None) }
+      self.state.compact();
+      let n = loop {
+        match self.reader.read(&mut self.chunk).await {
+          Ok(n) => break n,
+          Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+          Err(e) => return Err(DecodeError::StreamReadError {
+            source: Box::new(e.into()),
+            context: self.state.context(),
+          }),
+        }
+      };
+      self.state.filled(&self.chunk[..n]);
+    }
+  }
+}
+
+/// Transform OSM XML or osmChange XML from `reader` into a stream of fallible `Dataset` items,
+/// like `decode` does for o5m. The stream starts with an o5m2 header for `.osm` input or an
+/// o5c2 header for osmChange input. Elements in `<delete>` blocks and elements marked
+/// `visible="false"` become `Dataset::Delete` items. As with `decode`, the first error ends
+/// the stream.
+pub fn decode_xml(reader: Box<dyn io::AsyncRead+Send+Unpin>) -> DecodeStream {
+  let state = AsyncXmlDecoder { reader, state: XmlState::new(), chunk: vec![0;65536] };
+  Box::new(crate::unfold::unfold(state, |mut qs| async move {
+    match qs.next_item().await {
+      Ok(None) => None,
+      Ok(Some(x)) => Some((Ok(x),qs)),
+      Err(e) => Some((Err(e),qs)),
+    }
+  }))
+}
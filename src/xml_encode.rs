@@ -0,0 +1,305 @@
+use futures::{prelude::*,stream::Stream,io};
+use std::io::Write;
+use crate::{Dataset,ElementType,EncodeError,ErrorTrace,Info,Tags,Timestamp};
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+enum Action { Create(), Modify(), Delete() }
+
+impl Action {
+  fn name(&self) -> &'static str {
+    match self {
+      Self::Create() => "create",
+      Self::Modify() => "modify",
+      Self::Delete() => "delete",
+    }
+  }
+}
+
+/// Incremental OSM XML encoder. Writes `<osm>` documents, or `<osmChange>` documents when the
+/// first dataset is an o5c header.
+///
+/// o5c files do not tell created elements apart from modified ones, so in osmChange output the
+/// block is chosen by version alone: elements with version 1 go into `<create>` blocks and all
+/// other elements, including ones without a version, into `<modify>` blocks. Deletes go into
+/// `<delete>` blocks. osmChange has no `<bounds>` element, so `Dataset::BBox` is left out there.
+pub struct XmlEncoder {
+  begun: bool,
+  ended: bool,
+  change: bool,
+  timestamp: Option<i64>,
+  action: Option<Action>,
+}
+
+impl XmlEncoder {
+  pub fn new() -> Self {
+    Self { begun: false, ended: false, change: false, timestamp: None, action: None }
+  }
+  /// Write the xml declaration and the opening root tag if they have not been written yet.
+  pub fn begin(&mut self, buf: &mut Vec<u8>) {
+    if self.begun { return }
+    self.begun = true;
+    buf.extend_from_slice(b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    buf.extend_from_slice(match self.change {
+      true => b"<osmChange version=\"0.6\" generator=\"o5m-stream\"",
+      false => b"<osm version=\"0.6\" generator=\"o5m-stream\"",
+    });
+    if let Some(time) = self.timestamp {
+      buf.extend_from_slice(b" timestamp=\"");
+      timestamp(buf, time);
+      buf.push(b'"');
+    }
+    buf.extend_from_slice(b">\n");
+  }
+  /// Close any open block and the root tag. Nothing can be written afterwards.
+  pub fn end(&mut self, buf: &mut Vec<u8>) {
+    self.begin(buf);
+    if self.ended { return }
+    self.ended = true;
+    self.set_action(buf, None);
+    buf.extend_from_slice(match self.change {
+      true => b"</osmChange>\n",
+      false => b"</osm>\n",
+    });
+  }
+  /// Write `dataset` to `buf`. On an error nothing is written, and `buf` and the encoder are
+  /// left as they were.
+  pub fn write_dataset(&mut self, dataset: &Dataset, buf: &mut Vec<u8>) -> Result<(),EncodeError> {
+    if self.ended { return Ok(()) }
+    let (len,begun,action) = (buf.len(),self.begun,self.action);
+    let result = self.write(dataset, buf);
+    if result.is_err() {
+      buf.truncate(len);
+      self.begun = begun;
+      self.action = action;
+    }
+    result
+  }
+  fn write(&mut self, dataset: &Dataset, buf: &mut Vec<u8>) -> Result<(),EncodeError> {
+    match dataset {
+      Dataset::Header(header) => {
+        if !self.begun {
+          self.change = header.is_change();
+        }
+      },
+      Dataset::Timestamp(t) => {
+        if !self.begun {
+          self.timestamp = Some(t.time);
+        }
+      },
+      Dataset::BBox(_) if self.change => self.begin(buf),
+      Dataset::BBox(bbox) => {
+        self.begin(buf);
+        self.set_action(buf, None);
+        writeln!(buf, "  <bounds minlat=\"{}\" minlon=\"{}\" maxlat=\"{}\" maxlon=\"{}\"/>",
+          bbox.y1, bbox.x1, bbox.y2, bbox.x2).unwrap();
+      },
+      Dataset::Node(node) => {
+        self.begin(buf);
+        let indent = self.element_action(buf, &node.info);
+        open(buf, indent, "node", node.id, &node.info)?;
+        if let Some(data) = &node.data {
+          write!(buf, " lat=\"{}\" lon=\"{}\"", data.latitude, data.longitude).unwrap();
+        }
+        if node.tags.is_empty() {
+          buf.extend_from_slice(b"/>\n");
+        } else {
+          buf.extend_from_slice(b">\n");
+          tags(buf, indent, &node.tags)?;
+          close(buf, indent, "node");
+        }
+      },
+      Dataset::Way(way) => {
+        self.begin(buf);
+        let indent = self.element_action(buf, &way.info);
+        open(buf, indent, "way", way.id, &way.info)?;
+        let refs = way.data.as_ref().map(|d| d.refs.as_slice()).unwrap_or(&[]);
+        if refs.is_empty() && way.tags.is_empty() {
+          buf.extend_from_slice(b"/>\n");
+        } else {
+          buf.extend_from_slice(b">\n");
+          for r in refs {
+            writeln!(buf, "{}  <nd ref=\"{}\"/>", indent, r).unwrap();
+          }
+          tags(buf, indent, &way.tags)?;
+          close(buf, indent, "way");
+        }
+      },
+      Dataset::Relation(relation) => {
+        self.begin(buf);
+        let indent = self.element_action(buf, &relation.info);
+        open(buf, indent, "relation", relation.id, &relation.info)?;
+        let members = relation.data.as_ref().map(|d| d.members.as_slice()).unwrap_or(&[]);
+        if members.is_empty() && relation.tags.is_empty() {
+          buf.extend_from_slice(b"/>\n");
+        } else {
+          buf.extend_from_slice(b">\n");
+          for m in members {
+            write!(buf, "{}  <member type=\"{}\" ref=\"{}\" role=\"", indent,
+              element_name(&m.element_type), m.id).unwrap();
+            escape(buf, &m.role, "role")?;
+            buf.extend_from_slice(b"\"/>\n");
+          }
+          tags(buf, indent, &relation.tags)?;
+          close(buf, indent, "relation");
+        }
+      },
+      Dataset::Delete(delete) => {
+        self.begin(buf);
+        let indent = match self.change {
+          true => {
+            self.set_action(buf, Some(Action::Delete()));
+            "    "
+          },
+          false => "  ",
+        };
+        open(buf, indent, element_name(&delete.element_type), delete.id, &delete.info)?;
+        if !self.change {
+          // plain osm files mark deletions with the visible attribute
+          buf.extend_from_slice(b" visible=\"false\"");
+        }
+        buf.extend_from_slice(b"/>\n");
+      },
+    }
+    Ok(())
+  }
+  // open the block that an element with `info` belongs in and return its indentation
+  fn element_action(&mut self, buf: &mut Vec<u8>, info: &Option<Info>) -> &'static str {
+    if !self.change { return "  " }
+    let action = match info.as_ref().and_then(|info| info.version) {
+      Some(1) => Action::Create(),
+      _ => Action::Modify(),
+    };
+    self.set_action(buf, Some(action));
+    "    "
+  }
+  fn set_action(&mut self, buf: &mut Vec<u8>, action: Option<Action>) {
+    if self.action == action { return }
+    if let Some(prev) = self.action {
+      writeln!(buf, "  </{}>", prev.name()).unwrap();
+    }
+    if let Some(next) = action {
+      writeln!(buf, "  <{}>", next.name()).unwrap();
+    }
+    self.action = action;
+  }
+}
+
+impl Default for XmlEncoder {
+  fn default() -> Self { Self::new() }
+}
+
+fn element_name(element_type: &ElementType) -> &'static str {
+  match element_type {
+    ElementType::Node() => "node",
+    ElementType::Way() => "way",
+    ElementType::Relation() => "relation",
+  }
+}
+
+// start tag of an element with its id and info attributes, left open for more attributes
+fn open(buf: &mut Vec<u8>, indent: &str, name: &str, id: u64, info: &Option<Info>)
+-> Result<(),EncodeError> {
+  write!(buf, "{}<{} id=\"{}\"", indent, name, id).unwrap();
+  let info = match info {
+    Some(info) => info,
+    None => return Ok(()),
+  };
+  if let Some(version) = info.version {
+    write!(buf, " version=\"{}\"", version).unwrap();
+  }
+  if let Some(time) = info.timestamp {
+    buf.extend_from_slice(b" timestamp=\"");
+    timestamp(buf, time);
+    buf.push(b'"');
+  }
+  if let Some(uid) = info.uid {
+    write!(buf, " uid=\"{}\"", uid).unwrap();
+  }
+  if let Some(user) = &info.user {
+    buf.extend_from_slice(b" user=\"");
+    escape(buf, user, "user")?;
+    buf.push(b'"');
+  }
+  if let Some(changeset) = info.changeset {
+    write!(buf, " changeset=\"{}\"", changeset).unwrap();
+  }
+  Ok(())
+}
+
+fn close(buf: &mut Vec<u8>, indent: &str, name: &str) {
+  writeln!(buf, "{}</{}>", indent, name).unwrap();
+}
+
+// tags sorted by key so that the output does not depend on hash map order
+fn tags(buf: &mut Vec<u8>, indent: &str, tags: &Tags) -> Result<(),EncodeError> {
+  let mut sorted = tags.iter().collect::<Vec<_>>();
+  sorted.sort();
+  for (k,v) in sorted {
+    write!(buf, "{}  <tag k=\"", indent).unwrap();
+    escape(buf, k, "tag key")?;
+    buf.extend_from_slice(b"\" v=\"");
+    escape(buf, v, "tag value")?;
+    buf.extend_from_slice(b"\"/>\n");
+  }
+  Ok(())
+}
+
+// escape `s` for use in a double-quoted attribute value. tab, line feed and carriage return
+// are written as character references so that parsers do not turn them into spaces. xml 1.0
+// has no way to write the other control characters
+fn escape(buf: &mut Vec<u8>, s: &str, info: &str) -> Result<(),EncodeError> {
+  for c in s.chars() {
+    match c {
+      '&' => buf.extend_from_slice(b"&amp;"),
+      '<' => buf.extend_from_slice(b"&lt;"),
+      '>' => buf.extend_from_slice(b"&gt;"),
+      '"' => buf.extend_from_slice(b"&quot;"),
+      '\'' => buf.extend_from_slice(b"&apos;"),
+      '\0' => return Err(EncodeError::UnexpectedNullByte {
+        info: info.to_string(),
+        backtrace: ErrorTrace::capture(),
+      }),
+      '\t' | '\n' | '\r' => write!(buf, "&#{};", c as u32).unwrap(),
+      c if (c as u32) < 0x20 => return Err(EncodeError::UnexpectedControlCharacter {
+        info: info.to_string(),
+        byte: c as u8,
+        backtrace: ErrorTrace::capture(),
+      }),
+      c => {
+        let mut bytes = [0;4];
+        buf.extend_from_slice(c.encode_utf8(&mut bytes).as_bytes());
+      },
+    }
+  }
+  Ok(())
+}
+
+// seconds since the unix epoch as an ISO 8601 UTC timestamp
+fn timestamp(buf: &mut Vec<u8>, time: i64) {
+  write!(buf, "{}", Timestamp { time }).unwrap();
+}
+
+/// Write the `Dataset` items from `stream` to `writer` as OSM XML,
+/// or as osmChange XML if the first item is an o5c header. See `XmlEncoder` for how elements
+/// are sorted into the blocks of an osmChange document.
+pub async fn encode_xml(
+  mut stream: Box<dyn Stream<Item=Dataset>+Send+Unpin>,
+  mut writer: Box<dyn io::AsyncWrite+Send+Unpin>,
+) -> Result<(),EncodeError> {
+  let mut encoder = XmlEncoder::new();
+  let mut buf = vec![];
+  while let Some(dataset) = stream.next().await {
+    encoder.write_dataset(&dataset, &mut buf)?;
+    if buf.len() >= 4096 {
+      writer.write_all(&buf).await
+        .map_err(|e| EncodeError::StreamWriteError { source: Box::new(e.into()) })?;
+      buf.clear();
+    }
+  }
+  encoder.end(&mut buf);
+  writer.write_all(&buf).await
+    .map_err(|e| EncodeError::StreamWriteError { source: Box::new(e.into()) })?;
+  writer.flush().await
+    .map_err(|e| EncodeError::StreamWriteError { source: Box::new(e.into()) })?;
+  Ok(())
+}
@@ -0,0 +1,178 @@
+mod common;
+use common::{encode,node,tags};
+use o5m_stream::{
+  Dataset,Decoder,ElementType,Encoder,Relation,RelationData,RelationMember,Way,WayData,
+};
+use std::io::Write;
+use std::process::{Command,Stdio};
+
+fn sample() -> Vec<Dataset> {
+  vec![
+    node(1, 10_000_000, 20_000_000, &[("amenity","cafe"),("name","A")]),
+    node(2, 10_500_000, 20_500_000, &[("amenity","cafe")]),
+    node(3, 30_000_000, 40_000_000, &[("name","A")]),
+    Dataset::Way(Way {
+      id: 10,
+      info: None,
+      data: Some(WayData { refs: vec![1,2] }),
+      tags: tags(&[("highway","path"),("name","A")]),
+    }),
+    Dataset::Relation(Relation {
+      id: 20,
+      info: None,
+      data: Some(RelationData {
+        members: vec![RelationMember { id: 10, element_type: ElementType::Way(), role: "".into() }],
+      }),
+      tags: tags(&[("type","route")]),
+    }),
+  ]
+}
+
+// run the o5m binary with `input` on stdin. returns the exit status, stdout and stderr
+fn o5m(args: &[&str], input: &[u8]) -> (Option<i32>,Vec<u8>,String) {
+  let mut child = Command::new(env!("CARGO_BIN_EXE_o5m"))
+    .args(args)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+  // the binary may exit on bad arguments before it reads its input
+  match child.stdin.take().unwrap().write_all(input) {
+    Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {},
+    x => x.unwrap(),
+  }
+  let output = child.wait_with_output().unwrap();
+  (output.status.code(),output.stdout,String::from_utf8(output.stderr).unwrap())
+}
+
+fn ids(buf: &[u8]) -> Vec<(Option<ElementType>,u64)> {
+  Decoder::new(buf).filter_map(|item| match item.unwrap() {
+    Dataset::Node(node) => Some((Some(ElementType::Node()),node.id)),
+    Dataset::Way(way) => Some((Some(ElementType::Way()),way.id)),
+    Dataset::Relation(relation) => Some((Some(ElementType::Relation()),relation.id)),
+    _ => None,
+  }).collect()
+}
+
+#[test]
+fn cat() {
+  let (status,out,_) = o5m(&["cat"], &encode(&sample()));
+  assert_eq!(status, Some(0));
+  assert_eq!(String::from_utf8(out).unwrap(), "header o5m2
+node 1 1,2 amenity=cafe name=A
+node 2 1.05,2.05 amenity=cafe
+node 3 3,4 name=A
+way 10 [1,2] highway=path name=A
+relation 20 [w10:\"\"] type=route
+");
+}
+
+#[test]
+fn stats() {
+  let (status,out,_) = o5m(&["stats","-"], &encode(&sample()));
+  assert_eq!(status, Some(0));
+  assert_eq!(String::from_utf8(out).unwrap(), "format      o5m2
+nodes       3 (ids 1..3)
+ways        1 (ids 10..10)
+relations   1 (ids 20..20)
+deletes     0
+bbox        1,2,3,4
+");
+}
+
+#[test]
+fn filter() {
+  let input = encode(&sample());
+  let node = Some(ElementType::Node());
+  let way = Some(ElementType::Way());
+  let (status,out,_) = o5m(&["filter","--type","way,relation"], &input);
+  assert_eq!(status, Some(0));
+  assert_eq!(ids(&out), vec![(way.clone(),10),(Some(ElementType::Relation()),20)]);
+  // repeated tags all have to match
+  let (_,out,_) = o5m(&["filter","--tag","name=A"], &input);
+  assert_eq!(ids(&out), vec![(node.clone(),1),(node.clone(),3),(way.clone(),10)]);
+  let (_,out,_) = o5m(&["filter","--tag","name=A","--tag","amenity"], &input);
+  assert_eq!(ids(&out), vec![(node.clone(),1)]);
+  let (_,out,_) = o5m(&["filter","--type","node","--tag","amenity=cafe"], &input);
+  assert_eq!(ids(&out), vec![(node.clone(),1),(node.clone(),2)]);
+  let (_,out,_) = o5m(&["filter","--bbox","0.5,1.5,1.2,2.2","--type","way"], &input);
+  assert_eq!(ids(&out), vec![(way,10)]);
+}
+
+#[test]
+fn convert() {
+  let input = encode(&sample());
+  let (status,out,_) = o5m(&["convert","--to","osm"], &input);
+  assert_eq!(status, Some(0));
+  let xml = String::from_utf8(out).unwrap();
+  assert!(xml.contains("<osm "), "{}", xml);
+  assert!(xml.contains("<node id=\"1\" lat=\"2\" lon=\"1\">"), "{}", xml);
+  // the xml reads back to the same elements
+  let (_,out,_) = o5m(&["convert","--from","osm","--to","o5m"], xml.as_bytes());
+  assert_eq!(ids(&out), ids(&input));
+
+  let (status,out,_) = o5m(&["convert","--to","osc"], &input);
+  assert_eq!(status, Some(0));
+  let osc = String::from_utf8(out).unwrap();
+  assert!(osc.contains("<osmChange "), "{}", osc);
+  assert!(osc.contains("<modify>"), "{}", osc);
+
+  let (status,_,err) = o5m(&["convert"], &input);
+  assert_eq!(status, Some(1));
+  assert!(err.contains("convert needs --to FORMAT"), "{}", err);
+}
+
+#[test]
+fn lenient() {
+  // the first node refers to a string that was never added, and a reset follows it
+  let mut input = encode(&sample()[..1]);
+  input.extend_from_slice(&[0x10, 0x05, 0x02, 0x00, 0x00, 0x00, 0x05]);
+  let mut encoder = Encoder::new();
+  encoder.begin(&mut vec![]);
+  encoder.reset(&mut input);
+  encoder.write_dataset(&sample()[3], &mut input).unwrap();
+
+  let (status,out,err) = o5m(&["cat"], &input);
+  assert_eq!(status, Some(1));
+  assert_eq!(String::from_utf8(out).unwrap(), "header o5m2\nnode 1 1,2 amenity=cafe name=A\n");
+  assert!(err.contains("string at index 5 not available"), "{}", err);
+
+  let (status,out,err) = o5m(&["cat","--lenient"], &input);
+  assert_eq!(status, Some(0));
+  assert!(String::from_utf8(out).unwrap().ends_with("way 10 [1,2] highway=path name=A\n"));
+  assert!(err.contains("skipped bytes"), "{}", err);
+
+  let (status,_,err) = o5m(&["cat","--lenient","--from","osm"], &input);
+  assert_eq!(status, Some(1));
+  assert!(err.contains("--lenient only applies to o5m and pbf input"), "{}", err);
+}
+
+#[test]
+fn filter_geojson() {
+  let input = encode(&sample());
+  // the nodes of the way are not selected but still place it
+  let (status,out,_) = o5m(&["filter","--tag","highway","--to","geojsonl"], &input);
+  assert_eq!(status, Some(0));
+  let lines = String::from_utf8(out).unwrap();
+  assert_eq!(lines.lines().count(), 1, "{}", lines);
+  assert!(lines.contains("\"LineString\""), "{}", lines);
+  assert!(lines.contains("\"highway\":\"path\""), "{}", lines);
+
+  let (status,out,_) = o5m(&["filter","--type","way","--to","geojson"], &input);
+  assert_eq!(status, Some(0));
+  let collection = String::from_utf8(out).unwrap();
+  assert!(collection.contains("\"FeatureCollection\""), "{}", collection);
+  assert_eq!(collection.matches("\"Feature\"").count(), 1, "{}", collection);
+  assert!(collection.contains("\"LineString\""), "{}", collection);
+
+  let (status,out,_) = o5m(&["filter","--type","node","--tag","amenity","--to","geojsonl"], &input);
+  assert_eq!(status, Some(0));
+  let lines = String::from_utf8(out).unwrap();
+  assert_eq!(lines.lines().count(), 2, "{}", lines);
+  assert!(lines.lines().all(|l| l.contains("\"Point\"")), "{}", lines);
+
+  let (status,_,err) = o5m(&["filter","--complete-ways"], &input);
+  assert_eq!(status, Some(1));
+  assert!(err.contains("--complete-ways needs --bbox"), "{}", err);
+}
@@ -0,0 +1,78 @@
+// fixtures shared by the integration tests. each test crate uses only some of them
+#![allow(dead_code)]
+use o5m_stream::{
+  BBox,Coord,Dataset,ElementType,Encoder,Header,Info,Node,NodeData,Relation,RelationData,
+  RelationMember,Tags,Timestamp,Way,WayData,
+};
+
+pub fn tags(pairs: &[(&str,&str)]) -> Tags {
+  pairs.iter().map(|(k,v)| (k.to_string(),v.to_string())).collect()
+}
+
+pub fn node(id: u64, lon: i32, lat: i32, pairs: &[(&str,&str)]) -> Dataset {
+  let data = Some(NodeData { longitude: Coord(lon), latitude: Coord(lat) });
+  Dataset::Node(Node { id, info: None, data, tags: tags(pairs) })
+}
+
+pub fn way(id: u64, refs: &[u64], pairs: &[(&str,&str)]) -> Dataset {
+  let data = Some(WayData { refs: refs.to_vec() });
+  Dataset::Way(Way { id, info: None, data, tags: tags(pairs) })
+}
+
+// o5m bytes of `items`
+pub fn encode(items: &[Dataset]) -> Vec<u8> {
+  let mut encoder = Encoder::new();
+  let mut buf = vec![];
+  for data in items {
+    encoder.write_dataset(data, &mut buf).unwrap();
+  }
+  buf
+}
+
+// a file with the given header format that every encoder reads back unchanged: more nodes than
+// fit in one pbf block, with and without info and tags, then a way and a relation
+pub fn sample(format: &str) -> Vec<Dataset> {
+  let info = |version| Some(Info {
+    version: Some(version),
+    timestamp: Some(1_600_000_000),
+    changeset: Some(7),
+    uid: Some(9),
+    user: Some("alice".to_string()),
+  });
+  let mut items = vec![
+    Dataset::Header(Header { format: format.to_string() }),
+    Dataset::Timestamp(Timestamp { time: 1_600_000_000 }),
+    Dataset::BBox(BBox {
+      x1: Coord(-1_800_000_000),
+      y1: Coord(-900_000_000),
+      x2: Coord(1_800_000_000),
+      y2: Coord(900_000_000),
+    }),
+  ];
+  for id in 1..10_000 {
+    items.push(Dataset::Node(Node {
+      id: id * 3,
+      info: if id % 2 == 0 { info(id) } else { None },
+      data: Some(NodeData { longitude: Coord(-(id as i32) * 997), latitude: Coord(id as i32) }),
+      tags: if id % 5 == 0 { tags(&[("name",&format!("n{}", id % 7))]) } else { Tags::new() },
+    }));
+  }
+  items.push(Dataset::Way(Way {
+    id: 1,
+    info: info(2),
+    data: Some(WayData { refs: vec![3,6,3] }),
+    tags: tags(&[("highway","path"),("name","n1")]),
+  }));
+  items.push(Dataset::Relation(Relation {
+    id: 1,
+    info: None,
+    data: Some(RelationData {
+      members: vec![
+        RelationMember { id: 1, element_type: ElementType::Way(), role: "outer".to_string() },
+        RelationMember { id: 3, element_type: ElementType::Node(), role: String::new() },
+      ],
+    }),
+    tags: tags(&[("type","multipolygon")]),
+  }));
+  items
+}
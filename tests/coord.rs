@@ -0,0 +1,52 @@
+use o5m_stream::Coord;
+
+#[test]
+fn degrees_round_trip() {
+  let mut x = i32::MIN as i64;
+  while x <= i32::MAX as i64 {
+    let c = Coord(x as i32);
+    assert_eq!(Coord::from_degrees(c.to_degrees()), c);
+    x += 9_999_991;
+  }
+  for c in [Coord(i32::MIN), Coord(i32::MAX), Coord(0), Coord(1), Coord(-1)] {
+    assert_eq!(Coord::from_degrees(c.to_degrees()), c);
+  }
+  assert_eq!(Coord::from_degrees(1e10), Coord(i32::MAX));
+}
+
+#[test]
+fn display() {
+  assert_eq!(Coord(525_000_001).to_string(), "52.5000001");
+  assert_eq!(Coord(-1_234_000).to_string(), "-0.1234");
+  assert_eq!(Coord(520_000_000).to_string(), "52");
+  assert_eq!(Coord(-1).to_string(), "-0.0000001");
+  assert_eq!(Coord(0).to_string(), "0");
+  assert_eq!(Coord(i32::MIN).to_string(), "-214.7483648");
+}
+
+#[test]
+fn parse() {
+  let parse = |s: &str| s.parse::<Coord>().ok();
+  assert_eq!(parse("52.5000001"), Some(Coord(525_000_001)));
+  assert_eq!(parse("-0.1234"), Some(Coord(-1_234_000)));
+  assert_eq!(parse("+13"), Some(Coord(130_000_000)));
+  assert_eq!(parse(".5"), Some(Coord(5_000_000)));
+  assert_eq!(parse("7."), Some(Coord(70_000_000)));
+  // past the 7th decimal place, values round half away from zero
+  assert_eq!(parse("0.00000015"), Some(Coord(2)));
+  assert_eq!(parse("-0.00000014999"), Some(Coord(-1)));
+  assert_eq!(parse("-214.7483648"), Some(Coord(i32::MIN)));
+  for s in ["", "-", ".", "1e5", "1.2.3", " 1", "214.7483648", "99999999999999999999"] {
+    assert_eq!(parse(s), None, "{:?}", s);
+  }
+}
+
+#[test]
+fn display_parse_round_trip() {
+  let mut x = i32::MIN as i64;
+  while x <= i32::MAX as i64 {
+    let c = Coord(x as i32);
+    assert_eq!(c.to_string().parse::<Coord>().unwrap(), c);
+    x += 7_654_321;
+  }
+}
@@ -0,0 +1,433 @@
+mod common;
+use common::encode;
+use futures::prelude::*;
+use o5m_stream::{
+  Coord,Dataset,DatasetType,Decoder,DecodeError,DecoderOptions,Delete,ElementType,Encoder,
+  Frame,FrameDecoder,Header,Node,NodeData,ParallelDecoder,Relation,RelationData,RelationMember,
+  SliceDecoder,SliceFrameDecoder,TagFilter,Tags,Way,WayData,
+};
+
+// reset, then a node whose tags refer to a string that was never added to the table,
+// then a node that would be valid on its own
+const CORRUPT: &[u8] = &[
+  0xff,
+  0x10, 0x05, 0x02, 0x00, 0x00, 0x00, 0x05,
+  0x10, 0x04, 0x02, 0x00, 0x00, 0x00,
+];
+
+#[test]
+fn strict_error_ends_iteration() {
+  let items = Decoder::new(CORRUPT).collect::<Vec<_>>();
+  assert_eq!(items.len(), 1);
+  assert!(matches!(items[0], Err(DecodeError::StringUnavailable { .. })));
+}
+
+#[test]
+fn strict_error_ends_next_item() {
+  let mut decoder = Decoder::new(CORRUPT);
+  assert!(decoder.next_item().is_err());
+  assert!(decoder.next_item().unwrap().is_none());
+  assert!(decoder.next_item().unwrap().is_none());
+}
+
+#[test]
+fn decodes_valid_input() {
+  let items = Decoder::new(&CORRUPT[7..]).collect::<Result<Vec<_>,_>>();
+  // without the leading reset the first byte is wrong
+  assert!(items.is_err());
+  let mut input = vec![0xff];
+  input.extend_from_slice(&CORRUPT[8..]);
+  let items = Decoder::new(&input[..]).collect::<Result<Vec<_>,_>>().unwrap();
+  match items.as_slice() {
+    [Dataset::Node(node)] => {
+      assert_eq!(node.id, 1);
+      assert_eq!(node.data, Some(NodeData { longitude: Coord(0), latitude: Coord(0) }));
+    },
+    x => panic!("unexpected {:?}", x),
+  }
+}
+
+fn nodes(ids: std::ops::Range<u64>) -> Vec<Dataset> {
+  ids.map(|id| Dataset::Node(Node {
+    id,
+    info: None,
+    data: Some(NodeData { longitude: Coord(id as i32 * 10), latitude: Coord(-(id as i32)) }),
+    tags: vec![("name".to_string(),format!("node {}", id))].into_iter().collect(),
+  })).collect()
+}
+
+// two sections of nodes with a reset in between, and the offset of that reset
+fn two_sections() -> (Vec<u8>,Vec<Dataset>,Vec<Dataset>,u64) {
+  let (a,b) = (nodes(1..50),nodes(50..100));
+  let mut encoder = Encoder::new();
+  let mut buf = vec![];
+  for dataset in &a { encoder.write_dataset(dataset, &mut buf).unwrap() }
+  let reset = buf.len() as u64;
+  encoder.reset(&mut buf);
+  for dataset in &b { encoder.write_dataset(dataset, &mut buf).unwrap() }
+  (buf,a,b,reset)
+}
+
+#[test]
+fn lenient_resyncs_at_reset_between_frames() {
+  let (mut buf,a,b,reset) = two_sections();
+  // fill the payload of the 5th node with 0xff bytes, which are not resets
+  let mut frames = FrameDecoder::new(&buf[..]);
+  let mut n = 0;
+  let (offset,start,end) = loop {
+    let frame = frames.next_frame().unwrap().unwrap();
+    if frame.data_type == Some(DatasetType::Node()) { n += 1 }
+    if n == 5 {
+      break (frame.offset, frame.end - frame.data.len() as u64, frame.end);
+    }
+  };
+  buf[start as usize..end as usize].iter_mut().for_each(|b| *b = 0xff);
+  let options = DecoderOptions { lenient: true, ..Default::default() };
+  let items = Decoder::with_options(&buf[..], options).collect::<Vec<_>>();
+  // the header, 4 nodes, the skipped range and the whole second section
+  assert_eq!(items.len(), 1 + 4 + 1 + b.len());
+  assert!(matches!(items[0], Ok(Dataset::Header(_))));
+  for (item,expected) in items[1..5].iter().zip(&a) {
+    assert_eq!(item.as_ref().unwrap(), expected);
+  }
+  match &items[5] {
+    Err(DecodeError::Skipped { start, end, .. }) => assert_eq!((*start,*end), (offset,reset)),
+    x => panic!("unexpected {:?}", x),
+  }
+  let rest = items[6..].iter().map(|x| x.as_ref().unwrap().clone()).collect::<Vec<_>>();
+  assert_eq!(rest, b);
+}
+
+#[test]
+fn lenient_skips_to_end_without_reset() {
+  let (buf,_,_,reset) = two_sections();
+  let mut input = buf[..reset as usize].to_vec();
+  input[0] = 0x00;
+  let options = DecoderOptions { lenient: true, ..Default::default() };
+  let items = Decoder::with_options(&input[..], options).collect::<Vec<_>>();
+  assert_eq!(items.len(), 1);
+  match &items[0] {
+    Err(DecodeError::Skipped { start, end, .. }) => assert_eq!((*start,*end), (0,reset)),
+    x => panic!("unexpected {:?}", x),
+  }
+}
+
+// every element type and deletes, with tags and roles that later elements refer back to in the
+// string table
+fn mixed() -> (Vec<u8>,Vec<Dataset>) {
+  let tags = |id: u64| -> Tags {
+    vec![
+      ("name".to_string(),format!("n{}", id % 3)),
+      ("ref".to_string(),format!("r{}", id % 4)),
+    ].into_iter().collect()
+  };
+  let mut items = nodes(1..20);
+  for id in 1..10 {
+    items.push(Dataset::Way(Way {
+      id,
+      info: None,
+      data: Some(WayData { refs: vec![id,id+1] }),
+      tags: tags(id),
+    }));
+  }
+  items.push(Dataset::Delete(Delete { id: 10, element_type: ElementType::Way(), info: None }));
+  for id in 1..5 {
+    items.push(Dataset::Relation(Relation {
+      id,
+      info: None,
+      data: Some(RelationData {
+        members: vec![RelationMember {
+          id,
+          element_type: ElementType::Way(),
+          role: format!("n{}", id % 3),
+        }],
+      }),
+      tags: tags(id),
+    }));
+  }
+  items.push(Dataset::Delete(Delete { id: 30, element_type: ElementType::Node(), info: None }));
+  let mut encoder = Encoder::new();
+  let mut buf = vec![];
+  // deletes need an o5c header
+  let header = Dataset::Header(Header { format: "o5c2".to_string() });
+  encoder.write_dataset(&header, &mut buf).unwrap();
+  for dataset in &items { encoder.write_dataset(dataset, &mut buf).unwrap() }
+  (buf,items)
+}
+
+#[test]
+fn element_types() {
+  let (buf,items) = mixed();
+  for types in [vec![ElementType::Way()],vec![ElementType::Node(),ElementType::Relation()]] {
+    let options = DecoderOptions { element_types: Some(types.clone()), ..Default::default() };
+    let decoded = Decoder::with_options(&buf[..], options).collect::<Result<Vec<_>,_>>().unwrap();
+    let expected = items.iter()
+      .filter(|data| data.get_type().map(|t| types.contains(&t)).unwrap_or(true))
+      .cloned();
+    assert!(matches!(decoded[0], Dataset::Header(_)));
+    assert_eq!(decoded[1..], expected.collect::<Vec<_>>()[..], "{:?}", types);
+  }
+}
+
+// `data` with only the tags with these keys
+fn filter_tags(data: &Dataset, keys: &[&str]) -> Dataset {
+  let mut data = data.clone();
+  let tags = match &mut data {
+    Dataset::Node(node) => &mut node.tags,
+    Dataset::Way(way) => &mut way.tags,
+    Dataset::Relation(relation) => &mut relation.tags,
+    _ => return data,
+  };
+  tags.retain(|k,_| keys.contains(&k.as_str()));
+  data
+}
+
+#[test]
+fn tag_filter() {
+  let (buf,items) = mixed();
+  // filters and the keys they keep
+  let filters = [
+    (TagFilter::Allow(vec!["name".to_string()]),vec!["name"]),
+    (TagFilter::Deny(vec!["name".to_string()]),vec!["ref"]),
+    (TagFilter::Allow(vec![]),vec![]),
+  ];
+  for (filter,keys) in filters.iter() {
+    let options = DecoderOptions { tag_filter: Some(filter.clone()), ..Default::default() };
+    let decoded = Decoder::with_options(&buf[..], options).collect::<Result<Vec<_>,_>>().unwrap();
+    let expected = items.iter().map(|data| filter_tags(data, keys)).collect::<Vec<_>>();
+    assert_eq!(decoded[1..], expected[..], "{:?}", filter);
+  }
+}
+
+#[test]
+fn frames() {
+  let buf = [0xff, 0x10, 0x02, 0xaa, 0xbb, 0x99, 0x01, 0x00, 0xff, 0x11, 0x00];
+  let mut frames = FrameDecoder::new(&buf[..]);
+  let mut found = vec![];
+  while let Some(frame) = frames.next_frame().unwrap() {
+    let Frame { data_type, type_byte, offset, end, data } = frame;
+    found.push((data_type,type_byte,offset,end,data.to_vec()));
+  }
+  assert_eq!(found, vec![
+    (Some(DatasetType::Reset()),0xff,0,1,vec![]),
+    (Some(DatasetType::Node()),0x10,1,5,vec![0xaa,0xbb]),
+    (None,0x99,5,8,vec![0x00]),
+    (Some(DatasetType::Reset()),0xff,8,9,vec![]),
+    (Some(DatasetType::Way()),0x11,9,11,vec![]),
+  ]);
+  // the payload is not decoded, but input must begin with a reset byte
+  assert!(FrameDecoder::new(&buf[1..]).next_frame().is_err());
+}
+
+#[test]
+fn header() {
+  let node = [0x10, 0x04, 0x02, 0x00, 0x00, 0x00];
+  let with_format = |format: &[u8]| {
+    let mut buf = vec![0xff, 0xe0, format.len() as u8];
+    buf.extend_from_slice(format);
+    buf.extend_from_slice(&node);
+    buf
+  };
+  for format in ["o5m2","o5c2"] {
+    let buf = with_format(format.as_bytes());
+    let mut decoder = Decoder::new(&buf[..]);
+    let header = Header { format: format.to_string() };
+    assert_eq!(decoder.header().unwrap(), Some(header.clone()));
+    // reading the header does not consume it
+    assert_eq!(decoder.next_item().unwrap(), Some(Dataset::Header(header)));
+    assert!(matches!(decoder.next_item().unwrap(), Some(Dataset::Node(Node { id: 1, .. }))));
+  }
+  let mut buf = vec![0xff];
+  buf.extend_from_slice(&node);
+  let mut decoder = Decoder::new(&buf[..]);
+  assert_eq!(decoder.header().unwrap(), None);
+  assert!(matches!(decoder.next_item().unwrap(), Some(Dataset::Node(Node { id: 1, .. }))));
+  assert_eq!(decoder.next_item().unwrap(), None);
+}
+
+#[test]
+fn errors_point_at_the_failing_frame() {
+  let mut items = nodes(1..20);
+  items.extend((1..5).map(|id| Dataset::Way(Way {
+    id,
+    info: None,
+    data: Some(WayData { refs: vec![id,id+1] }),
+    tags: vec![("highway".to_string(),"path".to_string())].into_iter().collect(),
+  })));
+  let mut buf = encode(&items);
+  // the third way ends with a back-reference to highway=path. point it past the table instead
+  let mut frames = FrameDecoder::new(&buf[..]);
+  let mut ways = vec![];
+  while let Some(frame) = frames.next_frame().unwrap() {
+    if frame.data_type == Some(DatasetType::Way()) { ways.push((frame.offset,frame.end)) }
+  }
+  let (offset,end) = ways[2];
+  assert_eq!(buf[end as usize - 1], 0x01);
+  buf[end as usize - 1] = 0x7f;
+
+  let decoded = Decoder::new(&buf[..]).collect::<Vec<_>>();
+  let sliced = SliceDecoder::new(&buf[..]).collect::<Vec<_>>();
+  for items in [decoded,sliced] {
+    // the header, 19 nodes and 2 ways come first
+    assert_eq!(items.len(), 23);
+    match &items[22] {
+      Err(e@DecodeError::StringUnavailable { index: 127, .. }) => {
+        assert_eq!(e.context().offset, Some(offset));
+        assert_eq!(e.context().data_type, Some(DatasetType::Way()));
+        assert_eq!(e.context().last_id, Some(2));
+      },
+      x => panic!("unexpected {:?}", x),
+    }
+  }
+}
+
+#[test]
+fn truncated_input_is_an_error() {
+  let buf = encode(&nodes(1..5));
+  let mut frames = FrameDecoder::new(&buf[..]);
+  let mut last = 0;
+  while let Some(frame) = frames.next_frame().unwrap() { last = frame.offset }
+  // cut into the payload of the last node, and after the length of the last node
+  for end in [buf.len() - 2, last as usize + 2] {
+    let input = &buf[..end];
+    let decoded = Decoder::new(input).collect::<Vec<_>>();
+    let sliced = SliceDecoder::new(input).collect::<Vec<_>>();
+    for items in [decoded,sliced] {
+      // the header and the first three nodes come first
+      assert_eq!(items.len(), 5);
+      match &items[4] {
+        Err(e@DecodeError::UnexpectedEof { .. }) => {
+          assert_eq!(e.context().offset, Some(last));
+          assert_eq!(e.context().data_type, Some(DatasetType::Node()));
+          assert_eq!(e.context().last_id, Some(3));
+        },
+        x => panic!("unexpected {:?}", x),
+      }
+    }
+    let options = DecoderOptions { lenient: true, ..Default::default() };
+    let items = Decoder::with_options(input, options).collect::<Vec<_>>();
+    assert_eq!(items.len(), 5);
+    match &items[4] {
+      Err(DecodeError::Skipped { start, end: skipped_end, source, .. }) => {
+        assert_eq!((*start,*skipped_end), (last,end as u64));
+        assert!(matches!(**source, DecodeError::UnexpectedEof { .. }));
+      },
+      x => panic!("unexpected {:?}", x),
+    }
+  }
+}
+
+#[test]
+fn empty_frame_at_the_end_is_decoded() {
+  let mut buf = encode(&nodes(1..3));
+  // a node without a payload has no id
+  let offset = buf.len() as u64;
+  buf.extend_from_slice(&[0x10, 0x00]);
+  let items = Decoder::new(&buf[..]).collect::<Vec<_>>();
+  assert_eq!(items.len(), 4);
+  let e = items[3].as_ref().unwrap_err();
+  assert_eq!(e.context().offset, Some(offset));
+  assert_eq!(e.context().last_id, Some(2));
+}
+
+#[test]
+fn end_of_file_byte_ends_the_input() {
+  let mut buf = encode(&nodes(1..5));
+  let expected = Decoder::new(&buf[..]).collect::<Result<Vec<_>,_>>().unwrap();
+  let end = buf.len() as u64;
+  // osmconvert ends its files with 0xfe. a 0xf0 byte is a single-byte dataset too, and
+  // anything after 0xfe is ignored
+  buf.extend_from_slice(&[0xf0, 0xfe, 0x10, 0x7f, 0x00]);
+  for lenient in [false,true] {
+    let options = DecoderOptions { lenient, ..Default::default() };
+    let decoded = Decoder::with_options(&buf[..], options.clone());
+    assert_eq!(decoded.collect::<Result<Vec<_>,_>>().unwrap(), expected);
+    let sliced = SliceDecoder::with_options(&buf[..], options.clone());
+    assert_eq!(sliced.collect::<Result<Vec<_>,_>>().unwrap(), expected);
+    let parallel = ParallelDecoder::with_options(&buf[..], 2, options.clone());
+    assert_eq!(parallel.collect::<Result<Vec<_>,_>>().unwrap(), expected);
+    let stream = o5m_stream::decode_with_options(
+      Box::new(futures::io::Cursor::new(buf.clone())),
+      options.clone(),
+    );
+    let items = futures::executor::block_on(stream.collect::<Vec<_>>());
+    assert_eq!(items.into_iter().collect::<Result<Vec<_>,_>>().unwrap(), expected);
+
+    let mut frames = FrameDecoder::with_options(&buf[..], options.clone());
+    let mut read = vec![];
+    while let Some(frame) = frames.next_frame().unwrap() {
+      read.push((frame.type_byte,frame.offset));
+    }
+    let mut sliced = SliceFrameDecoder::with_options(&buf[..], options);
+    let mut sliced_read = vec![];
+    while let Some(frame) = sliced.next_frame().unwrap() {
+      sliced_read.push((frame.type_byte,frame.offset));
+    }
+    assert_eq!(read, sliced_read);
+    assert_eq!(read[read.len()-2..], [(0xf0,end),(0xfe,end+1)]);
+  }
+}
+
+fn varint(buf: &mut Vec<u8>, mut x: u64) {
+  while x >= 0x80 {
+    buf.push((x as u8) | 0x80);
+    x >>= 7;
+  }
+  buf.push(x as u8);
+}
+
+// a node that adds k=v to the string table, then a node with `n` new tags that refers to k=v
+// before them, or after them if `after` is set
+fn back_reference(n: usize, after: bool) -> Vec<u8> {
+  let mut buf = vec![0xff, 0x10, 0x09, 0x02, 0x00, 0x00, 0x00, 0x00, b'k', 0x00, b'v', 0x00];
+  let mut payload = vec![0x02, 0x00, 0x00, 0x00];
+  if !after { payload.push(0x01) }
+  for i in 0..n {
+    payload.push(0x00);
+    payload.extend_from_slice(format!("k{}", i).as_bytes());
+    payload.extend_from_slice(&[0x00, b'v', 0x00]);
+  }
+  if after { varint(&mut payload, n as u64 + 1) }
+  buf.push(0x10);
+  varint(&mut buf, payload.len() as u64);
+  buf.extend(payload);
+  buf
+}
+
+fn second_node(items: &[Dataset]) -> &Node {
+  match items {
+    [Dataset::Node(_),Dataset::Node(node)] => node,
+    x => panic!("unexpected {:?}", x),
+  }
+}
+
+#[test]
+fn pushed_out_strings_are_unavailable() {
+  let items = Decoder::new(&back_reference(14_999, true)[..]).collect::<Result<Vec<_>,_>>()
+    .unwrap();
+  let node = second_node(&items);
+  assert_eq!(node.tags.len(), 15_000);
+  assert_eq!(node.tags.get("k").map(|v| v.as_str()), Some("v"));
+
+  let items = Decoder::new(&back_reference(15_000, true)[..]).collect::<Vec<_>>();
+  assert_eq!(items.len(), 2);
+  match &items[1] {
+    Err(e@DecodeError::StringUnavailable { index, .. }) => {
+      assert_eq!(*index, 15_001);
+      assert_eq!(e.context().offset, Some(12));
+    },
+    x => panic!("unexpected {:?}", x),
+  }
+}
+
+#[test]
+fn strings_pushed_out_by_their_own_dataset_are_kept() {
+  // k=v was in the table when the node referred to it, so it stays the value of k
+  let buf = back_reference(15_000, false);
+  let items = Decoder::new(&buf[..]).collect::<Result<Vec<_>,_>>().unwrap();
+  let node = second_node(&items);
+  assert_eq!(node.tags.len(), 15_001);
+  assert_eq!(node.tags.get("k").map(|v| v.as_str()), Some("v"));
+  let items = SliceDecoder::new(&buf[..]).collect::<Result<Vec<_>,_>>().unwrap();
+  assert_eq!(second_node(&items).tags.get("k").map(|v| v.as_str()), Some("v"));
+}
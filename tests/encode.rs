@@ -0,0 +1,212 @@
+mod common;
+use common::{encode,tags};
+use async_std::{prelude::*,task::block_on};
+use o5m_stream::{
+  Coord,Dataset,Decoder,Delete,ElementType,EncodeError,Encoder,EncoderOptions,FrameDecoder,Header,
+  Info,Node,NodeData,Relation,RelationData,RelationMember,Tags,Way,WayData,
+};
+
+// node with a version, so that its info is written
+fn versioned(id: u64, tags: Tags) -> Dataset {
+  Dataset::Node(Node {
+    id,
+    info: Some(Info { version: Some(id % 5 + 1), ..Info::new() }),
+    data: Some(NodeData { longitude: Coord(-(id as i32) * 1000), latitude: Coord(id as i32) }),
+    tags,
+  })
+}
+
+fn sample() -> Vec<Dataset> {
+  let long = "x".repeat(300);
+  // deletes need an o5c header
+  let mut items = vec![Dataset::Header(Header { format: "o5c2".to_string() })];
+  // more unique strings than the string table holds, then the earliest ones again after they
+  // have dropped out of the table, along with a few recent ones
+  for id in 1..16_000 {
+    items.push(versioned(id, tags(&[("name",&format!("node {}", id))])));
+  }
+  for id in 16_000..16_100 {
+    let name = format!("node {}", id - 15_999);
+    let recent = format!("node {}", id - 50);
+    items.push(versioned(id, tags(&[("name",&name),("alt_name",&recent)])));
+  }
+  items.push(versioned(16_100, tags(&[("note",&long),(&long,"long key")])));
+  items.push(versioned(16_101, tags(&[("note",&long)])));
+  items.push(Dataset::Way(Way {
+    id: 1,
+    info: Some(Info {
+      version: Some(2),
+      timestamp: Some(1_600_000_000),
+      changeset: Some(7),
+      uid: Some(9),
+      user: Some(long.clone()),
+    }),
+    data: Some(WayData { refs: vec![16_100,16_101,1,16_100] }),
+    tags: tags(&[("highway","path"),("name","node 1")]),
+  }));
+  items.push(Dataset::Relation(Relation {
+    id: 1,
+    info: None,
+    data: Some(RelationData {
+      members: vec![
+        RelationMember { id: 1, element_type: ElementType::Way(), role: "outer".to_string() },
+        RelationMember { id: 3, element_type: ElementType::Node(), role: long.clone() },
+      ],
+    }),
+    tags: tags(&[("type","multipolygon")]),
+  }));
+  items.push(Dataset::Delete(Delete {
+    id: 5,
+    element_type: ElementType::Node(),
+    info: Some(Info { version: Some(4), ..Info::new() }),
+  }));
+  items.push(Dataset::Delete(Delete { id: 2, element_type: ElementType::Way(), info: None }));
+  items
+}
+
+#[test]
+fn round_trip() {
+  let items = sample();
+  let buf = encode(&items);
+  let stream = o5m_stream::decode(Box::new(async_std::io::Cursor::new(buf)));
+  let decoded = block_on(stream.collect::<Vec<_>>());
+  let decoded = decoded.into_iter().collect::<Result<Vec<_>,_>>().unwrap();
+  assert_eq!(decoded.len(), items.len());
+  for (a,b) in decoded.iter().zip(items.iter()) {
+    assert_eq!(a, b);
+  }
+}
+
+#[test]
+fn extreme_deltas_wrap() {
+  let info = |timestamp: i64, changeset: u64| Some(Info {
+    version: Some(1),
+    timestamp: Some(timestamp),
+    changeset: Some(changeset),
+    uid: Some(1),
+    user: Some("a".to_string()),
+  });
+  let items = [(1,i64::MIN,0),(u64::MAX,i64::MAX,u64::MAX),(2,-1,1)].iter()
+    .map(|(id,timestamp,changeset)| Dataset::Node(Node {
+      id: *id,
+      info: info(*timestamp, *changeset),
+      data: Some(NodeData { longitude: Coord(i32::MIN), latitude: Coord(i32::MAX) }),
+      tags: Tags::new(),
+    }))
+    .collect::<Vec<_>>();
+  let decoded = Decoder::new(&encode(&items)[..]).collect::<Result<Vec<_>,_>>().unwrap();
+  assert_eq!(decoded[1..], items[..]);
+}
+
+// element type bytes of each section between resets, with runs of the same type collapsed
+fn sections(buf: &[u8]) -> Vec<Vec<u8>> {
+  let mut frames = FrameDecoder::new(buf);
+  let mut sections = vec![];
+  let mut element_types = vec![];
+  while let Some(frame) = frames.next_frame().unwrap() {
+    if frame.type_byte == 0xff {
+      sections.push(std::mem::take(&mut element_types));
+    } else if frame.type_byte != 0xe0 {
+      element_types.push(frame.type_byte);
+    }
+  }
+  sections.push(element_types);
+  for section in &mut sections {
+    section.dedup();
+  }
+  sections
+}
+
+#[test]
+fn resets_when_the_element_type_changes() {
+  // nodes, the way, the relation, then the node delete and the way delete
+  assert_eq!(sections(&encode(&sample())),
+    vec![vec![],vec![0x10],vec![0x11],vec![0x12],vec![0x10],vec![0x11]]);
+}
+
+#[test]
+fn resets_at_an_interval() {
+  let items = sample();
+  let mut encoder = Encoder::with_options(EncoderOptions { reset_interval: Some(5000) });
+  let mut buf = vec![];
+  for data in &items {
+    encoder.write_dataset(data, &mut buf).unwrap();
+  }
+  // 16,101 nodes in sections of 5000, then the rest as before
+  let mut frames = FrameDecoder::new(buf.as_slice());
+  let mut sizes = vec![0];
+  while let Some(frame) = frames.next_frame().unwrap() {
+    match frame.type_byte {
+      0xff => sizes.push(0),
+      0x10..=0x12 => *sizes.last_mut().unwrap() += 1,
+      _ => {},
+    }
+  }
+  assert_eq!(sizes, vec![0,5000,5000,5000,1101,1,1,1,1]);
+  let decoded = Decoder::new(buf.as_slice()).collect::<Result<Vec<_>,_>>().unwrap();
+  assert_eq!(decoded, items);
+}
+
+#[test]
+fn missing_author_reads_back_empty() {
+  let info = Info {
+    version: Some(1),
+    timestamp: Some(1_600_000_000),
+    changeset: Some(7),
+    uid: None,
+    user: None,
+  };
+  let node = |info| Dataset::Node(Node {
+    id: 1,
+    info: Some(info),
+    data: Some(NodeData { longitude: Coord(1), latitude: Coord(2) }),
+    tags: Tags::new(),
+  });
+  let decoded = Decoder::new(&encode(&[node(info.clone())])[..])
+    .collect::<Result<Vec<_>,_>>().unwrap();
+  let expected = Info { uid: Some(0), user: Some(String::new()), ..info };
+  assert_eq!(decoded[1..], [node(expected)]);
+}
+
+#[test]
+fn rejects_datasets_that_read_back_differently() {
+  let header = Dataset::Header(Header { format: "o5c2".to_string() });
+  let rejected = [
+    vec![versioned(1, Tags::new()), header.clone()],
+    vec![Dataset::Node(Node { id: 1, info: None, data: None, tags: Tags::new() })],
+    vec![Dataset::Way(Way { id: 1, info: None, data: None, tags: Tags::new() })],
+    vec![Dataset::Relation(Relation { id: 1, info: None, data: None, tags: Tags::new() })],
+  ];
+  for items in rejected.iter() {
+    let mut encoder = Encoder::new();
+    let mut buf = vec![];
+    let (last,rest) = items.split_last().unwrap();
+    for data in rest { encoder.write_dataset(data, &mut buf).unwrap() }
+    let len = buf.len();
+    let result = encoder.write_dataset(last, &mut buf);
+    assert!(matches!(result, Err(EncodeError::UnsupportedDataset { .. })), "{:?}", last);
+    assert_eq!(buf.len(), len);
+  }
+}
+
+#[test]
+fn adds_a_header() {
+  let items = sample().into_iter()
+    .filter(|data| !matches!(data, Dataset::Header(_) | Dataset::Delete(_)))
+    .collect::<Vec<_>>();
+  let decoded = Decoder::new(&encode(&items)[..]).collect::<Result<Vec<_>,_>>().unwrap();
+  assert_eq!(decoded[0], Dataset::Header(Header { format: "o5m2".to_string() }));
+  assert_eq!(decoded[1..], items[..]);
+}
+
+#[test]
+fn deletes_need_an_o5c_header() {
+  let delete = Dataset::Delete(Delete { id: 4, element_type: ElementType::Way(), info: None });
+  for items in [vec![],vec![Dataset::Header(Header { format: "o5m2".to_string() })]] {
+    let mut encoder = Encoder::new();
+    let mut buf = vec![];
+    for data in &items { encoder.write_dataset(data, &mut buf).unwrap() }
+    let result = encoder.write_dataset(&delete, &mut buf);
+    assert!(matches!(result, Err(EncodeError::UnsupportedDataset { .. })));
+  }
+}
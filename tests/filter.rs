@@ -0,0 +1,140 @@
+mod common;
+use common::{node,way};
+use futures::{executor::block_on,prelude::*};
+use o5m_stream::{
+  Area,BBox,Coord,Dataset,Delete,ElementType,NodeData,Relation,RelationData,RelationMember,
+  SpatialFilter,Tags,
+};
+
+fn point(x: i32, y: i32) -> NodeData {
+  NodeData { longitude: Coord(x), latitude: Coord(y) }
+}
+
+// members given as a type letter and id
+fn relation(id: u64, members: &[(char,u64)]) -> Dataset {
+  let members = members.iter().map(|(t,id)| RelationMember {
+    id: *id,
+    element_type: match t {
+      'n' => ElementType::Node(),
+      'w' => ElementType::Way(),
+      _ => ElementType::Relation(),
+    },
+    role: String::new(),
+  }).collect();
+  let data = Some(RelationData { members });
+  Dataset::Relation(Relation { id, info: None, data, tags: Tags::new() })
+}
+
+fn sample() -> Vec<Dataset> {
+  vec![
+    node(1, 5, 5, &[]),
+    node(2, 10, 10, &[]),
+    node(3, 20, 5, &[]),
+    node(4, 30, 30, &[]),
+    node(5, -5, 5, &[]),
+    way(10, &[1,3], &[]),
+    way(11, &[3,4], &[]),
+    way(12, &[4,5], &[]),
+    relation(20, &[('w',10)]),
+    relation(21, &[('w',12),('n',3)]),
+    relation(22, &[('r',20)]),
+    relation(23, &[('n',2)]),
+    relation(24, &[('n',1),('w',12)]),
+    Dataset::Delete(Delete { id: 4, element_type: ElementType::Node(), info: None }),
+  ]
+}
+
+// kept datasets as a type letter and id
+fn run(options: SpatialFilter) -> Vec<(char,u64)> {
+  let stream = Box::new(futures::stream::iter(sample().into_iter().map(Ok)));
+  let items = block_on(o5m_stream::filter(stream, options).collect::<Vec<_>>());
+  items.into_iter().map(|item| match item.unwrap() {
+    Dataset::Node(node) => ('n',node.id),
+    Dataset::Way(way) => ('w',way.id),
+    Dataset::Relation(relation) => ('r',relation.id),
+    Dataset::Delete(delete) => ('d',delete.id),
+    data => panic!("unexpected {:?}", data),
+  }).collect()
+}
+
+fn bbox() -> Area {
+  Area::BBox(BBox { x1: Coord(0), y1: Coord(0), x2: Coord(10), y2: Coord(10) })
+}
+
+#[test]
+fn keeps_elements_inside_the_area() {
+  assert_eq!(run(SpatialFilter::new(bbox())), vec![
+    ('n',1),('n',2),('w',10),('r',20),('r',22),('r',23),('r',24),('d',4),
+  ]);
+}
+
+#[test]
+fn complete_ways() {
+  let mut options = SpatialFilter::new(bbox());
+  options.complete_ways = true;
+  // node 3 comes along with way 10, and relation 21 with node 3
+  assert_eq!(run(options), vec![
+    ('n',1),('n',2),('n',3),('w',10),('r',20),('r',21),('r',22),('r',23),('r',24),('d',4),
+  ]);
+}
+
+#[test]
+fn complete_relations() {
+  let mut options = SpatialFilter::new(bbox());
+  options.complete_relations = true;
+  assert_eq!(run(options), vec![
+    ('n',1),('n',2),('n',3),('n',4),('n',5),('w',10),('w',12),
+    ('r',20),('r',22),('r',23),('r',24),('d',4),
+  ]);
+}
+
+#[test]
+fn polygon() {
+  let triangle = Area::Polygon(vec![point(0, 0), point(12, 0), point(0, 12)]);
+  assert!(triangle.contains(&point(1, 1)));
+  assert!(triangle.contains(&point(5, 5)));
+  assert!(!triangle.contains(&point(7, 7)));
+  assert!(!triangle.contains(&point(-1, 1)));
+  assert_eq!(run(SpatialFilter::new(triangle)), vec![
+    ('n',1),('w',10),('r',20),('r',22),('r',24),('d',4),
+  ]);
+}
+
+#[test]
+fn bbox_across_the_antimeridian() {
+  let area = Area::BBox(BBox {
+    x1: Coord(1_700_000_000),
+    y1: Coord(0),
+    x2: Coord(-1_700_000_000),
+    y2: Coord(10),
+  });
+  assert!(area.contains(&point(1_800_000_000, 5)));
+  assert!(area.contains(&point(-1_750_000_000, 5)));
+  assert!(!area.contains(&point(0, 5)));
+  assert!(!area.contains(&point(1_800_000_000, 11)));
+}
+
+#[test]
+fn held_items_come_out_before_a_later_error() {
+  let error = || o5m_stream::parse::unsigned(&[0x80]).unwrap_err();
+  let mut items = sample().into_iter().map(Ok).collect::<Vec<_>>();
+  items.insert(2, Err(error()));
+  items.push(Err(error()));
+  for (complete_ways,complete_relations) in [(true,false),(false,true)] {
+    let mut options = SpatialFilter::new(bbox());
+    options.complete_ways = complete_ways;
+    options.complete_relations = complete_relations;
+    let stream = Box::new(futures::stream::iter(items.iter().map(|item| match item {
+      Ok(data) => Ok(data.clone()),
+      Err(_) => Err(error()),
+    }).collect::<Vec<_>>()));
+    let output = block_on(o5m_stream::filter(stream, options).collect::<Vec<_>>());
+    let ids = output.iter().map(|item| match item {
+      Ok(Dataset::Node(node)) => ('n',node.id),
+      Ok(_) => ('-',0),
+      Err(_) => ('e',0),
+    }).collect::<Vec<_>>();
+    assert_eq!(ids[..4], [('n',1),('n',2),('e',0),('n',3)]);
+    assert_eq!(ids.last(), Some(&('e',0)));
+  }
+}
@@ -0,0 +1,86 @@
+mod common;
+use common::{node,tags,way};
+use futures::{executor::block_on,prelude::*};
+use o5m_stream::{
+  Assembled,Dataset,ElementType,GeoJsonEncoder,GeoJsonFormat,HashMapCache,Relation,RelationData,
+  RelationMember,
+};
+
+// multipolygon relation with way members given by id and role
+fn relation(id: u64, members: &[(u64,&str)]) -> Dataset {
+  let members = members.iter().map(|(id,role)| RelationMember {
+    id: *id,
+    element_type: ElementType::Way(),
+    role: role.to_string(),
+  }).collect();
+  let data = Some(RelationData { members });
+  Dataset::Relation(Relation { id, info: None, data, tags: tags(&[("type","multipolygon")]) })
+}
+
+fn assembled() -> Vec<Assembled> {
+  let items = vec![
+    node(1, 0, 0, &[("name","a \"b\"\n")]),
+    node(2, 40_000_000, 0, &[]),
+    node(3, 40_000_000, 40_000_000, &[]),
+    node(4, 0, 40_000_000, &[]),
+    node(5, 10_000_000, 10_000_000, &[]),
+    node(6, 20_000_000, 10_000_000, &[]),
+    node(7, 20_000_000, 20_000_000, &[]),
+    node(8, 100_000_000, 100_000_000, &[]),
+    node(9, 110_000_000, 100_000_000, &[]),
+    node(10, 110_000_000, 110_000_000, &[]),
+    way(1, &[1,2,3], &[("highway","path")]),
+    way(2, &[3,4,1], &[]),
+    way(3, &[5,6,7,5], &[("building","yes")]),
+    // not every location is known, so it is left out
+    way(4, &[1,99], &[]),
+    way(5, &[8,9,10,8], &[]),
+    relation(1, &[(1,"outer"),(2,"outer"),(3,"inner")]),
+    relation(2, &[(3,"outer"),(5,"outer")]),
+  ];
+  let stream = Box::new(futures::stream::iter(items.into_iter().map(Ok)));
+  let stream = o5m_stream::assemble_ways(stream, Box::new(HashMapCache::new()));
+  let stream = o5m_stream::assemble_multipolygons(stream);
+  block_on(stream.map(|item| item.unwrap()).collect())
+}
+
+fn write(format: GeoJsonFormat) -> String {
+  let mut encoder = GeoJsonEncoder::new(format);
+  let mut buf = vec![];
+  for item in assembled() {
+    encoder.write_item(&item, &mut buf).unwrap();
+  }
+  encoder.end(&mut buf);
+  String::from_utf8(buf).unwrap()
+}
+
+const FEATURES: &[&str] = &[
+  r#"{"type":"Feature","id":"node/1","geometry":{"type":"Point","coordinates":[0,0]},"properties":{"name":"a \"b\"\n"}}"#,
+  r#"{"type":"Feature","id":"node/2","geometry":{"type":"Point","coordinates":[4,0]},"properties":{}}"#,
+  r#"{"type":"Feature","id":"node/3","geometry":{"type":"Point","coordinates":[4,4]},"properties":{}}"#,
+  r#"{"type":"Feature","id":"node/4","geometry":{"type":"Point","coordinates":[0,4]},"properties":{}}"#,
+  r#"{"type":"Feature","id":"node/5","geometry":{"type":"Point","coordinates":[1,1]},"properties":{}}"#,
+  r#"{"type":"Feature","id":"node/6","geometry":{"type":"Point","coordinates":[2,1]},"properties":{}}"#,
+  r#"{"type":"Feature","id":"node/7","geometry":{"type":"Point","coordinates":[2,2]},"properties":{}}"#,
+  r#"{"type":"Feature","id":"node/8","geometry":{"type":"Point","coordinates":[10,10]},"properties":{}}"#,
+  r#"{"type":"Feature","id":"node/9","geometry":{"type":"Point","coordinates":[11,10]},"properties":{}}"#,
+  r#"{"type":"Feature","id":"node/10","geometry":{"type":"Point","coordinates":[11,11]},"properties":{}}"#,
+  r#"{"type":"Feature","id":"way/1","geometry":{"type":"LineString","coordinates":[[0,0],[4,0],[4,4]]},"properties":{"highway":"path"}}"#,
+  r#"{"type":"Feature","id":"way/2","geometry":{"type":"LineString","coordinates":[[4,4],[0,4],[0,0]]},"properties":{}}"#,
+  r#"{"type":"Feature","id":"way/3","geometry":{"type":"Polygon","coordinates":[[[1,1],[2,1],[2,2],[1,1]]]},"properties":{"building":"yes"}}"#,
+  r#"{"type":"Feature","id":"way/5","geometry":{"type":"Polygon","coordinates":[[[10,10],[11,10],[11,11],[10,10]]]},"properties":{}}"#,
+  r#"{"type":"Feature","id":"relation/1","geometry":{"type":"Polygon","coordinates":[[[0,0],[4,0],[4,4],[0,4],[0,0]],[[1,1],[2,2],[2,1],[1,1]]]},"properties":{"type":"multipolygon"}}"#,
+  r#"{"type":"Feature","id":"relation/2","geometry":{"type":"MultiPolygon","coordinates":[[[[1,1],[2,1],[2,2],[1,1]]],[[[10,10],[11,10],[11,11],[10,10]]]]},"properties":{"type":"multipolygon"}}"#,
+];
+
+#[test]
+fn writes_lines() {
+  assert_eq!(write(GeoJsonFormat::Lines()), FEATURES.join("\n") + "\n");
+}
+
+#[test]
+fn writes_feature_collection() {
+  let expected = format!("{{\"type\":\"FeatureCollection\",\"features\":[\n{}\n]}}\n",
+    FEATURES.join(",\n"));
+  assert_eq!(write(GeoJsonFormat::FeatureCollection()), expected);
+}
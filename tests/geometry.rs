@@ -0,0 +1,94 @@
+mod common;
+use common::node;
+use futures::{executor::block_on,prelude::*};
+use o5m_stream::{
+  Assembled,Coord,Dataset,DatasetType,DecodeError,DenseCache,HashMapCache,LocationCache,NodeData,
+  Tags,Way,WayAssembler,WayData,
+};
+
+fn location(lon: i32, lat: i32) -> NodeData {
+  NodeData { longitude: Coord(lon), latitude: Coord(lat) }
+}
+
+#[test]
+fn caches_store_locations() {
+  let mut dense = DenseCache::new();
+  let mut map = HashMapCache::new();
+  for cache in [&mut dense as &mut dyn LocationCache, &mut map] {
+    cache.set(3, &location(-1_800_000_000, 900_000_000)).unwrap();
+    cache.set(1000, &location(0, 0)).unwrap();
+    assert_eq!(cache.get(3), Some(location(-1_800_000_000, 900_000_000)));
+    assert_eq!(cache.get(1000), Some(location(0, 0)));
+    assert_eq!(cache.get(4), None);
+    assert_eq!(cache.get(u64::MAX), None);
+  }
+}
+
+#[test]
+fn dense_cache_rejects_huge_ids() {
+  let mut cache = DenseCache::new();
+  for id in [(-5i64) as u64, u64::MAX, DenseCache::MAX_ID + 1] {
+    let e = cache.set(id, &location(1, 2)).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(cache.get(id), None);
+  }
+  cache.set(7, &location(1, 2)).unwrap();
+  assert_eq!(cache.get(7), Some(location(1, 2)));
+}
+
+fn way(id: u64, refs: Option<&[u64]>) -> Dataset {
+  let data = refs.map(|refs| WayData { refs: refs.to_vec() });
+  Dataset::Way(Way { id, info: None, data, tags: Tags::new() })
+}
+
+#[test]
+fn assembles_ways() {
+  let mut assembler = WayAssembler::new(Box::new(DenseCache::new()));
+  for data in [node(1, 10, 20, &[]), node(2, 30, 40, &[]), node(3, 50, 60, &[])] {
+    assert_eq!(assembler.assemble(data.clone()).unwrap(), Assembled::Dataset(data));
+  }
+  let geometry = match assembler.assemble(way(7, Some(&[1,2,9,3,1]))).unwrap() {
+    Assembled::Way(geometry) => geometry,
+    x => panic!("expected a way, got {:?}", x),
+  };
+  let (a,b,c) = (Some(location(10, 20)),Some(location(30, 40)),Some(location(50, 60)));
+  assert_eq!(geometry.points, vec![a.clone(),b,None,c,a]);
+  assert_eq!(geometry.unresolved(), vec![9]);
+  assert!(!geometry.is_complete());
+  assert!(geometry.is_closed());
+  // ways without refs, such as deleted ones, pass through
+  let deleted = way(8, None);
+  assert_eq!(assembler.assemble(deleted.clone()).unwrap(), Assembled::Dataset(deleted));
+}
+
+#[test]
+fn assemble_ways_stream() {
+  let items: Vec<Result<Dataset,DecodeError>> = vec![
+    Ok(node(1, 10, 20, &[])),
+    Ok(node(2, 30, 40, &[])),
+    Ok(node(u64::MAX, 0, 0, &[])),
+    Ok(way(5, Some(&[1,2]))),
+  ];
+  let stream = o5m_stream::assemble_ways(
+    Box::new(futures::stream::iter(items)),
+    Box::new(DenseCache::new()),
+  );
+  let items = block_on(stream.collect::<Vec<_>>());
+  assert_eq!(items.len(), 4);
+  // the cache error for the huge id is reported and the stream goes on
+  match &items[2] {
+    Err(e@DecodeError::LocationCacheError { id, .. }) => {
+      assert_eq!(*id, u64::MAX);
+      assert_eq!(e.context().data_type, Some(DatasetType::Node()));
+      assert_eq!(e.context().last_id, Some(2));
+    },
+    x => panic!("expected a cache error, got {:?}", x),
+  }
+  match &items[3] {
+    Ok(Assembled::Way(geometry)) => {
+      assert!(geometry.is_complete());
+      assert!(!geometry.is_closed());
+    },
+    x => panic!("expected a way, got {:?}", x),
+  }
+}
@@ -0,0 +1,108 @@
+mod common;
+use common::{node,way};
+use futures::{executor::block_on,prelude::*};
+use o5m_stream::{
+  Assembled,Dataset,ElementType,HashMapCache,InvalidRing,MultipolygonGeometry,NodeData,Relation,
+  RelationData,RelationMember,Tags,
+};
+
+fn relation(id: u64, area_type: Option<&str>, members: &[(u64,&str)]) -> Dataset {
+  let members = members.iter().map(|(id,role)| RelationMember {
+    id: *id,
+    element_type: ElementType::Way(),
+    role: role.to_string(),
+  }).collect();
+  let mut tags = Tags::new();
+  if let Some(t) = area_type { tags.insert("type".to_string(), t.to_string()); }
+  Dataset::Relation(Relation { id, info: None, data: Some(RelationData { members }), tags })
+}
+
+fn assemble(items: Vec<Dataset>) -> Vec<Assembled> {
+  let stream = Box::new(futures::stream::iter(items.into_iter().map(Ok)));
+  let stream = o5m_stream::assemble_ways(stream, Box::new(HashMapCache::new()));
+  let stream = o5m_stream::assemble_multipolygons(stream);
+  block_on(stream.map(|item| item.unwrap()).collect())
+}
+
+// twice the signed area, positive for counter-clockwise rings
+fn area2(points: &[NodeData]) -> i64 {
+  points.windows(2).map(|w| {
+    (w[0].longitude.0 as i64)*(w[1].latitude.0 as i64)
+      - (w[1].longitude.0 as i64)*(w[0].latitude.0 as i64)
+  }).sum()
+}
+
+fn sample() -> Vec<Dataset> {
+  vec![
+    // outer square, split into two ways
+    node(1, 0, 0, &[]), node(2, 100, 0, &[]), node(3, 100, 100, &[]), node(4, 0, 100, &[]),
+    // inner square, counter-clockwise
+    node(5, 10, 10, &[]), node(6, 20, 10, &[]), node(7, 20, 20, &[]), node(8, 10, 20, &[]),
+    // second outer ring, a clockwise triangle
+    node(9, 200, 200, &[]), node(10, 200, 300, &[]), node(11, 300, 200, &[]),
+    // inner ring outside of both outer rings
+    node(12, 500, 500, &[]), node(13, 510, 500, &[]), node(14, 510, 510, &[]),
+    node(15, 0, 0, &[]), node(16, 1, 1, &[]),
+    way(1, &[1,2,3], &[]),
+    way(2, &[1,4,3], &[]),
+    way(3, &[5,6,7,8,5], &[]),
+    way(4, &[9,10,11,9], &[]),
+    way(5, &[12,13,14,12], &[]),
+    way(6, &[15,16], &[]),
+    relation(1, Some("multipolygon"), &[
+      (1,"outer"),(2,"outer"),(4,""),(99,"outer"),(6,"outer"),(3,"inner"),(5,"inner"),(1,"label"),
+    ]),
+    relation(2, None, &[(1,"outer")]),
+  ]
+}
+
+#[test]
+fn assembles_multipolygons() {
+  let items = assemble(sample());
+  assert_eq!(items.len(), 24);
+  let MultipolygonGeometry { relation, polygons, invalid } = match &items[22] {
+    Assembled::Multipolygon(geometry) => geometry.clone(),
+    x => panic!("expected a multipolygon, got {:?}", x),
+  };
+  assert_eq!(relation.id, 1);
+  assert_eq!(polygons.len(), 2);
+
+  let square = &polygons[0];
+  assert_eq!(square.outer.ways, vec![1,2]);
+  assert_eq!(square.outer.points.len(), 5);
+  assert_eq!(square.outer.points.first(), square.outer.points.last());
+  assert_eq!(area2(&square.outer.points), 2 * 100 * 100);
+  assert_eq!(square.inners.len(), 1);
+  assert_eq!(square.inners[0].ways, vec![3]);
+  assert_eq!(area2(&square.inners[0].points), -2 * 10 * 10);
+
+  let triangle = &polygons[1];
+  assert_eq!(triangle.outer.ways, vec![4]);
+  assert!(area2(&triangle.outer.points) > 0);
+  assert!(triangle.inners.is_empty());
+
+  assert_eq!(invalid.len(), 3);
+  assert_eq!(invalid[0], InvalidRing::MissingWay(99));
+  assert!(matches!(&invalid[1], InvalidRing::Unclosed(ring) if ring.ways == vec![6]));
+  assert!(matches!(&invalid[2], InvalidRing::UnmatchedInner(ring) if ring.ways == vec![5]));
+
+  // relations without an area type pass through
+  assert!(matches!(&items[23], Assembled::Dataset(Dataset::Relation(r)) if r.id == 2));
+}
+
+#[test]
+fn degenerate_rings() {
+  let items = assemble(vec![
+    node(1, 0, 0, &[]), node(2, 10, 0, &[]),
+    way(1, &[1,2,1], &[]),
+    relation(1, Some("boundary"), &[(1,"outer")]),
+  ]);
+  match &items[3] {
+    Assembled::Multipolygon(geometry) => {
+      assert!(geometry.polygons.is_empty());
+      let invalid = &geometry.invalid[..];
+      assert!(matches!(invalid, [InvalidRing::Degenerate(ring)] if ring.ways == vec![1]));
+    },
+    x => panic!("expected a multipolygon, got {:?}", x),
+  }
+}
@@ -0,0 +1,198 @@
+use o5m_stream::{
+  Coord,Dataset,Decoder,DecodeError,DecoderOptions,ElementType,Encoder,EncoderOptions,Info,Node,
+  NodeData,ParallelDecoder,Relation,RelationData,RelationMember,Tags,Way,WayData,
+};
+
+// enough input for several chunks, with a reset every 100 elements
+fn sample() -> Vec<u8> {
+  let mut encoder = Encoder::with_options(EncoderOptions { reset_interval: Some(100) });
+  let mut buf = vec![];
+  for id in 1..20_000 {
+    let mut tags = Tags::new();
+    tags.insert("name".to_string(), format!("node {}", id % 300));
+    let node = Dataset::Node(Node {
+      id,
+      info: None,
+      data: Some(NodeData { longitude: Coord(id as i32), latitude: Coord(-(id as i32)) }),
+      tags,
+    });
+    encoder.write_dataset(&node, &mut buf).unwrap();
+  }
+  for id in 1..1000 {
+    let way = Dataset::Way(Way {
+      id,
+      info: None,
+      data: Some(WayData { refs: (id..id+20).collect() }),
+      tags: Tags::new(),
+    });
+    encoder.write_dataset(&way, &mut buf).unwrap();
+  }
+  buf
+}
+
+// sections of several megabytes without resets, with author information and tags that refer
+// back to strings from far earlier in the section
+fn long_sections() -> Vec<u8> {
+  let info = |id: u64| Some(Info {
+    version: Some(id % 3 + 1),
+    timestamp: Some(1_600_000_000 + (id as i64) * 7),
+    changeset: Some(1000 + id / 10),
+    uid: Some(id % 50),
+    user: Some(format!("user {}", id % 50)),
+  });
+  let tags = |id: u64| -> Tags {
+    vec![("name".to_string(),format!("n{}", id % 20_000))].into_iter().collect()
+  };
+  let mut encoder = Encoder::new();
+  let mut buf = vec![];
+  for id in 1..100_000 {
+    encoder.write_dataset(&Dataset::Node(Node {
+      id: id * 3,
+      info: info(id),
+      data: Some(NodeData {
+        longitude: Coord((id * 7919 % 100_000) as i32),
+        latitude: Coord(-((id * 104_729 % 100_000) as i32)),
+      }),
+      tags: tags(id),
+    }), &mut buf).unwrap();
+  }
+  for id in 1..30_000 {
+    encoder.write_dataset(&Dataset::Way(Way {
+      id,
+      info: info(id),
+      data: Some(WayData { refs: (id*3..id*3+20).collect() }),
+      tags: tags(id),
+    }), &mut buf).unwrap();
+  }
+  for id in 1..30_000 {
+    let members = (0..10).map(|i| RelationMember {
+      id: id + i,
+      element_type: ElementType::Way(),
+      role: format!("r{}", (id + i) % 30),
+    }).collect();
+    encoder.write_dataset(&Dataset::Relation(Relation {
+      id,
+      info: info(id),
+      data: Some(RelationData { members }),
+      tags: tags(id),
+    }), &mut buf).unwrap();
+  }
+  buf
+}
+
+// errors compare by their message without the backtrace that the backtrace feature adds
+fn collect<I: Iterator<Item=o5m_stream::DecodeItem>>(items: I) -> Vec<Result<Dataset,String>> {
+  items.map(|item| item.map_err(|e| e.to_string().lines().next().unwrap_or("").into())).collect()
+}
+
+#[test]
+fn matches_decoder() {
+  let buf = sample();
+  assert!(buf.len() > 4 << 16);
+  let expected = collect(Decoder::new(&buf[..]));
+  assert_eq!(expected.len(), 20_999);
+  for threads in [0,1,2,5] {
+    assert_eq!(collect(ParallelDecoder::new(&buf[..], threads)), expected, "{} threads", threads);
+  }
+}
+
+#[test]
+fn matches_decoder_on_corrupt_input() {
+  let buf = sample();
+  for i in (1..buf.len()).step_by(buf.len() / 12) {
+    let mut bad = buf.clone();
+    bad[i] ^= 0x55;
+    for lenient in [false,true] {
+      let options = DecoderOptions { lenient, ..Default::default() };
+      assert_eq!(
+        collect(ParallelDecoder::with_options(&bad[..], 3, options.clone())),
+        collect(Decoder::with_options(&bad[..], options)),
+        "byte {} lenient {}", i, lenient,
+      );
+    }
+  }
+}
+
+#[test]
+fn splits_sections_without_resets() {
+  let buf = long_sections();
+  assert!(buf.len() > 5_000_000);
+  let expected = collect(Decoder::new(&buf[..]));
+  assert_eq!(expected.len(), 159_998);
+  for threads in [1,3] {
+    assert_eq!(collect(ParallelDecoder::new(&buf[..], threads)), expected, "{} threads", threads);
+  }
+  let mut bad = buf.clone();
+  bad[buf.len() / 2] ^= 0x55;
+  // the same with the frames that are skipped ending at an end-of-file byte
+  let mut ended = bad.clone();
+  ended.extend_from_slice(&[0xfe, 0x10]);
+  for bad in [bad,ended] {
+    for lenient in [false,true] {
+      let options = DecoderOptions { lenient, ..Default::default() };
+      assert_eq!(
+        collect(ParallelDecoder::with_options(&bad[..], 3, options.clone())),
+        collect(Decoder::with_options(&bad[..], options)),
+        "lenient {}", lenient,
+      );
+    }
+  }
+}
+
+#[test]
+fn huge_length_is_an_error() {
+  let mut buf = sample();
+  let end = buf.len();
+  // a node whose length claims 4GB
+  buf.extend_from_slice(&[0x10, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+  buf.extend_from_slice(&[0; 1000]);
+  for lenient in [false,true] {
+    let options = DecoderOptions { lenient, ..Default::default() };
+    let items = ParallelDecoder::with_options(&buf[..], 2, options).collect::<Vec<_>>();
+    assert_eq!(items.len(), 21_000);
+    assert!(items[..20_999].iter().all(|item| item.is_ok()));
+    match &items[20_999] {
+      Err(e@DecodeError::ChunkTooLarge { .. }) => {
+        assert_eq!(e.context().offset, Some(end as u64));
+      },
+      x => panic!("unexpected {:?}", x),
+    }
+  }
+}
+
+#[test]
+fn stops_early() {
+  let buf = sample();
+  let mut decoder = ParallelDecoder::new(&buf[..], 2);
+  assert!(decoder.next().unwrap().is_ok());
+  // dropping the decoder with chunks still in flight shuts the workers down
+  drop(decoder);
+}
+
+#[test]
+fn lenient_resyncs_after_a_long_section() {
+  let mut buf = sample();
+  // a node that refers to a string that was never added, then more frames without a reset
+  // than fit in one chunk
+  buf.extend_from_slice(&[0xff, 0x10, 0x05, 0x02, 0x00, 0x00, 0x00, 0x05]);
+  let filler = [&[0x20, 0x88, 0x27][..], &[0; 5000][..]].concat();
+  while buf.len() < 70 << 20 { buf.extend_from_slice(&filler) }
+  let mut encoder = Encoder::new();
+  encoder.begin(&mut vec![]);
+  encoder.reset(&mut buf);
+  for id in 1..10 {
+    encoder.write_dataset(&Dataset::Way(Way {
+      id,
+      info: None,
+      data: Some(WayData { refs: vec![id,id+1] }),
+      tags: Tags::new(),
+    }), &mut buf).unwrap();
+  }
+  for lenient in [false,true] {
+    let options = DecoderOptions { lenient, ..Default::default() };
+    let items = collect(ParallelDecoder::with_options(&buf[..], 3, options.clone()));
+    assert_eq!(items, collect(Decoder::with_options(&buf[..], options)), "lenient {}", lenient);
+    assert!(items.iter().all(|item| !matches!(item, Err(e) if e.contains("no frame boundary"))), "{:?}",
+      items.iter().filter(|item| item.is_err()).collect::<Vec<_>>());
+  }
+}
@@ -0,0 +1,276 @@
+#![cfg(feature="pbf")]
+mod common;
+use common::{sample,tags};
+use futures::{executor::block_on,prelude::*};
+use o5m_stream::{
+  BBox,Coord,Dataset,DecodeError,DecoderOptions,Delete,ElementType,EncodeError,Header,Info,Node,
+  NodeData,PbfDecoder,PbfEncoder,Relation,Tags,Way,
+};
+
+fn varint(buf: &mut Vec<u8>, mut x: u64) {
+  while x >= 0x80 {
+    buf.push((x as u8) | 0x80);
+    x >>= 7;
+  }
+  buf.push(x as u8);
+}
+
+fn zigzag(x: i64) -> u64 {
+  ((x << 1) ^ (x >> 63)) as u64
+}
+
+// length-delimited field `n`
+fn field(buf: &mut Vec<u8>, n: u64, bytes: &[u8]) {
+  varint(buf, (n << 3) | 2);
+  varint(buf, bytes.len() as u64);
+  buf.extend_from_slice(bytes);
+}
+
+fn packed(values: &[u64]) -> Vec<u8> {
+  let mut buf = vec![];
+  for x in values { varint(&mut buf, *x) }
+  buf
+}
+
+// one uncompressed blob of `kind` holding `block`
+fn blob(kind: &str, block: &[u8]) -> Vec<u8> {
+  let mut data = vec![];
+  field(&mut data, 1, block);
+  let mut header = vec![];
+  field(&mut header, 1, kind.as_bytes());
+  varint(&mut header, 3 << 3);
+  varint(&mut header, data.len() as u64);
+  let mut buf = (header.len() as u32).to_be_bytes().to_vec();
+  buf.extend_from_slice(&header);
+  buf.extend_from_slice(&data);
+  buf
+}
+
+fn header_block(features: &[&str]) -> Vec<u8> {
+  let mut block = vec![];
+  for feature in features { field(&mut block, 4, feature.as_bytes()) }
+  blob("OSMHeader", &block)
+}
+
+// dense nodes 1, 2 and 3 with a version for each of `versions`
+fn dense_nodes(versions: &[u64]) -> Vec<u8> {
+  dense_nodes_with(versions, &[0,0,0])
+}
+
+// dense nodes like `dense_nodes`, with these zigzag encoded timestamp deltas
+fn dense_nodes_with(versions: &[u64], timestamps: &[u64]) -> Vec<u8> {
+  let mut info = vec![];
+  field(&mut info, 1, &packed(versions));
+  field(&mut info, 2, &packed(timestamps));
+  for n in 3..=5 { field(&mut info, n, &packed(&[0,0,0])) }
+  let mut dense = vec![];
+  field(&mut dense, 1, &packed(&[zigzag(1),zigzag(1),zigzag(1)]));
+  field(&mut dense, 5, &info);
+  field(&mut dense, 8, &packed(&[0,0,0]));
+  field(&mut dense, 9, &packed(&[0,0,0]));
+  dense
+}
+
+// a primitive block with a group for each of `groups` of dense nodes
+fn data_block(groups: &[Vec<u8>]) -> Vec<u8> {
+  data_block_with(groups, &[])
+}
+
+// a primitive block like `data_block`, with integer fields such as the granularity
+fn data_block_with(groups: &[Vec<u8>], ints: &[(u64,u64)]) -> Vec<u8> {
+  let mut block = vec![];
+  field(&mut block, 1, &[]);
+  for (n,x) in ints {
+    varint(&mut block, n << 3);
+    varint(&mut block, *x);
+  }
+  for dense in groups {
+    let mut group = vec![];
+    field(&mut group, 2, dense);
+    field(&mut block, 2, &group);
+  }
+  blob("OSMData", &block)
+}
+
+fn dense_block(versions: &[u64]) -> Vec<u8> {
+  data_block(&[dense_nodes(versions)])
+}
+
+fn node_ids(items: &[Result<Dataset,String>]) -> Vec<u64> {
+  items.iter().filter_map(|item| match item {
+    Ok(Dataset::Node(node)) => Some(node.id),
+    _ => None,
+  }).collect()
+}
+
+fn decode(buf: &[u8], lenient: bool) -> Vec<Result<Dataset,String>> {
+  let options = DecoderOptions { lenient, ..Default::default() };
+  PbfDecoder::with_options(buf, options).map(|item| item.map_err(|e| e.to_string())).collect()
+}
+
+#[test]
+fn dense_info_with_short_versions_is_an_error() {
+  let mut buf = header_block(&["OsmSchema-V0.6","DenseNodes"]);
+  buf.extend(dense_block(&[1,1]));
+  buf.extend(dense_block(&[1,2,3]));
+  let items = decode(&buf, true);
+  assert_eq!(items.iter().filter(|item| item.is_err()).count(), 1);
+  assert_eq!(node_ids(&items), vec![1,2,3]);
+}
+
+#[test]
+fn historical_information_keeps_the_o5m_header() {
+  let mut buf = header_block(&["OsmSchema-V0.6","DenseNodes","HistoricalInformation"]);
+  buf.extend(dense_block(&[1,2,3]));
+  let items = PbfDecoder::new(buf.as_slice()).collect::<Result<Vec<_>,_>>().unwrap();
+  assert_eq!(items[0], Dataset::Header(Header { format: "o5m2".to_string() }));
+  assert_eq!(items.len(), 4);
+}
+
+#[test]
+fn failed_block_comes_after_the_header_without_its_elements() {
+  let mut truncated = dense_nodes(&[1,2,3]);
+  truncated.truncate(truncated.len() - 2);
+  let mut buf = data_block(&[dense_nodes(&[1,2,3]),truncated]);
+  buf.extend(dense_block(&[4,5,6]));
+  let items = decode(&buf, true);
+  assert_eq!(items[0], Ok(Dataset::Header(Header { format: "o5m2".to_string() })));
+  assert!(items[1].is_err());
+  assert_eq!(items.len(), 5);
+  assert_eq!(node_ids(&items), vec![1,2,3]);
+  match &items[2] {
+    Ok(Dataset::Node(node)) => assert_eq!(node.info.as_ref().unwrap().version, Some(4)),
+    x => panic!("unexpected {:?}", x),
+  }
+}
+
+#[test]
+fn strict_error_ends_decoding() {
+  let mut buf = dense_block(&[1,2,3]);
+  buf.extend(dense_block(&[1,1]));
+  buf.extend(dense_block(&[1,2,3]));
+  let items = decode(&buf, false);
+  assert_eq!(items.len(), 5);
+  assert_eq!(node_ids(&items), vec![1,2,3]);
+  assert!(items[4].is_err());
+  let stream = o5m_stream::decode_pbf(Box::new(futures::io::Cursor::new(buf)));
+  let items = block_on(stream.collect::<Vec<_>>());
+  assert_eq!(items.len(), 5);
+  assert!(items[4].is_err());
+}
+
+#[test]
+fn huge_offsets_and_granularity_are_errors() {
+  // a latitude offset that overflows when added to the coordinates, and a date granularity
+  // whose product with the timestamp overflows
+  let blocks = [
+    data_block_with(&[dense_nodes_with(&[1,2,3], &[0,0,0])], &[(19,i64::MAX as u64)]),
+    data_block_with(&[dense_nodes_with(&[1,2,3], &[zigzag(7),0,0])], &[(18,i64::MAX as u64)]),
+  ];
+  for (n,buf) in blocks.iter().enumerate() {
+    let items = PbfDecoder::new(buf.as_slice()).collect::<Vec<_>>();
+    assert_eq!(items.len(), 2, "block {}", n);
+    assert!(matches!(items[1], Err(DecodeError::InvalidPbf { .. })), "block {}", n);
+  }
+}
+
+fn round_trip(items: &[Dataset]) -> Vec<Dataset> {
+  let mut encoder = PbfEncoder::new();
+  let mut buf = vec![];
+  for data in items {
+    encoder.write_dataset(data, &mut buf).unwrap();
+  }
+  encoder.end(&mut buf);
+  PbfDecoder::new(buf.as_slice()).collect::<Result<Vec<_>,_>>().unwrap()
+}
+
+#[test]
+fn round_trip_osm() {
+  let items = sample("o5m2");
+  assert_eq!(round_trip(&items), items);
+}
+
+#[test]
+fn round_trip_deletes() {
+  let mut items = sample("o5c2");
+  items.push(Dataset::Delete(Delete {
+    id: 4,
+    element_type: ElementType::Way(),
+    info: Some(Info { version: Some(3), ..Info::new() }),
+  }));
+  assert_eq!(round_trip(&items), items);
+}
+
+#[test]
+fn round_trip_headers() {
+  for format in ["o5m2","o5c2"] {
+    let items = vec![Dataset::Header(Header { format: format.to_string() })];
+    assert_eq!(round_trip(&items), items);
+  }
+}
+
+#[test]
+fn deletes_need_an_o5c_header() {
+  let delete = Dataset::Delete(Delete { id: 4, element_type: ElementType::Way(), info: None });
+  for items in [vec![],vec![Dataset::Header(Header { format: "o5m2".to_string() })]] {
+    let mut encoder = PbfEncoder::new();
+    let mut buf = vec![];
+    for data in &items {
+      encoder.write_dataset(data, &mut buf).unwrap();
+    }
+    let result = encoder.write_dataset(&delete, &mut buf);
+    assert!(matches!(result, Err(EncodeError::UnsupportedDataset { .. })));
+  }
+}
+
+#[test]
+fn elements_without_data_are_unsupported() {
+  let items = vec![
+    Dataset::Node(Node { id: 1, data: None, info: None, tags: Tags::new() }),
+    Dataset::Way(Way { id: 2, data: None, info: None, tags: Tags::new() }),
+    Dataset::Relation(Relation { id: 3, data: None, info: None, tags: Tags::new() }),
+  ];
+  for data in &items {
+    let mut encoder = PbfEncoder::new();
+    let mut buf = vec![];
+    let result = encoder.write_dataset(data, &mut buf);
+    assert!(matches!(result, Err(EncodeError::UnsupportedDataset { .. })));
+  }
+}
+
+#[test]
+fn round_trip_empty_tags() {
+  let node = |id, pairs: &[(&str,&str)]| Dataset::Node(Node {
+    id,
+    info: None,
+    data: Some(NodeData { longitude: Coord(1), latitude: Coord(2) }),
+    tags: tags(pairs),
+  });
+  // an empty key or value does not end the tags of its node early
+  let items = vec![
+    Dataset::Header(Header { format: "o5m2".to_string() }),
+    node(1, &[("","x"),("a","b")]),
+    node(2, &[("note","")]),
+    node(3, &[("c","d")]),
+  ];
+  assert_eq!(round_trip(&items), items);
+}
+
+#[test]
+fn header_bbox_rounds_to_the_nearest_unit() {
+  let mut bbox = vec![];
+  for (n,x) in [(1,-149),(2,149),(3,-151),(4,-50)] {
+    varint(&mut bbox, n << 3);
+    varint(&mut bbox, zigzag(x));
+  }
+  let mut block = vec![];
+  field(&mut block, 1, &bbox);
+  field(&mut block, 4, b"OsmSchema-V0.6");
+  let items = decode(&blob("OSMHeader", &block), false);
+  assert!(items.contains(&Ok(Dataset::BBox(BBox {
+    x1: Coord(-1),
+    y1: Coord(0),
+    x2: Coord(1),
+    y2: Coord(-2),
+  }))), "{:?}", items);
+}
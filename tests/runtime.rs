@@ -0,0 +1,129 @@
+// the async decode and encode paths on runtimes other than async-std
+mod common;
+use common::sample;
+use futures::{executor::block_on,prelude::*};
+use std::pin::Pin;
+use std::sync::{Arc,Mutex};
+use std::task::{Context,Poll};
+
+// writer that leaves its output where the test can read it after the encoder drops the writer
+#[derive(Clone,Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Shared {
+  fn bytes(&self) -> Vec<u8> {
+    self.0.lock().unwrap().clone()
+  }
+}
+
+impl futures::io::AsyncWrite for Shared {
+  fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8])
+  -> Poll<std::io::Result<usize>> {
+    self.0.lock().unwrap().extend_from_slice(buf);
+    Poll::Ready(Ok(buf.len()))
+  }
+  fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    Poll::Ready(Ok(()))
+  }
+  fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    Poll::Ready(Ok(()))
+  }
+}
+
+#[cfg(feature="tokio")]
+impl tokio::io::AsyncWrite for Shared {
+  fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8])
+  -> Poll<std::io::Result<usize>> {
+    self.0.lock().unwrap().extend_from_slice(buf);
+    Poll::Ready(Ok(buf.len()))
+  }
+  fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    Poll::Ready(Ok(()))
+  }
+  fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    Poll::Ready(Ok(()))
+  }
+}
+
+#[test]
+fn futures_round_trip() {
+  let items = sample("o5m2");
+  let writer = Shared::default();
+  let stream = Box::new(futures::stream::iter(items.clone()));
+  block_on(o5m_stream::encode(stream, Box::new(writer.clone()))).unwrap();
+  let reader = futures::io::Cursor::new(writer.bytes());
+  let decoded = block_on(o5m_stream::decode(Box::new(reader)).collect::<Vec<_>>());
+  assert_eq!(decoded.into_iter().collect::<Result<Vec<_>,_>>().unwrap(), items);
+}
+
+#[cfg(feature="tokio")]
+#[tokio::test]
+async fn tokio_round_trip() {
+  let items = sample("o5m2");
+  let writer = Shared::default();
+  let stream = Box::new(futures::stream::iter(items.clone()));
+  o5m_stream::encode_tokio(stream, Box::new(writer.clone())).await.unwrap();
+  let reader = std::io::Cursor::new(writer.bytes());
+  let decoded = o5m_stream::decode_tokio(Box::new(reader)).collect::<Vec<_>>().await;
+  assert_eq!(decoded.into_iter().collect::<Result<Vec<_>,_>>().unwrap(), items);
+}
+
+// reader that fails with `Interrupted` before every read and returns a few bytes at a time
+struct Interrupting {
+  data: Vec<u8>,
+  interrupt: bool,
+}
+
+impl futures::io::AsyncRead for Interrupting {
+  fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut [u8])
+  -> Poll<std::io::Result<usize>> {
+    self.interrupt = !self.interrupt;
+    if self.interrupt {
+      return Poll::Ready(Err(std::io::ErrorKind::Interrupted.into()));
+    }
+    let n = buf.len().min(self.data.len()).min(7);
+    buf[..n].copy_from_slice(&self.data[..n]);
+    self.data.drain(..n);
+    Poll::Ready(Ok(n))
+  }
+}
+
+#[test]
+fn decode_retries_interrupted_reads() {
+  let items = sample("o5m2");
+  let writer = Shared::default();
+  let stream = Box::new(futures::stream::iter(items.clone()));
+  block_on(o5m_stream::encode(stream, Box::new(writer.clone()))).unwrap();
+  let reader = Interrupting { data: writer.bytes(), interrupt: false };
+  let decoded = block_on(o5m_stream::decode(Box::new(reader)).collect::<Vec<_>>());
+  assert_eq!(decoded.into_iter().collect::<Result<Vec<_>,_>>().unwrap(), items);
+}
+
+#[test]
+fn decode_xml_retries_interrupted_reads() {
+  let items = sample("o5m2");
+  let mut encoder = o5m_stream::XmlEncoder::new();
+  let mut buf = vec![];
+  for data in &items {
+    encoder.write_dataset(data, &mut buf).unwrap();
+  }
+  encoder.end(&mut buf);
+  let reader = Interrupting { data: buf, interrupt: false };
+  let decoded = block_on(o5m_stream::decode_xml(Box::new(reader)).collect::<Vec<_>>());
+  assert_eq!(decoded.into_iter().collect::<Result<Vec<_>,_>>().unwrap(), items);
+}
+
+#[cfg(feature="pbf")]
+#[test]
+fn decode_pbf_retries_interrupted_reads() {
+  let items = sample("o5m2");
+  let mut encoder = o5m_stream::PbfEncoder::new();
+  let mut buf = vec![];
+  for data in &items {
+    encoder.write_dataset(data, &mut buf).unwrap();
+  }
+  encoder.end(&mut buf);
+  let reader = Interrupting { data: buf, interrupt: false };
+  let decoded = block_on(o5m_stream::decode_pbf(Box::new(reader)).collect::<Vec<_>>());
+  assert_eq!(decoded.into_iter().collect::<Result<Vec<_>,_>>().unwrap(), items);
+}
@@ -0,0 +1,140 @@
+mod common;
+use common::encode;
+use o5m_stream::{
+  Coord,Dataset,Decoder,ElementType,Encoder,Node,NodeData,Relation,RelationData,RelationMember,
+  Way,WayData,
+};
+use std::io::Cursor;
+
+// a node whose tags refer to a string that was never added to the table
+const BAD_NODE: &[u8] = &[0x10, 0x05, 0x02, 0x00, 0x00, 0x00, 0x05];
+
+fn nodes(ids: std::ops::Range<u64>) -> Vec<Dataset> {
+  ids.map(|id| Dataset::Node(Node {
+    id,
+    info: None,
+    data: Some(NodeData { longitude: Coord(id as i32), latitude: Coord(-(id as i32)) }),
+    tags: vec![("name".to_string(),format!("node {}", id))].into_iter().collect(),
+  })).collect()
+}
+
+fn ways(ids: std::ops::Range<u64>) -> Vec<Dataset> {
+  ids.map(|id| Dataset::Way(Way {
+    id,
+    info: None,
+    data: Some(WayData { refs: vec![id,id+1] }),
+    tags: vec![("highway".to_string(),"path".to_string())].into_iter().collect(),
+  })).collect()
+}
+
+fn relations(ids: std::ops::Range<u64>) -> Vec<Dataset> {
+  ids.map(|id| Dataset::Relation(Relation {
+    id,
+    info: None,
+    data: Some(RelationData {
+      members: vec![RelationMember { id, element_type: ElementType::Way(), role: "outer".into() }],
+    }),
+    tags: vec![("type".to_string(),"multipolygon".to_string())].into_iter().collect(),
+  })).collect()
+}
+
+// a reset followed by the encoded elements, without the header an encoder starts with
+fn elements(items: &[Dataset]) -> Vec<u8> {
+  let mut encoder = Encoder::new();
+  encoder.begin(&mut vec![]);
+  let mut buf = vec![0xff];
+  for dataset in items { encoder.write_dataset(dataset, &mut buf).unwrap() }
+  buf
+}
+
+fn jump(forward: u32, backward: u32) -> Vec<u8> {
+  let mut buf = vec![0xef, 0x08];
+  buf.extend_from_slice(&forward.to_be_bytes());
+  buf.extend_from_slice(&backward.to_be_bytes());
+  buf
+}
+
+fn header() -> Vec<u8> {
+  vec![0xff, 0xe0, 0x04, b'o', b'5', b'm', b'2']
+}
+
+// header, then each section behind a jump dataset that points to the next one. `forward`
+// overrides the distance stored in a section's jump.
+fn with_jumps(sections: &[Vec<u8>], forward: &[Option<u32>]) -> Vec<u8> {
+  let mut buf = header();
+  let mut backward = 0;
+  for (i,section) in sections.iter().enumerate() {
+    let len = (10 + section.len()) as u32;
+    let next = if i + 1 < sections.len() { len } else { 0 };
+    buf.extend(jump(forward.get(i).cloned().flatten().unwrap_or(next), backward));
+    buf.extend_from_slice(section);
+    backward = len;
+  }
+  buf
+}
+
+fn rest(decoder: &mut Decoder<Cursor<Vec<u8>>>) -> Vec<Dataset> {
+  decoder.collect::<Result<Vec<_>,_>>().unwrap()
+}
+
+#[test]
+fn follows_jumps_to_ways_and_relations() {
+  // only the first node of the section is valid, so scanning past it would fail
+  let mut node_section = elements(&nodes(1..2));
+  for _ in 0..20 { node_section.extend_from_slice(BAD_NODE) }
+  let mut way_section = elements(&ways(1..2));
+  for _ in 0..20 { way_section.extend_from_slice(BAD_NODE) }
+  let sections = vec![node_section,way_section,elements(&relations(1..4))];
+  let buf = with_jumps(&sections, &[]);
+
+  let mut decoder = Decoder::new(Cursor::new(buf.clone()));
+  assert!(decoder.seek_to(ElementType::Way()).unwrap());
+  assert_eq!(decoder.next_item().unwrap(), Some(ways(1..2).remove(0)));
+
+  let mut decoder = Decoder::new(Cursor::new(buf));
+  assert!(decoder.seek_to(ElementType::Relation()).unwrap());
+  assert_eq!(rest(&mut decoder), relations(1..4));
+}
+
+#[test]
+fn ignores_jumps_past_the_end() {
+  let sections = vec![elements(&nodes(1..30)),elements(&ways(1..5)),elements(&relations(1..3))];
+  let buf = with_jumps(&sections, &[Some(1_000_000)]);
+  let mut decoder = Decoder::new(Cursor::new(buf));
+  assert!(decoder.seek_to(ElementType::Way()).unwrap());
+  let mut expected = ways(1..5);
+  expected.extend(relations(1..3));
+  assert_eq!(rest(&mut decoder), expected);
+}
+
+#[test]
+fn ignores_jumps_between_frames() {
+  let sections = vec![elements(&nodes(1..30)),elements(&ways(1..5)),elements(&relations(1..3))];
+  // lands on the length of the first way, past the next jump and reset
+  let forward = (10 + sections[0].len() + 12) as u32;
+  let buf = with_jumps(&sections, &[Some(forward)]);
+  let mut decoder = Decoder::new(Cursor::new(buf));
+  assert!(decoder.seek_to(ElementType::Way()).unwrap());
+  let mut expected = ways(1..5);
+  expected.extend(relations(1..3));
+  assert_eq!(rest(&mut decoder), expected);
+}
+
+#[test]
+fn scans_without_jumps() {
+  let mut items = nodes(1..30);
+  items.extend(ways(1..5));
+  items.extend(relations(1..3));
+  let buf = encode(&items);
+
+  let mut decoder = Decoder::new(Cursor::new(buf.clone()));
+  assert!(decoder.seek_to(ElementType::Way()).unwrap());
+  assert_eq!(decoder.next_item().unwrap(), Some(ways(1..2).remove(0)));
+  assert!(decoder.seek_to(ElementType::Relation()).unwrap());
+  assert_eq!(rest(&mut decoder), relations(1..3));
+
+  let buf = encode(&nodes(1..30));
+  let mut decoder = Decoder::new(Cursor::new(buf));
+  assert!(!decoder.seek_to(ElementType::Way()).unwrap());
+  assert_eq!(decoder.next_item().unwrap(), None);
+}
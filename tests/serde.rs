@@ -0,0 +1,85 @@
+#![cfg(feature="serde")]
+mod common;
+use common::tags;
+use o5m_stream::{
+  BBox,Coord,Dataset,Delete,ElementType,Header,Info,Node,NodeData,Relation,RelationData,
+  RelationMember,Tags,Timestamp,Way,WayData,
+};
+
+// each variant of Dataset with the json the readme documents for it
+fn documented() -> Vec<(Dataset,&'static str)> {
+  vec![
+    (Dataset::Header(Header { format: "o5m2".to_string() }),r#"{"header":{"format":"o5m2"}}"#),
+    (Dataset::Timestamp(Timestamp { time: 1_600_000_000 }),r#"{"timestamp":{"time":1600000000}}"#),
+    (
+      Dataset::BBox(BBox {
+        x1: Coord(130_883_500),
+        y1: Coord(523_382_400),
+        x2: Coord(137_611_600),
+        y2: Coord(526_755_400),
+      }),
+      r#"{"bbox":{"x1":130883500,"y1":523382400,"x2":137611600,"y2":526755400}}"#,
+    ),
+    (
+      Dataset::Node(Node {
+        id: 1,
+        info: None,
+        data: Some(NodeData { longitude: Coord(134_000_000), latitude: Coord(525_000_000) }),
+        tags: Tags::new(),
+      }),
+      r#"{"node":{"id":1,"info":null,"data":{"longitude":134000000,"latitude":525000000},"tags":{}}}"#,
+    ),
+    (
+      Dataset::Way(Way {
+        id: 2,
+        info: None,
+        data: Some(WayData { refs: vec![1,3,4] }),
+        tags: tags(&[("highway","path")]),
+      }),
+      r#"{"way":{"id":2,"info":null,"data":{"refs":[1,3,4]},"tags":{"highway":"path"}}}"#,
+    ),
+    (
+      Dataset::Relation(Relation {
+        id: 3,
+        info: Some(Info {
+          version: Some(2),
+          timestamp: Some(1_600_000_000),
+          changeset: Some(7),
+          uid: Some(9),
+          user: Some("alice".to_string()),
+        }),
+        data: Some(RelationData {
+          members: vec![
+            RelationMember { id: 2, element_type: ElementType::Way(), role: "outer".to_string() },
+          ],
+        }),
+        tags: tags(&[("type","multipolygon")]),
+      }),
+      concat!(
+        r#"{"relation":{"id":3,"info":{"version":2,"timestamp":1600000000,"changeset":7,"uid":9,"#,
+        r#""user":"alice"},"data":{"members":[{"id":2,"element_type":"way","role":"outer"}]},"#,
+        r#""tags":{"type":"multipolygon"}}}"#,
+      ),
+    ),
+    (
+      Dataset::Delete(Delete { id: 4, element_type: ElementType::Node(), info: None }),
+      r#"{"delete":{"id":4,"element_type":"node","info":null}}"#,
+    ),
+  ]
+}
+
+#[test]
+fn json_matches_readme() {
+  for (dataset,json) in documented() {
+    assert_eq!(serde_json::to_string(&dataset).unwrap(), json);
+    assert_eq!(serde_json::from_str::<Dataset>(json).unwrap(), dataset);
+  }
+}
+
+#[test]
+fn bincode_round_trip() {
+  for (dataset,_) in documented() {
+    let bytes = bincode::serialize(&dataset).unwrap();
+    assert_eq!(bincode::deserialize::<Dataset>(&bytes).unwrap(), dataset);
+  }
+}
@@ -0,0 +1,120 @@
+use o5m_stream::{
+  Coord,Dataset,Decoder,DecodeError,DecoderOptions,Encoder,Frame,FrameDecoder,Node,NodeData,
+  SliceDecoder,SliceFrameDecoder,Tags,Way,WayData,
+};
+
+fn sample() -> Vec<u8> {
+  let mut encoder = Encoder::new();
+  let mut buf = vec![];
+  for id in 1..200 {
+    let mut tags = Tags::new();
+    tags.insert("name".to_string(), format!("node {}", id % 7));
+    let node = Dataset::Node(Node {
+      id,
+      info: None,
+      data: Some(NodeData { longitude: Coord(id as i32), latitude: Coord(2 * id as i32) }),
+      tags,
+    });
+    encoder.write_dataset(&node, &mut buf).unwrap();
+  }
+  let way = Dataset::Way(Way {
+    id: 1,
+    info: None,
+    data: Some(WayData { refs: (1..200).collect() }),
+    tags: Tags::new(),
+  });
+  encoder.write_dataset(&way, &mut buf).unwrap();
+  buf
+}
+
+fn collect<I: Iterator<Item=o5m_stream::DecodeItem>>(items: I) -> Vec<Result<Dataset,String>> {
+  items.map(|item| item.map_err(|e| e.to_string())).collect()
+}
+
+#[test]
+fn matches_decoder() {
+  let buf = sample();
+  let expected = collect(Decoder::new(&buf[..]));
+  assert_eq!(expected.len(), 201);
+  assert_eq!(collect(SliceDecoder::new(&buf)), expected);
+  let mut decoder = SliceDecoder::new(&buf);
+  assert_eq!(decoder.header().unwrap().unwrap().format, "o5m2");
+  let mut n = 0;
+  while let Some(data) = decoder.next_ref().unwrap() {
+    assert_eq!(Ok(data.to_dataset()), expected[n]);
+    n += 1;
+  }
+  assert_eq!(n, expected.len());
+}
+
+#[test]
+fn matches_decoder_on_corrupt_input() {
+  let buf = sample();
+  for i in (1..buf.len()).step_by(37) {
+    let mut bad = buf.clone();
+    bad[i] ^= 0x55;
+    for lenient in [false,true] {
+      let options = DecoderOptions { lenient, ..Default::default() };
+      assert_eq!(
+        collect(SliceDecoder::with_options(&bad, options.clone())),
+        collect(Decoder::with_options(&bad[..], options)),
+        "byte {} lenient {}", i, lenient,
+      );
+    }
+  }
+}
+
+type FrameItem = Result<(u8,u64,u64,Vec<u8>),String>;
+
+fn item(frame: Frame) -> FrameItem {
+  Ok((frame.type_byte,frame.offset,frame.end,frame.data.to_vec()))
+}
+
+fn frames(buf: &[u8], options: &DecoderOptions) -> Vec<FrameItem> {
+  let mut frames = FrameDecoder::with_options(buf, options.clone());
+  let mut out = vec![];
+  loop {
+    match frames.next_frame() {
+      Ok(Some(frame)) => out.push(item(frame)),
+      Ok(None) => break,
+      Err(e) => out.push(Err(e.to_string())),
+    }
+  }
+  out
+}
+
+#[test]
+fn frames_match_frame_decoder() {
+  let buf = sample();
+  let mut bad = buf.clone();
+  bad.insert(0, 0x10);
+  bad[400] ^= 0x55;
+  bad.truncate(bad.len() - 3);
+  for input in [&buf,&bad] {
+    for lenient in [false,true] {
+      let options = DecoderOptions { lenient, ..Default::default() };
+      let mut decoder = SliceFrameDecoder::with_options(input, options.clone());
+      let mut out = vec![];
+      loop {
+        match decoder.next_frame() {
+          Ok(Some(frame)) => out.push(item(frame)),
+          Ok(None) => break,
+          Err(e) => out.push(Err(e.to_string())),
+        }
+      }
+      assert_eq!(out, frames(input, &options), "lenient {}", lenient);
+    }
+  }
+}
+
+#[test]
+fn header_uses_the_options() {
+  let mut buf = sample();
+  buf.insert(0, 0x10);
+  let options = DecoderOptions { lenient: true, ..Default::default() };
+  let decoder = SliceDecoder::with_options(&buf, options.clone());
+  assert!(matches!(decoder.header(), Err(DecodeError::Skipped { .. })));
+  assert!(matches!(SliceDecoder::new(&buf).header(), Err(DecodeError::UnexpectedByte { .. })));
+  let mut decoder = Decoder::with_options(&buf[..], options);
+  assert!(matches!(decoder.header(), Err(DecodeError::Skipped { .. })));
+}
@@ -0,0 +1,264 @@
+mod common;
+use common::tags;
+use futures::{executor::block_on,prelude::*};
+use o5m_stream::{
+  BBox,Coord,Dataset,Delete,ElementType,EncodeError,Header,Info,Node,NodeData,Relation,RelationData,
+  RelationMember,Tags,Timestamp,Way,WayData,XmlDecoder,XmlEncoder,
+};
+
+fn info(version: u64) -> Option<Info> {
+  Some(Info {
+    version: Some(version),
+    timestamp: Some(1_600_000_000),
+    changeset: Some(7),
+    uid: Some(9),
+    user: Some("a & \"b\"".to_string()),
+  })
+}
+
+fn sample(format: &str) -> Vec<Dataset> {
+  vec![
+    Dataset::Header(Header { format: format.to_string() }),
+    Dataset::Timestamp(Timestamp { time: 1_600_000_000 }),
+    Dataset::BBox(BBox {
+      x1: Coord(-1_800_000_000),
+      y1: Coord(-900_000_000),
+      x2: Coord(1_800_000_000),
+      y2: Coord(900_000_000),
+    }),
+    Dataset::Node(Node {
+      id: 1,
+      info: info(1),
+      data: Some(NodeData { longitude: Coord(134_000_001), latitude: Coord(-525_000_000) }),
+      tags: tags(&[("name","<x>\n"),("amenity","cafe")]),
+    }),
+    Dataset::Node(Node {
+      id: 2,
+      info: None,
+      data: Some(NodeData { longitude: Coord(0), latitude: Coord(1) }),
+      tags: Tags::new(),
+    }),
+    Dataset::Way(Way {
+      id: 3,
+      info: info(2),
+      data: Some(WayData { refs: vec![1,2] }),
+      tags: tags(&[("highway","path")]),
+    }),
+    Dataset::Relation(Relation {
+      id: 4,
+      info: None,
+      data: Some(RelationData {
+        members: vec![
+          RelationMember { id: 3, element_type: ElementType::Way(), role: "outer".to_string() },
+          RelationMember { id: 1, element_type: ElementType::Node(), role: String::new() },
+        ],
+      }),
+      tags: tags(&[("type","multipolygon")]),
+    }),
+    Dataset::Delete(Delete { id: 5, element_type: ElementType::Node(), info: info(3) }),
+  ]
+}
+
+fn write(items: &[Dataset]) -> String {
+  let mut encoder = XmlEncoder::new();
+  let mut buf = vec![];
+  for data in items {
+    encoder.write_dataset(data, &mut buf).unwrap();
+  }
+  encoder.end(&mut buf);
+  String::from_utf8(buf).unwrap()
+}
+
+const OSM: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6" generator="o5m-stream" timestamp="2020-09-13T12:26:40Z">
+  <bounds minlat="-90" minlon="-180" maxlat="90" maxlon="180"/>
+  <node id="1" version="1" timestamp="2020-09-13T12:26:40Z" uid="9" user="a &amp; &quot;b&quot;" changeset="7" lat="-52.5" lon="13.4000001">
+    <tag k="amenity" v="cafe"/>
+    <tag k="name" v="&lt;x&gt;&#10;"/>
+  </node>
+  <node id="2" lat="0.0000001" lon="0"/>
+  <way id="3" version="2" timestamp="2020-09-13T12:26:40Z" uid="9" user="a &amp; &quot;b&quot;" changeset="7">
+    <nd ref="1"/>
+    <nd ref="2"/>
+    <tag k="highway" v="path"/>
+  </way>
+  <relation id="4">
+    <member type="way" ref="3" role="outer"/>
+    <member type="node" ref="1" role=""/>
+    <tag k="type" v="multipolygon"/>
+  </relation>
+  <node id="5" version="3" timestamp="2020-09-13T12:26:40Z" uid="9" user="a &amp; &quot;b&quot;" changeset="7" visible="false"/>
+</osm>
+"#;
+
+const OSC: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osmChange version="0.6" generator="o5m-stream" timestamp="2020-09-13T12:26:40Z">
+  <create>
+    <node id="1" version="1" timestamp="2020-09-13T12:26:40Z" uid="9" user="a &amp; &quot;b&quot;" changeset="7" lat="-52.5" lon="13.4000001">
+      <tag k="amenity" v="cafe"/>
+      <tag k="name" v="&lt;x&gt;&#10;"/>
+    </node>
+  </create>
+  <modify>
+    <node id="2" lat="0.0000001" lon="0"/>
+    <way id="3" version="2" timestamp="2020-09-13T12:26:40Z" uid="9" user="a &amp; &quot;b&quot;" changeset="7">
+      <nd ref="1"/>
+      <nd ref="2"/>
+      <tag k="highway" v="path"/>
+    </way>
+    <relation id="4">
+      <member type="way" ref="3" role="outer"/>
+      <member type="node" ref="1" role=""/>
+      <tag k="type" v="multipolygon"/>
+    </relation>
+  </modify>
+  <delete>
+    <node id="5" version="3" timestamp="2020-09-13T12:26:40Z" uid="9" user="a &amp; &quot;b&quot;" changeset="7"/>
+  </delete>
+</osmChange>
+"#;
+
+#[test]
+fn writes_osm() {
+  assert_eq!(write(&sample("o5m2")), OSM);
+}
+
+#[test]
+fn writes_osm_change() {
+  assert_eq!(write(&sample("o5c2")), OSC);
+}
+
+#[test]
+fn rejects_null_bytes() {
+  let mut encoder = XmlEncoder::new();
+  let node = Dataset::Node(Node { id: 1, info: None, data: None, tags: tags(&[("a","b\0")]) });
+  assert!(encoder.write_dataset(&node, &mut vec![]).is_err());
+}
+
+#[test]
+fn rejects_control_characters() {
+  let mut encoder = XmlEncoder::new();
+  let node = Dataset::Node(Node { id: 1, info: None, data: None, tags: tags(&[("a","b\x01")]) });
+  match encoder.write_dataset(&node, &mut vec![]) {
+    Err(EncodeError::UnexpectedControlCharacter { info, byte, .. }) => {
+      assert_eq!(info, "tag value");
+      assert_eq!(byte, 0x01);
+    },
+    x => panic!("unexpected {:?}", x),
+  }
+  // tab, line feed and carriage return are allowed
+  let node = Dataset::Node(Node { id: 1, info: None, data: None, tags: tags(&[("a","\t\n\r")]) });
+  let mut buf = vec![];
+  encoder.write_dataset(&node, &mut buf).unwrap();
+  assert!(String::from_utf8(buf).unwrap().contains("v=\"&#9;&#10;&#13;\""));
+}
+
+#[test]
+fn failed_element_leaves_the_output_unchanged() {
+  let items = sample("o5c2");
+  let mut encoder = XmlEncoder::new();
+  let mut buf = vec![];
+  for data in &items[..3] {
+    encoder.write_dataset(data, &mut buf).unwrap();
+  }
+  let written = buf.clone();
+  let bad = Dataset::Way(Way { id: 6, info: info(1), data: None, tags: tags(&[("a","b\0")]) });
+  assert!(encoder.write_dataset(&bad, &mut buf).is_err());
+  assert_eq!(buf, written);
+  for data in &items[3..] {
+    encoder.write_dataset(data, &mut buf).unwrap();
+  }
+  encoder.end(&mut buf);
+  assert_eq!(String::from_utf8(buf).unwrap(), OSC);
+}
+
+// reader that returns one byte per call, so that every tag is split across reads
+struct Bytes<'a>(&'a [u8]);
+
+impl<'a> std::io::Read for Bytes<'a> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    if self.0.is_empty() || buf.is_empty() { return Ok(0) }
+    buf[0] = self.0[0];
+    self.0 = &self.0[1..];
+    Ok(1)
+  }
+}
+
+#[test]
+fn reads_osm() {
+  let items = XmlDecoder::new(OSM.as_bytes()).collect::<Result<Vec<_>,_>>().unwrap();
+  assert_eq!(items, sample("o5m2"));
+  let items = XmlDecoder::new(Bytes(OSM.as_bytes())).collect::<Result<Vec<_>,_>>().unwrap();
+  assert_eq!(items, sample("o5m2"));
+}
+
+// what OSC reads back as: osmChange has no bounds
+fn change_sample() -> Vec<Dataset> {
+  sample("o5c2").into_iter().filter(|data| !matches!(data, Dataset::BBox(_))).collect()
+}
+
+#[test]
+fn reads_osm_change() {
+  let items = XmlDecoder::new(OSC.as_bytes()).collect::<Result<Vec<_>,_>>().unwrap();
+  assert_eq!(items, change_sample());
+}
+
+#[test]
+fn decode_xml_stream() {
+  let stream = o5m_stream::decode_xml(Box::new(futures::io::Cursor::new(OSC.as_bytes())));
+  let items = block_on(stream.collect::<Vec<_>>());
+  let items = items.into_iter().collect::<Result<Vec<_>,_>>().unwrap();
+  assert_eq!(items, change_sample());
+}
+
+#[test]
+fn skips_comments_and_unknown_tags() {
+  let xml = r#"<?xml version="1.0"?>
+<!-- <node id="9"/> -->
+<osm version="0.6">
+  <note>text</note>
+  <node id="1" lat="1" lon="2"><tag k="a" v="&#65;&amp;&lt;"/></node>
+</osm>"#;
+  let items = XmlDecoder::new(xml.as_bytes()).collect::<Result<Vec<_>,_>>().unwrap();
+  assert_eq!(items, vec![
+    Dataset::Header(Header { format: "o5m2".to_string() }),
+    Dataset::Node(Node {
+      id: 1,
+      info: None,
+      data: Some(NodeData { longitude: Coord(20_000_000), latitude: Coord(10_000_000) }),
+      tags: tags(&[("a","A&<")]),
+    }),
+  ]);
+}
+
+#[test]
+fn reports_errors() {
+  let errors = [
+    r#"<osm><node id="x"/></osm>"#,
+    r#"<osm><node id="1" lat="1" lon="east"/></osm>"#,
+    r#"<osm><way id="1"><nd/></way></osm>"#,
+    r#"<osm><relation id="1"><member type="area" ref="1"/></relation></osm>"#,
+    r#"<osm><node id="1"><node id="2"/></node></osm>"#,
+    r#"<osm><node id="1""#,
+  ];
+  for xml in errors.iter() {
+    let items = XmlDecoder::new(xml.as_bytes()).collect::<Vec<_>>();
+    assert!(items.iter().any(|item| item.is_err()), "{}", xml);
+  }
+}
+
+#[test]
+fn first_error_ends_the_stream() {
+  let xml = concat!(
+    r#"<osm><node id="1" lat="1" lon="2"/><node id="x"/>"#,
+    r#"<node id="3" lat="1" lon="2"/></osm>"#,
+  );
+  let items = XmlDecoder::new(xml.as_bytes()).collect::<Vec<_>>();
+  assert_eq!(items.len(), 3, "{:?}", items);
+  assert!(items[1].is_ok());
+  assert!(items[2].is_err());
+  let stream = o5m_stream::decode_xml(Box::new(futures::io::Cursor::new(xml.as_bytes())));
+  let items = block_on(stream.collect::<Vec<_>>());
+  assert_eq!(items.len(), 3, "{:?}", items);
+  assert!(items[2].is_err());
+}